@@ -10,8 +10,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     prost_config.type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]");
 
     // 2) Run tonic_build with our custom prost_config
+    //    (server stubs are only used by the mock secret service in src/mock,
+    //    so they are left out unless the `mock` feature is on)
     tonic_build::configure()
-        .build_server(false)
+        .build_server(env::var_os("CARGO_FEATURE_MOCK").is_some())
         .compile_with_config(
             prost_config,
             &["proto/secret_service.proto"], // ← your .proto file(s)
@@ -0,0 +1,338 @@
+//! Account settings and password resets, validated here and carried out by
+//! the management API.
+
+use std::net::IpAddr;
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use warp::{Rejection, reject};
+
+use crate::{
+    api::{
+        common::{
+            FieldError,
+            PortalRejection::{
+                self, Forbidden, Invalid, NotFound, TooManyRequests, Unauthorized, Whoops,
+            },
+        },
+        cookie::SessionData,
+        helper::token_rejection,
+        user_sessions::end_user_sessions,
+    },
+    auth::{
+        login_limit::{AttemptKeys, Reservation},
+        token::get_token,
+    },
+    state::{AppState, SharedState},
+};
+
+const MIN_PASSWORD_LEN: usize = 10;
+const MAX_PASSWORD_LEN: usize = 256;
+
+#[derive(Deserialize)]
+pub struct UpdateEmail {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePassword {
+    pub current_password: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordConfirm {
+    pub token: String,
+    pub password: String,
+}
+
+fn check_email(email: &str, errors: &mut Vec<FieldError>) {
+    let valid = email.len() <= 254
+        && !email.chars().any(char::is_whitespace)
+        && matches!(email.split_once('@'), Some((local, domain))
+            if !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.'));
+    if !valid {
+        errors.push(FieldError::new(
+            "email",
+            "format",
+            "not a valid email address".to_owned(),
+        ));
+    }
+}
+
+fn check_password(field: &str, password: &str, errors: &mut Vec<FieldError>) {
+    let len = password.chars().count();
+    if len < MIN_PASSWORD_LEN {
+        errors.push(FieldError::new(
+            field,
+            "too_short",
+            format!("must be at least {} characters", MIN_PASSWORD_LEN),
+        ));
+    } else if len > MAX_PASSWORD_LEN {
+        errors.push(FieldError::new(
+            field,
+            "too_long",
+            format!("must be at most {} characters", MAX_PASSWORD_LEN),
+        ));
+    }
+}
+
+fn required(field: &str, value: &str, errors: &mut Vec<FieldError>) {
+    if value.trim().is_empty() {
+        errors.push(FieldError::new(field, "required", "is required".to_owned()));
+    }
+}
+
+fn validated(errors: Vec<FieldError>) -> Result<(), Rejection> {
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(reject::custom(Invalid(errors))),
+    }
+}
+
+/// Sends `body` to the management API, with the session's token when there
+/// is one, and returns the reply's JSON. Refusals become rejections blaming
+/// the field the upstream names, or `field` when it names none.
+async fn call_upstream(
+    state: &AppState,
+    method: Method,
+    path: &str,
+    session: Option<SessionData>,
+    body: Value,
+    field: &str,
+) -> Result<Value, Rejection> {
+    let mut request = state
+        .http_client
+        .request(method, state.upstream_url(path))
+        .json(&body);
+    if let Some(session) = session {
+        let token = get_token(state, session).await.map_err(token_rejection)?;
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.map_err(|e| {
+        log!(LogLevel::Error, "{} request failed: {}", path, e);
+        reject::custom(Whoops(e.to_string()))
+    })?;
+
+    let status = response.status();
+    let json: Value = response.json().await.unwrap_or_default();
+    if status.is_success() {
+        return Ok(json);
+    }
+
+    let error = &json["errors"][0];
+    let message = error["message"]
+        .as_str()
+        .unwrap_or("rejected by the management API")
+        .to_owned();
+    let field = error["details"]["field"].as_str().unwrap_or(field);
+    log!(LogLevel::Warn, "{} answered {}: {}", path, status, message);
+    Err(match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+            reject::custom(Invalid(vec![FieldError::new(field, "rejected", message)]))
+        }
+        StatusCode::CONFLICT => {
+            reject::custom(Invalid(vec![FieldError::new(field, "conflict", message)]))
+        }
+        StatusCode::UNAUTHORIZED => reject::custom(Unauthorized(message)),
+        StatusCode::FORBIDDEN => reject::custom(Forbidden),
+        StatusCode::NOT_FOUND => reject::custom(NotFound(message)),
+        _ => reject::custom(Whoops(format!("management API answered {}", status))),
+    })
+}
+
+pub async fn update_email_handler(
+    state: SharedState,
+    session: SessionData,
+    update: UpdateEmail,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let email = update.email.trim();
+    let mut errors = Vec::new();
+    check_email(email, &mut errors);
+    validated(errors)?;
+
+    let user_id = session.user_id.clone();
+    call_upstream(
+        &state,
+        Method::PUT,
+        "account/email",
+        Some(session),
+        json!({ "email": email }),
+        "email",
+    )
+    .await?;
+    log!(LogLevel::Info, "{} changed their email", user_id);
+    Ok(warp::reply::json(&json!({ "email": email })))
+}
+
+/// Changes the password and ends the user's other sessions, which may be
+/// the reason for the change.
+pub async fn change_password_handler(
+    state: SharedState,
+    session: SessionData,
+    update: UpdatePassword,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut errors = Vec::new();
+    required("current_password", &update.current_password, &mut errors);
+    check_password("password", &update.password, &mut errors);
+    if update.password == update.current_password {
+        errors.push(FieldError::new(
+            "password",
+            "unchanged",
+            "must differ from the current password".to_owned(),
+        ));
+    }
+    validated(errors)?;
+
+    let user_id = session.user_id.clone();
+    let session_id = session.session_id.clone();
+    call_upstream(
+        &state,
+        Method::PUT,
+        "account/password",
+        Some(session),
+        json!({
+            "current_password": update.current_password,
+            "password": update.password,
+        }),
+        "password",
+    )
+    .await?;
+    log!(LogLevel::Info, "{} changed their password", user_id);
+
+    let ended = end_user_sessions(&state, &user_id, Some(&session_id))
+        .await
+        .map_err(|e| {
+            log!(LogLevel::Error, "ending sessions of {}: {}", user_id, e);
+            reject::custom(Whoops(
+                "Password changed, but other sessions could not be ended".to_owned(),
+            ))
+        })?;
+    Ok(warp::reply::json(
+        &json!({ "updated": true, "sessions_ended": ended }),
+    ))
+}
+
+/// Reserves a password reset attempt. Resets are unauthenticated, so they
+/// are held to the login limits, per address and per email, under keys of
+/// their own.
+async fn throttle_reset<'a>(
+    state: &'a AppState,
+    email: Option<&str>,
+    client_ip: Option<IpAddr>,
+) -> Result<Reservation<'a>, Rejection> {
+    let keys = AttemptKeys::password_reset(email, client_ip);
+    state.login_limiter.check(keys).await.map_err(|wait| {
+        log!(
+            LogLevel::Warn,
+            "password reset from {:?} throttled for {:?}",
+            client_ip,
+            wait
+        );
+        reject::custom(TooManyRequests(wait))
+    })
+}
+
+/// Asks the upstream to email a reset link. Answers the same whether or not
+/// the address has an account, so it can't be used to find accounts. Every
+/// request counts against the limits, since each one sends an email.
+pub async fn password_reset_request_handler(
+    state: SharedState,
+    client_ip: Option<IpAddr>,
+    request: ResetPasswordRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let email = request.email.trim();
+    let mut errors = Vec::new();
+    check_email(email, &mut errors);
+    validated(errors)?;
+    let attempt = throttle_reset(&state, Some(email), client_ip).await?;
+
+    let result = call_upstream(
+        &state,
+        Method::POST,
+        "auth/password-reset/request",
+        None,
+        json!({ "email": email }),
+        "email",
+    )
+    .await;
+    attempt.failed().await;
+    match result {
+        Ok(_) => {}
+        Err(rejection) if matches!(rejection.find::<PortalRejection>(), Some(NotFound(_))) => {
+            log!(LogLevel::Debug, "password reset for an unknown address");
+        }
+        Err(rejection) => return Err(rejection),
+    }
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "requested": true })),
+        warp::http::StatusCode::ACCEPTED,
+    ))
+}
+
+/// Sets a new password with an emailed reset token. Every session of the
+/// account ends, since whoever held them may not know the password any
+/// more. Refused tokens count against the address, so they can't be
+/// guessed.
+pub async fn password_reset_confirm_handler(
+    state: SharedState,
+    client_ip: Option<IpAddr>,
+    confirm: ResetPasswordConfirm,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut errors = Vec::new();
+    required("token", &confirm.token, &mut errors);
+    check_password("password", &confirm.password, &mut errors);
+    validated(errors)?;
+    let attempt = throttle_reset(&state, None, client_ip).await?;
+
+    let reply = match call_upstream(
+        &state,
+        Method::POST,
+        "auth/password-reset/confirm",
+        None,
+        json!({ "token": confirm.token.trim(), "password": confirm.password }),
+        "token",
+    )
+    .await
+    {
+        Ok(reply) => reply,
+        Err(rejection) => {
+            if matches!(
+                rejection.find::<PortalRejection>(),
+                Some(Invalid(_) | NotFound(_) | Unauthorized(_))
+            ) {
+                attempt.failed().await;
+            }
+            return Err(rejection);
+        }
+    };
+    drop(attempt);
+
+    let user_id = reply["data"]["user_id"]
+        .as_str()
+        .or_else(|| reply["user_id"].as_str());
+    match user_id {
+        Some(user_id) => {
+            log!(LogLevel::Info, "{} reset their password", user_id);
+            if let Err(e) = end_user_sessions(&state, user_id, None).await {
+                log!(LogLevel::Error, "ending sessions of {}: {}", user_id, e);
+            }
+        }
+        None => log!(
+            LogLevel::Warn,
+            "password reset didn't name the account, its sessions stay"
+        ),
+    }
+    Ok(warp::reply::json(&json!({ "reset": true })))
+}
@@ -0,0 +1,466 @@
+//! Personal access tokens, for scripts that call the proxy and secret
+//! routes with `Authorization: Bearer` instead of a session cookie.
+//!
+//! Each token acts through a session of its own, opened by signing in again
+//! when the token is created, so ending the token ends nothing else. A
+//! token's expiry stands in for the idle timeout and maximum lifetime, and
+//! never outlasts that session.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+use artisan_middleware::{
+    api::token::SimpleLoginRequest,
+    dusa_collection_utils::{
+        core::{logger::LogLevel, types::rwarc::LockWithTimeout},
+        log,
+    },
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use ring::{
+    digest::{SHA256, digest},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use uuid::Uuid;
+use warp::{Rejection, Reply, http::StatusCode, reject};
+
+use crate::{
+    api::{
+        common::{
+            FieldError,
+            PortalRejection::{BadRequest, Forbidden, Invalid, NotFound, Unauthorized, Whoops},
+        },
+        cookie::{LoginError, LoginStep, SessionData, SessionMeta, login, login_mfa},
+        handler::throttle_login,
+        helper::{find_session, token_rejection},
+        rate_limit::RouteClass,
+        user_sessions::end_session,
+    },
+    auth::token::get_token,
+    database::api_tokens::ApiToken,
+    state::{AppState, SharedState},
+};
+
+const TOKEN_PREFIX: &str = "adt_";
+const MAX_TOKENS_PER_USER: usize = 20;
+const MAX_NAME_LEN: usize = 64;
+const DEFAULT_DAYS: u32 = 30;
+const MAX_DAYS: u32 = 365;
+
+#[derive(Deserialize)]
+pub struct CreateToken {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<u32>,
+    /// The account password, to open the token's own session.
+    pub password: String,
+    /// Needed when the account answers sign-ins with an MFA challenge.
+    pub code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenEntry {
+    id: String,
+    name: String,
+    scopes: Vec<&'static str>,
+    created_at: i64,
+    expires_at: i64,
+    last_used: Option<i64>,
+}
+
+impl From<&ApiToken> for TokenEntry {
+    fn from(token: &ApiToken) -> Self {
+        Self {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            scopes: token.scopes.iter().map(|s| s.name()).collect(),
+            created_at: token.created_at.timestamp(),
+            expires_at: token.expires_at.timestamp(),
+            last_used: token.last_used.map(|t| t.timestamp()),
+        }
+    }
+}
+
+/// When each token was last used, held in memory and written to the store
+/// in batches by `spawn_activity_flush` so a busy token doesn't cost a write
+/// per request.
+pub struct TokenUses {
+    pending: LockWithTimeout<HashMap<String, DateTime<Utc>>>,
+}
+
+impl Default for TokenUses {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenUses {
+    pub fn new() -> Self {
+        Self {
+            pending: LockWithTimeout::new(HashMap::new()),
+        }
+    }
+
+    pub async fn record(&self, id: &str, at: DateTime<Utc>) {
+        if let Ok(mut pending) = self.pending.try_write().await {
+            pending.insert(id.to_owned(), at);
+        }
+    }
+
+    /// A use not yet written to the store.
+    async fn pending(&self, id: &str) -> Option<DateTime<Utc>> {
+        match self.pending.try_read().await {
+            Ok(pending) => pending.get(id).copied(),
+            Err(_) => None,
+        }
+    }
+
+    /// Writes out uses recorded since the last flush.
+    pub async fn flush(&self, state: &AppState) {
+        let pending: Vec<(String, DateTime<Utc>)> = {
+            let Ok(mut pending) = self.pending.try_write().await else {
+                return;
+            };
+            pending.drain().collect()
+        };
+        for (id, at) in &pending {
+            if let Err(e) = state.api_tokens.touch(id, *at).await {
+                log!(LogLevel::Warn, "last_used update for {}: {}", id, e);
+            }
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(digest(&SHA256, token.as_bytes()))
+}
+
+fn new_token() -> Result<String, Rejection> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| reject::custom(Whoops("no randomness for a token".to_owned())))?;
+    Ok(format!("{}{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes)))
+}
+
+/// The session behind `token`, if the token is live and its scopes cover
+/// `class`.
+pub async fn token_principal(
+    state: &AppState,
+    token: &str,
+    class: RouteClass,
+) -> Result<SessionData, Rejection> {
+    let invalid = || reject::custom(Unauthorized("Invalid API token".to_owned()));
+    if !token.starts_with(TOKEN_PREFIX) {
+        return Err(invalid());
+    }
+    let found = state
+        .api_tokens
+        .by_hash(&hash_token(token))
+        .await
+        .map_err(|e| {
+            log!(LogLevel::Error, "API token lookup: {}", e);
+            reject::custom(Whoops(e))
+        })?;
+    let Some(found) = found else {
+        return Err(invalid());
+    };
+    if !found.scopes.contains(&class) {
+        log!(
+            LogLevel::Warn,
+            "API token {} of {} used outside its scopes for {}",
+            found.id,
+            found.user_id,
+            class.name()
+        );
+        return Err(reject::custom(Forbidden));
+    }
+
+    // The session goes when the user logs out everywhere or changes their
+    // password, and the token with it.
+    let Ok(session) = find_session(state, &found.session_id).await else {
+        log!(
+            LogLevel::Info,
+            "API token {} lost its session, removing it",
+            found.id
+        );
+        if let Err(e) = state.api_tokens.delete(&found.id).await {
+            log!(LogLevel::Error, "deleting API token {}: {}", found.id, e);
+        }
+        return Err(invalid());
+    };
+    state.token_uses.record(&found.id, Utc::now()).await;
+    Ok(session)
+}
+
+/// Ids of the sessions that tokens act through, which are left out of the
+/// dashboard refresh and its idle timeout.
+pub async fn token_sessions(state: &AppState) -> HashSet<String> {
+    match state.api_tokens.session_ids().await {
+        Ok(ids) => ids,
+        Err(e) => {
+            log!(LogLevel::Warn, "listing API token sessions: {}", e);
+            HashSet::new()
+        }
+    }
+}
+
+fn check_create(create: &CreateToken) -> Result<Vec<RouteClass>, Rejection> {
+    let mut errors = Vec::new();
+    let name_len = create.name.trim().chars().count();
+    if name_len == 0 || name_len > MAX_NAME_LEN {
+        errors.push(FieldError::new(
+            "name",
+            "length",
+            format!("must be 1 to {} characters", MAX_NAME_LEN),
+        ));
+    }
+
+    let mut scopes = Vec::new();
+    for scope in &create.scopes {
+        match RouteClass::from_name(scope) {
+            Some(class) if !scopes.contains(&class) => scopes.push(class),
+            Some(_) => {}
+            None => errors.push(FieldError::new(
+                "scopes",
+                "unknown",
+                format!("unknown scope {:?}", scope),
+            )),
+        }
+    }
+    if create.scopes.is_empty() {
+        errors.push(FieldError::new(
+            "scopes",
+            "required",
+            "name at least one scope".to_owned(),
+        ));
+    }
+
+    if create
+        .expires_in_days
+        .is_some_and(|days| !(1..=MAX_DAYS).contains(&days))
+    {
+        errors.push(FieldError::new(
+            "expires_in_days",
+            "range",
+            format!("must be 1 to {} days", MAX_DAYS),
+        ));
+    }
+    if create.password.is_empty() {
+        errors.push(FieldError::new(
+            "password",
+            "required",
+            "is required".to_owned(),
+        ));
+    }
+
+    match errors.is_empty() {
+        true => Ok(scopes),
+        false => Err(reject::custom(Invalid(errors))),
+    }
+}
+
+async fn account_email(state: &AppState, session: &SessionData) -> Result<String, Rejection> {
+    let token = get_token(state, session.clone())
+        .await
+        .map_err(token_rejection)?;
+    let response = state
+        .http_client
+        .get(state.upstream_url("account/me"))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| reject::custom(Whoops(e.to_string())))?;
+    let json: Value = response.json().await.unwrap_or_default();
+    json["email"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| reject::custom(Whoops("account/me named no email".to_owned())))
+}
+
+/// Signs the user in again for the token's session, counting failures
+/// against the same limits as the login form.
+async fn open_token_session(
+    state: &AppState,
+    client_ip: Option<IpAddr>,
+    email: String,
+    create: &CreateToken,
+) -> Result<SessionData, Rejection> {
+    let attempt = throttle_login(state, &email, client_ip).await?;
+
+    let request = SimpleLoginRequest {
+        email,
+        password: create.password.clone(),
+    };
+    let result = match login(state, request).await {
+        Ok(LoginStep::Session(session)) => Ok(session),
+        Ok(LoginStep::Challenge { challenge, .. }) => match create.code.as_deref() {
+            Some(code) => login_mfa(state, &challenge, code.trim()).await,
+            None => {
+                return Err(reject::custom(Invalid(vec![FieldError::new(
+                    "code",
+                    "required",
+                    "the account needs a sign-in code".to_owned(),
+                )])));
+            }
+        },
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(session) => {
+            attempt.succeeded().await;
+            Ok(session)
+        }
+        Err(LoginError::Refused) => {
+            attempt.failed().await;
+            Err(reject::custom(Invalid(vec![FieldError::new(
+                "password",
+                "rejected",
+                "wrong password or code".to_owned(),
+            )])))
+        }
+        Err(LoginError::Failed(err)) => Err(reject::custom(Whoops(err))),
+    }
+}
+
+pub async fn create_token_handler(
+    state: SharedState,
+    client_ip: Option<IpAddr>,
+    session: SessionData,
+    create: CreateToken,
+) -> Result<impl Reply, Rejection> {
+    let scopes = check_create(&create)?;
+    let existing = state
+        .api_tokens
+        .list_user(&session.user_id)
+        .await
+        .map_err(|e| reject::custom(Whoops(e)))?;
+    if existing.len() >= MAX_TOKENS_PER_USER {
+        return Err(reject::custom(BadRequest(format!(
+            "At most {} API tokens, revoke one first",
+            MAX_TOKENS_PER_USER
+        ))));
+    }
+
+    let name = create.name.trim().to_owned();
+    let email = account_email(&state, &session).await?;
+    let backing = open_token_session(&state, client_ip, email, &create).await?;
+    if backing.user_id != session.user_id {
+        log!(
+            LogLevel::Error,
+            "API token sign-in for {} came back as {}",
+            session.user_id,
+            backing.user_id
+        );
+        return Err(reject::custom(Forbidden));
+    }
+    let meta = SessionMeta::new(
+        client_ip.map(|ip| ip.to_string()),
+        Some(format!("API token: {}", name)),
+    );
+    state
+        .sessions
+        .insert_with_meta(&backing, &meta)
+        .await
+        .map_err(|e| reject::custom(Whoops(e)))?;
+
+    let secret = new_token()?;
+    let now = Utc::now();
+    let days = create.expires_in_days.unwrap_or(DEFAULT_DAYS);
+    let token = ApiToken {
+        id: Uuid::new_v4().to_string(),
+        user_id: session.user_id.clone(),
+        name,
+        token_hash: hash_token(&secret),
+        scopes,
+        session_id: backing.session_id.clone(),
+        created_at: now,
+        // The token can't act once its session is gone.
+        expires_at: (now + Duration::days(i64::from(days))).min(backing.expires_at),
+        last_used: None,
+    };
+    if let Err(e) = state.api_tokens.insert(&token).await {
+        log!(
+            LogLevel::Error,
+            "storing API token of {}: {}",
+            token.user_id,
+            e
+        );
+        let _ = end_session(&state, &backing).await;
+        return Err(reject::custom(Whoops(e)));
+    }
+    log!(
+        LogLevel::Info,
+        "{} created API token {} ({})",
+        token.user_id,
+        token.id,
+        token.name
+    );
+
+    let mut body = json!(TokenEntry::from(&token));
+    // The only time the token is shown.
+    body["token"] = json!(secret);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&body),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn list_tokens_handler(
+    state: SharedState,
+    session: SessionData,
+) -> Result<impl Reply, Rejection> {
+    let tokens = state
+        .api_tokens
+        .list_user(&session.user_id)
+        .await
+        .map_err(|e| reject::custom(Whoops(e)))?;
+    let mut entries = Vec::with_capacity(tokens.len());
+    for token in &tokens {
+        let mut entry = TokenEntry::from(token);
+        if let Some(at) = state.token_uses.pending(&token.id).await {
+            entry.last_used = Some(at.timestamp());
+        }
+        entries.push(entry);
+    }
+    Ok(warp::reply::json(&json!({ "tokens": entries })))
+}
+
+/// Deletes the token and ends the session it acted through.
+pub async fn revoke_token_handler(
+    id: String,
+    state: SharedState,
+    session: SessionData,
+) -> Result<impl Reply, Rejection> {
+    let tokens = state
+        .api_tokens
+        .list_user(&session.user_id)
+        .await
+        .map_err(|e| reject::custom(Whoops(e)))?;
+    let Some(token) = tokens.into_iter().find(|t| t.id == id) else {
+        return Err(reject::custom(NotFound("No such API token".to_owned())));
+    };
+
+    state
+        .api_tokens
+        .delete(&token.id)
+        .await
+        .map_err(|e| reject::custom(Whoops(e)))?;
+    if let Ok(backing) = state.sessions.lookup(&token.session_id).await
+        && let Err(e) = end_session(&state, &backing).await
+    {
+        log!(LogLevel::Error, "ending session of API token {}: {}", id, e);
+    }
+    log!(
+        LogLevel::Info,
+        "{} revoked API token {}",
+        session.user_id,
+        id
+    );
+    Ok(warp::reply::json(&json!({ "revoked": id })))
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 
 use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
@@ -15,6 +15,12 @@ pub struct Cache {
     inner: LockWithTimeout<HashMap<String, CachedResponse>>,
 }
 
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cache {
     pub fn new() -> Self {
         Self {
@@ -49,6 +55,12 @@ pub struct SessionCache {
     inner: LockWithTimeout<HashMap<String, CachedSession>>,
 }
 
+impl Default for SessionCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SessionCache {
     pub fn new() -> Self {
         Self {
@@ -76,6 +88,16 @@ impl SessionCache {
         }
     }
 
+    /// Applies `f` to a cached session, e.g. to swap in refreshed tokens,
+    /// without resetting the entry's age.
+    pub async fn update(&self, key: &str, f: impl FnOnce(&mut SessionData)) {
+        if let Ok(mut guard) = self.inner.try_write().await
+            && let Some(entry) = guard.get_mut(key)
+        {
+            f(&mut entry.data);
+        }
+    }
+
     pub async fn remove(&self, key: &str) {
         if let Ok(mut guard) = self.inner.try_write().await {
             guard.remove(key);
@@ -88,3 +110,64 @@ impl SessionCache {
         }
     }
 }
+
+/// Runners (and lazily, their instances) a user is allowed to operate on.
+#[derive(Clone, Default)]
+pub struct OwnedRunners {
+    pub runners: HashSet<String>,
+    pub instances: Option<HashSet<String>>,
+}
+
+#[derive(Clone)]
+pub struct CachedOwnership {
+    pub data: OwnedRunners,
+    pub inserted: Instant,
+}
+
+pub struct OwnershipCache {
+    inner: LockWithTimeout<HashMap<String, CachedOwnership>>,
+}
+
+impl Default for OwnershipCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OwnershipCache {
+    pub fn new() -> Self {
+        Self {
+            inner: LockWithTimeout::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, user_id: &str, ttl: Duration) -> Option<OwnedRunners> {
+        let guard = self.inner.try_read().await.ok()?;
+        guard
+            .get(user_id)
+            .filter(|c| c.inserted.elapsed() < ttl)
+            .map(|c| c.data.clone())
+    }
+
+    pub async fn insert(&self, user_id: String, data: OwnedRunners) {
+        if let Ok(mut guard) = self.inner.try_write().await {
+            guard.insert(
+                user_id,
+                CachedOwnership {
+                    data,
+                    inserted: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Stores the instance set without resetting the entry's age, so the
+    /// runner list is still refreshed on its original schedule.
+    pub async fn set_instances(&self, user_id: &str, instances: HashSet<String>) {
+        if let Ok(mut guard) = self.inner.try_write().await
+            && let Some(entry) = guard.get_mut(user_id)
+        {
+            entry.data.instances = Some(instances);
+        }
+    }
+}
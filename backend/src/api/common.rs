@@ -1,3 +1,29 @@
+use serde::Serialize;
+use std::time::Duration;
+use warp::{
+    Rejection, Reply,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    reply::{Response, json, with_status},
+};
+
+/// One rejected field, reported back to the client alongside the others.
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub(crate) fn new(field: &str, code: &'static str, message: String) -> Self {
+        Self {
+            field: field.to_owned(),
+            code,
+            message,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum PortalRejection {
@@ -7,6 +33,77 @@ pub enum PortalRejection {
     Timeout(String),
     Login,
     Forbidden,
+    BadRequest(String),
+    Invalid(Vec<FieldError>),
+    Unavailable(String),
+    CsrfRejected(String),
+    NotFound(String),
+    /// The upstream service doesn't offer this yet.
+    NotImplemented(String),
+    /// Answered with `Retry-After` set to the wait, in whole seconds.
+    TooManyRequests(Duration),
 }
 
 impl warp::reject::Reject for PortalRejection {}
+
+impl PortalRejection {
+    fn status(&self) -> StatusCode {
+        match self {
+            PortalRejection::Unauthorized(_) | PortalRejection::Login => StatusCode::UNAUTHORIZED,
+            PortalRejection::Forbidden | PortalRejection::CsrfRejected(_) => StatusCode::FORBIDDEN,
+            PortalRejection::BadRequest(_) => StatusCode::BAD_REQUEST,
+            PortalRejection::NotFound(_) => StatusCode::NOT_FOUND,
+            PortalRejection::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            PortalRejection::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            PortalRejection::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            PortalRejection::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            PortalRejection::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            PortalRejection::ClipasError(_) | PortalRejection::Whoops(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            PortalRejection::ClipasError(msg)
+            | PortalRejection::Unauthorized(msg)
+            | PortalRejection::Whoops(msg)
+            | PortalRejection::Timeout(msg)
+            | PortalRejection::BadRequest(msg)
+            | PortalRejection::Unavailable(msg)
+            | PortalRejection::CsrfRejected(msg)
+            | PortalRejection::NotFound(msg)
+            | PortalRejection::NotImplemented(msg) => msg.clone(),
+            PortalRejection::Login => "Login required".to_owned(),
+            PortalRejection::Forbidden => "Forbidden".to_owned(),
+            PortalRejection::Invalid(_) => "Validation failed".to_owned(),
+            PortalRejection::TooManyRequests(_) => "Too many requests, try again later".to_owned(),
+        }
+    }
+}
+
+/// Turns our own rejections into JSON error replies. Anything else is passed
+/// through untouched so the static file fallbacks still get a chance.
+pub async fn handle_rejection(err: Rejection) -> Result<Response, Rejection> {
+    match err.find::<PortalRejection>() {
+        Some(rejection) => {
+            let body = match rejection {
+                PortalRejection::Invalid(fields) => {
+                    serde_json::json!({ "error": rejection.message(), "fields": fields })
+                }
+                _ => serde_json::json!({ "error": rejection.message() }),
+            };
+            let mut resp = with_status(json(&body), rejection.status()).into_response();
+            if let PortalRejection::TooManyRequests(wait) = rejection {
+                let secs = wait
+                    .as_secs()
+                    .saturating_add(u64::from(wait.subsec_nanos() > 0));
+                resp.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(secs.max(1)));
+            }
+            Ok(resp)
+        }
+        None => Err(err),
+    }
+}
@@ -1,15 +1,14 @@
-use crate::state::get_state;
+use crate::{auth::verify::TokenVerifier, state::AppState};
 use artisan_middleware::{
     api::token::SimpleLoginRequest,
     dusa_collection_utils::{core::logger::LogLevel, log},
 };
 use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
 use sqlx::Row;
 use uuid::Uuid;
 
-use crate::database::connection::get_db_pool;
-
-use super::helper::{get_base_url, peek_exp_from_jwt_unverified, peek_sub_from_jwt_unverified};
+use super::helper::{peek_exp_from_jwt_unverified, peek_sub_from_jwt_unverified};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -25,6 +24,38 @@ pub struct SessionData {
     pub expires_at: DateTime<Utc>,
 }
 
+/// Where and when a session was opened, and when it was last used. Stored
+/// next to the tokens in `sessions`:
+///
+/// ```sql
+/// ALTER TABLE sessions
+///     ADD COLUMN created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+///     ADD COLUMN last_seen  DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+///     ADD COLUMN ip_address VARCHAR(45) NULL,
+///     ADD COLUMN user_agent VARCHAR(512) NULL;
+/// ```
+#[derive(Clone, Debug)]
+pub struct SessionMeta {
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl SessionMeta {
+    /// Metadata for a session opened now.
+    pub fn new(ip: Option<String>, user_agent: Option<String>) -> Self {
+        let now = Utc::now();
+        Self {
+            created_at: now,
+            last_seen: now,
+            ip,
+            // Keeps the column bounded whatever the client sends.
+            user_agent: user_agent.map(|ua| ua.chars().take(512).collect()),
+        }
+    }
+}
+
 fn timestamp_to_u64<S>(dt: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -41,7 +72,76 @@ where
         .ok_or_else(|| serde::de::Error::custom("invalid timestamp"))
 }
 
-pub async fn login(request: SimpleLoginRequest) -> Result<SessionData, String> {
+/// `sub` of the access token and `exp` of the refresh token, both checked
+/// against the upstream's keys.
+async fn verified_identity(
+    verifier: &TokenVerifier,
+    token: &str,
+    refresh: &str,
+) -> Result<(String, u64), String> {
+    let auth = verifier.verify(token, false).await;
+    let refresh = verifier.verify(refresh, true).await;
+    match (auth, refresh) {
+        (Ok(auth), Ok(refresh)) => auth
+            .sub
+            .map(|sub| (sub, refresh.exp))
+            .ok_or_else(|| "access token has no sub".to_owned()),
+        (Err(err), _) | (_, Err(err)) => {
+            log!(LogLevel::Error, "login(): upstream token {}", err);
+            Err(err)
+        }
+    }
+}
+
+fn unverified_identity(token: &str, refresh: &str) -> Result<(String, u64), String> {
+    // Decode expiration from refresh JWT
+    let expiration_raw: u64 = peek_exp_from_jwt_unverified(refresh).map_err(|err| {
+        log!(
+            LogLevel::Error,
+            "login(): peek_exp_from_jwt_unverified failed: {}",
+            err.to_string()
+        );
+        err.to_string()
+    })?;
+
+    let user_id: String = peek_sub_from_jwt_unverified(token).map_err(|err| {
+        log!(
+            LogLevel::Error,
+            "login(): peek_sub_from_jwt_unverified failed: {}",
+            err.to_string()
+        );
+        err.to_string()
+    })?;
+
+    Ok((user_id, expiration_raw))
+}
+
+/// Why a login didn't produce a session.
+#[derive(Debug)]
+pub enum LoginError {
+    /// The upstream turned the credentials down.
+    Refused,
+    /// Anything else: the upstream was unreachable or answered nonsense.
+    Failed(String),
+}
+
+impl From<String> for LoginError {
+    fn from(err: String) -> Self {
+        LoginError::Failed(err)
+    }
+}
+
+/// What the upstream made of a login.
+pub enum LoginStep {
+    Session(SessionData),
+    /// A second factor is needed before tokens are issued.
+    Challenge {
+        challenge: String,
+        methods: Vec<String>,
+    },
+}
+
+pub async fn login(state: &AppState, request: SimpleLoginRequest) -> Result<LoginStep, LoginError> {
     // Log entry into login function (at Debug level).
     log!(
         LogLevel::Debug,
@@ -49,17 +149,17 @@ pub async fn login(request: SimpleLoginRequest) -> Result<SessionData, String> {
         request.email
     );
 
-    let client = get_state().http_client.clone();
+    let client = state.http_client.clone();
 
     // Log that we are about to send the HTTP request.
     log!(
         LogLevel::Debug,
-        "login(): sending POST to {}auth/login",
-        get_base_url()
+        "login(): sending POST to {}",
+        state.upstream_url("auth/login")
     );
 
     let response = client
-        .post(&format!("{}auth/login", get_base_url()))
+        .post(state.upstream_url("auth/login"))
         .json(&serde_json::json!({ "email": request.email, "password": request.password }))
         .send()
         .await
@@ -96,69 +196,35 @@ pub async fn login(request: SimpleLoginRequest) -> Result<SessionData, String> {
             json.to_string()
         );
 
-        let token = json.get("auth").and_then(|t| t.as_str());
-        let refresh = json.get("refresh").and_then(|t| t.as_str());
-
-        match (token, refresh) {
-            (Some(token), Some(refresh)) => {
-                log!(
-                    LogLevel::Info,
-                    "login(): successfully got auth and refresh tokens"
-                );
-
-                // Decode expiration from refresh JWT
-                let expiration_raw: u64 =
-                    peek_exp_from_jwt_unverified(&refresh).map_err(|err| {
-                        log!(
-                            LogLevel::Error,
-                            "login(): peek_exp_from_jwt_unverified failed: {}",
-                            err.to_string()
-                        );
-                        err.to_string()
-                    })?;
-
-                let session_id: String = Uuid::new_v4().to_string();
-                let user_id: String = peek_sub_from_jwt_unverified(&token).map_err(|err| {
-                    log!(
-                        LogLevel::Error,
-                        "login(): peek_sub_from_jwt_unverified failed: {}",
-                        err.to_string()
-                    );
-                    err.to_string()
-                })?;
-
-                let auth_jwt: String = token.to_string();
-                let refresh_jwt: String = refresh.to_string();
-
-                #[allow(deprecated)]
-                let expires_at: DateTime<Utc> = DateTime::from_timestamp(expiration_raw as i64, 0)
-                    .unwrap_or(DateTime::default());
-
-                let session = SessionData {
-                    session_id: session_id.clone(),
-                    user_id: user_id.clone(),
-                    auth_jwt,
-                    refresh_jwt,
-                    expires_at,
-                };
-
-                log!(
-                    LogLevel::Info,
-                    "login success user {} session {}",
-                    user_id,
-                    session_id
-                );
+        if json.get("mfa_required").and_then(|v| v.as_bool()) == Some(true) {
+            let Some(challenge) = json.get("challenge").and_then(|c| c.as_str()) else {
+                log!(LogLevel::Error, "MFA required but no challenge given");
+                return Err(LoginError::Failed("Login failed".into()));
+            };
+            let methods = json
+                .get("methods")
+                .and_then(|m| m.as_array())
+                .map(|methods| {
+                    methods
+                        .iter()
+                        .filter_map(|m| m.as_str().map(str::to_owned))
+                        .collect()
+                })
+                .unwrap_or_else(|| vec!["totp".to_owned()]);
+            log!(
+                LogLevel::Info,
+                "login(): {} has to answer an MFA challenge",
+                request.email
+            );
+            return Ok(LoginStep::Challenge {
+                challenge: challenge.to_owned(),
+                methods,
+            });
+        }
 
-                return Ok(session);
-            }
-            _ => {
-                log!(
-                    LogLevel::Error,
-                    "Failed to parse both refresh and auth token"
-                );
-                return Err("Login failed".into());
-            }
-        };
+        session_from_tokens(state, &json)
+            .await
+            .map(LoginStep::Session)
     } else {
         log!(
             LogLevel::Warn,
@@ -166,7 +232,110 @@ pub async fn login(request: SimpleLoginRequest) -> Result<SessionData, String> {
             response.status()
         );
 
-        return Err("Login failed".into());
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(LoginError::Refused),
+            _ => Err(LoginError::Failed("Login failed".into())),
+        }
+    }
+}
+
+/// Upstream path that takes the code for a challenged login.
+const MFA_VERIFY_PATH: &str = "auth/login/mfa";
+
+/// Second step of a challenged login: trades the challenge and the user's
+/// code for tokens. A wrong code comes back as `Refused`.
+pub async fn login_mfa(
+    state: &AppState,
+    challenge: &str,
+    code: &str,
+) -> Result<SessionData, LoginError> {
+    let response = state
+        .http_client
+        .post(state.upstream_url(MFA_VERIFY_PATH))
+        .json(&serde_json::json!({ "challenge": challenge, "code": code }))
+        .send()
+        .await
+        .map_err(|err| {
+            log!(LogLevel::Error, "login_mfa(): HTTP request failed: {}", err);
+            err.to_string()
+        })?;
+
+    match response.status() {
+        status if status.is_success() => {
+            let json: serde_json::Value = response.json().await.map_err(|err| {
+                log!(
+                    LogLevel::Error,
+                    "login_mfa(): failed to parse JSON: {}",
+                    err
+                );
+                err.to_string()
+            })?;
+            session_from_tokens(state, &json).await
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(LoginError::Refused),
+        status => {
+            log!(
+                LogLevel::Warn,
+                "MFA verification failed with status {}",
+                status
+            );
+            Err(LoginError::Failed("Login failed".into()))
+        }
+    }
+}
+
+/// A new session from the `auth` and `refresh` tokens of a login response.
+async fn session_from_tokens(
+    state: &AppState,
+    json: &serde_json::Value,
+) -> Result<SessionData, LoginError> {
+    let token = json.get("auth").and_then(|t| t.as_str());
+    let refresh = json.get("refresh").and_then(|t| t.as_str());
+
+    match (token, refresh) {
+        (Some(token), Some(refresh)) => {
+            log!(
+                LogLevel::Info,
+                "login(): successfully got auth and refresh tokens"
+            );
+
+            let session_id: String = Uuid::new_v4().to_string();
+            let (user_id, expiration_raw) = match &state.token_verifier {
+                Some(verifier) => verified_identity(verifier, token, refresh).await?,
+                None => unverified_identity(token, refresh)?,
+            };
+
+            let auth_jwt: String = token.to_string();
+            let refresh_jwt: String = refresh.to_string();
+
+            #[allow(deprecated)]
+            let expires_at: DateTime<Utc> =
+                DateTime::from_timestamp(expiration_raw as i64, 0).unwrap_or_default();
+
+            let session = SessionData {
+                session_id: session_id.clone(),
+                user_id: user_id.clone(),
+                auth_jwt,
+                refresh_jwt,
+                expires_at,
+            };
+
+            log!(
+                LogLevel::Info,
+                "login success user {} session {}",
+                user_id,
+                session_id
+            );
+
+            Ok(session)
+        }
+        _ => {
+            log!(
+                LogLevel::Error,
+                "Failed to parse both refresh and auth token"
+            );
+            Err(LoginError::Failed("Login failed".into()))
+        }
     }
 }
 
@@ -185,7 +354,6 @@ pub async fn lookup_session(
     .await
     .map_err(|e| {
         log!(LogLevel::Error, "lookup_session query error: {}", e);
-        ()
     })?;
 
     log!(
@@ -201,7 +369,6 @@ pub async fn lookup_session(
                 "lookup_session column user_id error: {}",
                 e
             );
-            ()
         })?;
         let auth_jwt: String = r.try_get("auth_jwt").map_err(|e| {
             log!(
@@ -209,7 +376,6 @@ pub async fn lookup_session(
                 "lookup_session column auth_jwt error: {}",
                 e
             );
-            ()
         })?;
         let refresh_jwt: String = r.try_get("refresh_jwt").map_err(|e| {
             log!(
@@ -217,7 +383,6 @@ pub async fn lookup_session(
                 "lookup_session column refresh_jwt error: {}",
                 e
             );
-            ()
         })?;
         let expires_at: DateTime<Utc> = r.try_get("expires_at").map_err(|e| {
             log!(
@@ -225,7 +390,6 @@ pub async fn lookup_session(
                 "lookup_session column expires_at error: {}",
                 e
             );
-            ()
         })?;
 
         log!(LogLevel::Trace, "lookup_session fetched user {}", user_id);
@@ -272,7 +436,141 @@ pub async fn lookup_session(
     }
 }
 
-pub async fn update_session_auth(auth: String, session_id: String) -> Result<String, String> {
+pub async fn insert_session(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    session: &SessionData,
+    meta: &SessionMeta,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO sessions (session_id, user_id, auth_jwt, refresh_jwt, expires_at,
+                                 created_at, last_seen, ip_address, user_agent)
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(&session.session_id)
+    .bind(&session.user_id)
+    .bind(&session.auth_jwt)
+    .bind(&session.refresh_jwt)
+    .bind(session.expires_at)
+    .bind(meta.created_at)
+    .bind(meta.last_seen)
+    .bind(&meta.ip)
+    .bind(&meta.user_agent)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Moves a session to a new id, keeping everything else.
+pub async fn rename_session(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    old_id: &str,
+    new_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE sessions SET session_id = ? WHERE session_id = ?")
+        .bind(new_id)
+        .bind(old_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn touch_session(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    session_id: &str,
+    last_seen: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE sessions SET last_seen = ? WHERE session_id = ?")
+        .bind(last_seen)
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn session_meta(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    session_id: &str,
+) -> Result<SessionMeta, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT created_at, last_seen, ip_address, user_agent
+        FROM sessions
+        WHERE session_id = ?"#,
+    )
+    .bind(session_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(SessionMeta {
+        created_at: row.try_get("created_at")?,
+        last_seen: row.try_get("last_seen")?,
+        ip: row.try_get("ip_address")?,
+        user_agent: row.try_get("user_agent")?,
+    })
+}
+
+/// The user's live sessions with their metadata, most recently used first.
+pub async fn list_user_sessions(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    user_id: &str,
+) -> Result<Vec<(SessionData, SessionMeta)>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT session_id, user_id, auth_jwt, refresh_jwt, expires_at,
+                  created_at, last_seen, ip_address, user_agent
+        FROM sessions
+        WHERE user_id = ? AND expires_at > NOW()
+        ORDER BY last_seen DESC"#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut sessions = Vec::new();
+    for row in rows {
+        sessions.push((
+            SessionData {
+                session_id: row.try_get("session_id")?,
+                user_id: row.try_get("user_id")?,
+                auth_jwt: row.try_get("auth_jwt")?,
+                refresh_jwt: row.try_get("refresh_jwt")?,
+                expires_at: row.try_get("expires_at")?,
+            },
+            SessionMeta {
+                created_at: row.try_get("created_at")?,
+                last_seen: row.try_get("last_seen")?,
+                ip: row.try_get("ip_address")?,
+                user_agent: row.try_get("user_agent")?,
+            },
+        ));
+    }
+    Ok(sessions)
+}
+
+pub async fn delete_session(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    session_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM sessions WHERE session_id = ?")
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_user_sessions(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_session_auth(
+    pool: &sqlx::Pool<sqlx::MySql>,
+    auth: String,
+    session_id: String,
+) -> Result<String, String> {
     log!(
         LogLevel::Debug,
         "update_session_auth(): about to update auth_jwt for session_id={}",
@@ -288,7 +586,7 @@ pub async fn update_session_auth(auth: String, session_id: String) -> Result<Str
     match sqlx::query(query_str)
         .bind(&auth)
         .bind(&session_id)
-        .execute(get_db_pool())
+        .execute(pool)
         .await
     {
         Ok(res) => {
@@ -311,15 +609,36 @@ pub async fn update_session_auth(auth: String, session_id: String) -> Result<Str
     }
 }
 
-pub async fn load_active_sessions(
+/// Stores a rotated refresh token along with the access token it came with;
+/// the session now lives as long as the new refresh token.
+pub async fn rotate_session_tokens(
     pool: &sqlx::Pool<sqlx::MySql>,
-) -> Result<Vec<SessionData>, sqlx::Error> {
-    // we delete the old session data here because I can't be vexed to learn triggers on a mysql db
+    session_id: &str,
+    auth: &str,
+    refresh: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
     sqlx::query(
-        r#"DELETE FROM sessions WHERE expires_at <= NOW()"#,
+        r#"UPDATE sessions
+           SET auth_jwt = ?, refresh_jwt = ?, expires_at = ?
+           WHERE session_id = ?"#,
     )
+    .bind(auth)
+    .bind(refresh)
+    .bind(expires_at)
+    .bind(session_id)
     .execute(pool)
     .await?;
+    Ok(())
+}
+
+pub async fn load_active_sessions(
+    pool: &sqlx::Pool<sqlx::MySql>,
+) -> Result<Vec<SessionData>, sqlx::Error> {
+    // we delete the old session data here because I can't be vexed to learn triggers on a mysql db
+    sqlx::query(r#"DELETE FROM sessions WHERE expires_at <= NOW()"#)
+        .execute(pool)
+        .await?;
 
     let rows = sqlx::query(
         r#"SELECT session_id, user_id, auth_jwt, refresh_jwt, expires_at
@@ -340,4 +659,4 @@ pub async fn load_active_sessions(
         });
     }
     Ok(sessions)
-}
\ No newline at end of file
+}
@@ -0,0 +1,140 @@
+//! CSRF protection for cookie-authenticated requests. Every session has a
+//! token, an HMAC of its id, handed to the frontend in a readable cookie at
+//! login. Anything but GET/HEAD/OPTIONS must echo it in `X-CSRF-Token`, and
+//! must not come from an origin outside `ALLOWED_ORIGINS`.
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use warp::{
+    Filter,
+    http::Method,
+    path::FullPath,
+    reject::{self, Rejection},
+};
+
+use crate::{
+    api::{common::PortalRejection::CsrfRejected, user_sessions::session_handle},
+    state::{SharedState, with_state},
+};
+
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Signs session ids into CSRF tokens. Without `CSRF_SECRET` a random key
+/// is used, so tokens don't survive a restart or work across instances.
+pub struct CsrfKey {
+    key: hmac::Key,
+}
+
+impl CsrfKey {
+    pub fn new(secret: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = match secret {
+            Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            None => {
+                log!(
+                    LogLevel::Warn,
+                    "CSRF_SECRET not set, CSRF tokens won't survive a restart"
+                );
+                let mut bytes = [0u8; 32];
+                SystemRandom::new()
+                    .fill(&mut bytes)
+                    .map_err(|_| "no randomness for the CSRF key")?;
+                hmac::Key::new(hmac::HMAC_SHA256, &bytes)
+            }
+        };
+        Ok(Self { key })
+    }
+
+    /// The token for the session with `session_id`.
+    pub fn token(&self, session_id: &str) -> String {
+        URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, session_id.as_bytes()))
+    }
+
+    fn verify(&self, session_id: &str, token: &str) -> bool {
+        URL_SAFE_NO_PAD
+            .decode(token)
+            .is_ok_and(|tag| hmac::verify(&self.key, session_id.as_bytes(), &tag).is_ok())
+    }
+}
+
+/// `scheme://host[:port]` of a URL, the form `Origin` uses.
+fn origin_of(url: &str) -> Option<&str> {
+    let (scheme, rest) = url.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    Some(&url[..scheme.len() + 3 + end])
+}
+
+/// Routes that act without a session, where a session token proves nothing.
+/// Matched whole, so proxied paths that end the same way still need a token.
+const SESSIONLESS: [&str; 4] = [
+    "/api/auth/login",
+    "/api/auth/login/verify",
+    "/api/auth/password-reset/request",
+    "/api/auth/password-reset/confirm",
+];
+
+/// Passes safe requests through; everything else needs an allowed origin
+/// and, once there is a session cookie, its token. Login and password
+/// resets have no session yet, so only the origin is checked there.
+pub fn csrf_guard(state: SharedState) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_state(state)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("referer"))
+        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::header::optional::<String>(CSRF_HEADER))
+        .and_then(
+            |state: SharedState,
+             method: Method,
+             path: FullPath,
+             origin: Option<String>,
+             referer: Option<String>,
+             cookies: Option<String>,
+             token: Option<String>| async move {
+                if matches!(method, Method::GET | Method::HEAD | Method::OPTIONS) {
+                    return Ok(());
+                }
+
+                let source = origin
+                    .as_deref()
+                    .or_else(|| referer.as_deref().and_then(origin_of));
+                if let Some(source) = source
+                    && !state.config.allowed_origins.iter().any(|o| o == source)
+                {
+                    log!(LogLevel::Warn, "{} request from {} refused", method, source);
+                    return Err(reject::custom(CsrfRejected(
+                        "Cross-origin request refused".to_owned(),
+                    )));
+                }
+
+                if SESSIONLESS.contains(&path.as_str()) {
+                    return Ok(());
+                }
+                let Some(session_id) = cookies
+                    .as_deref()
+                    .and_then(|c| state.config.session_cookie.read(c))
+                else {
+                    return Ok(());
+                };
+                match token {
+                    Some(token) if state.csrf.verify(&session_id, &token) => Ok(()),
+                    _ => {
+                        log!(
+                            LogLevel::Warn,
+                            "{} without a valid CSRF token for session {}",
+                            method,
+                            session_handle(&session_id)
+                        );
+                        Err(reject::custom(CsrfRejected(
+                            "Missing or invalid CSRF token".to_owned(),
+                        )))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
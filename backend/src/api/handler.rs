@@ -1,10 +1,22 @@
 use crate::api::cache::CachedResponse;
-use crate::state::get_state;
+use crate::state::{AppState, SharedState};
 use crate::updater::spawn_session_refresh;
 use crate::{
-    api::{common::PortalRejection::Whoops, helper::get_base_url},
-    auth::token::get_token,
-    database::connection::get_db_pool,
+    api::{
+        common::{
+            FieldError,
+            PortalRejection::{BadRequest, Invalid, TooManyRequests, Unauthorized, Whoops},
+        },
+        helper::{
+            activity_rejection, percent_decoded, require_instance, token_rejection, unresolved_path,
+        },
+        session_cookie::with_cookies,
+    },
+    auth::{
+        login_limit::{AttemptKeys, Reservation},
+        revoke::revoke_session_tokens,
+        token::get_token,
+    },
 };
 use artisan_middleware::{
     api::token::SimpleLoginRequest,
@@ -12,139 +24,234 @@ use artisan_middleware::{
     portal::{ApiResponse, RunnerSummary},
 };
 use bytes::Bytes;
-use cookie::CookieBuilder;
+use serde::Deserialize;
 use serde_json::Value as JsonValue;
-use std::time::{Duration, Instant};
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
 use warp::hyper::Body;
 use warp::{
-    http::header::{HeaderValue, SET_COOKIE},
+    Reply,
+    http::{StatusCode, header::HeaderValue},
     reply::Response,
 };
 
-use super::cookie::{SessionData, login};
+use super::cookie::{LoginError, LoginStep, SessionData, SessionMeta, login, login_mfa};
 
 pub async fn login_handler(
+    state: SharedState,
+    host: Option<String>,
+    client_ip: Option<IpAddr>,
+    user_agent: Option<String>,
     login_data: SimpleLoginRequest,
-) -> Result<impl warp::Reply, warp::Rejection> {
+) -> Result<Response, warp::Rejection> {
     log!(
         LogLevel::Debug,
         "login_handler called for {}",
         login_data.email
     );
-    return match login(login_data).await {
-        Ok(session) => {
-            sqlx::query(
-                r#"INSERT INTO sessions (session_id, user_id, auth_jwt, refresh_jwt, expires_at)
-                   VALUES (?, ?, ?, ?, ?)"#,
-            )
-            .bind(&session.session_id)
-            .bind(&session.user_id)
-            .bind(&session.auth_jwt)
-            .bind(&session.refresh_jwt)
-            .bind(session.expires_at)
-            .execute(get_db_pool())
-            .await
-            .map_err(|e| {
-                log!(
-                    LogLevel::Error,
-                    "DB insert error for {}: {}",
-                    session.session_id,
-                    e
-                );
-                warp::reject::custom(Whoops(e.to_string()))
-            })?;
+    let email = login_data.email.clone();
+    let attempt = throttle_login(&state, &email, client_ip).await?;
 
-            get_state()
-                .session_cache
-                .insert(session.session_id.clone(), session.clone())
-                .await;
-            spawn_session_refresh(session.clone());
+    match login(&state, login_data).await {
+        Ok(LoginStep::Session(session)) => {
+            attempt.succeeded().await;
+            start_session(&state, host, client_ip, user_agent, session).await
+        }
+        // The email's failures stay counted until the code is right too.
+        Ok(LoginStep::Challenge { challenge, methods }) => {
+            let ttl = state.config.mfa_pending_ttl;
+            let pending = state
+                .pending_logins
+                .create(challenge, email, ttl)
+                .await
+                .map_err(|e| warp::reject::custom(Whoops(e)))?;
+            let body = warp::reply::json(&serde_json::json!({
+                "mfa_required": true,
+                "pending_login": pending,
+                "methods": methods,
+                "expires_in": ttl.as_secs(),
+            }));
+            Ok(warp::reply::with_status(body, StatusCode::ACCEPTED).into_response())
+        }
+        Err(LoginError::Refused) => {
+            attempt.failed().await;
+            Err(warp::reject::custom(Unauthorized(
+                "Invalid email or password".to_owned(),
+            )))
+        }
+        Err(LoginError::Failed(err)) => Err(warp::reject::custom(Whoops(err))),
+    }
+}
 
-            #[allow(deprecated)]
-            let cookie = CookieBuilder::new("session_id", session.session_id.clone())
-                .http_only(true)
-                .path("/")
-                .secure(true)
-                .finish();
+/// Second step of a login that was answered with an MFA challenge.
+#[derive(Deserialize)]
+pub struct VerifyLogin {
+    pub pending_login: String,
+    pub code: String,
+}
 
-            let set_cookie_header = cookie.to_string();
+pub async fn login_verify_handler(
+    state: SharedState,
+    host: Option<String>,
+    client_ip: Option<IpAddr>,
+    user_agent: Option<String>,
+    verify: VerifyLogin,
+) -> Result<Response, warp::Rejection> {
+    let code = verify.code.trim();
+    if !(6..=8).contains(&code.len()) || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(warp::reject::custom(Invalid(vec![FieldError::new(
+            "code",
+            "format",
+            "expected a 6 to 8 digit code".to_owned(),
+        )])));
+    }
+    let Some(pending) = state.pending_logins.get(&verify.pending_login).await else {
+        return Err(warp::reject::custom(Unauthorized(
+            "Login expired, sign in again".to_owned(),
+        )));
+    };
+    let attempt = throttle_login(&state, &pending.email, client_ip).await?;
 
-            let header_value = HeaderValue::from_str(&set_cookie_header)
-                .expect("cookie.to_string() returned invalid header‐value");
+    match login_mfa(&state, &pending.challenge, code).await {
+        Ok(session) => {
+            state.pending_logins.remove(&verify.pending_login).await;
+            attempt.succeeded().await;
+            start_session(&state, host, client_ip, user_agent, session).await
+        }
+        Err(LoginError::Refused) => {
+            attempt.failed().await;
+            state.pending_logins.failed(&verify.pending_login).await;
+            Err(warp::reject::custom(Unauthorized(
+                "Invalid code".to_owned(),
+            )))
+        }
+        Err(LoginError::Failed(err)) => Err(warp::reject::custom(Whoops(err))),
+    }
+}
 
+/// Reserves a login attempt for `email` from `client_ip`, to be settled
+/// with the outcome.
+pub(crate) async fn throttle_login<'a>(
+    state: &'a AppState,
+    email: &str,
+    client_ip: Option<IpAddr>,
+) -> Result<Reservation<'a>, warp::Rejection> {
+    let keys = AttemptKeys::new(email, client_ip);
+    state.login_limiter.check(keys).await.map_err(|wait| {
+        log!(
+            LogLevel::Warn,
+            "login for {} from {:?} throttled for {:?}",
+            email,
+            client_ip,
+            wait
+        );
+        warp::reject::custom(TooManyRequests(wait))
+    })
+}
+
+/// Stores a freshly logged-in session and hands out its cookies.
+async fn start_session(
+    state: &SharedState,
+    host: Option<String>,
+    client_ip: Option<IpAddr>,
+    user_agent: Option<String>,
+    session: SessionData,
+) -> Result<Response, warp::Rejection> {
+    let meta = SessionMeta::new(client_ip.map(|ip| ip.to_string()), user_agent);
+    state
+        .sessions
+        .insert_with_meta(&session, &meta)
+        .await
+        .map_err(|e| {
             log!(
-                LogLevel::Debug,
-                "session {} inserted in DB",
-                session.session_id
+                LogLevel::Error,
+                "DB insert error for {}: {}",
+                session.session_id,
+                e
             );
+            warp::reject::custom(Whoops(e))
+        })?;
 
-            let body = format!("Logged in as {}.", session.user_id);
-            let reply = warp::reply::with_header(body, SET_COOKIE, header_value);
+    state
+        .session_cache
+        .insert(session.session_id.clone(), session.clone())
+        .await;
+    spawn_session_refresh(state.clone(), session.clone());
+
+    let cookies = &state.config.session_cookie;
+    let session_cookie = cookies.session(host.as_deref(), &session);
+    let csrf_cookie = cookies.csrf(
+        host.as_deref(),
+        &session,
+        state.csrf.token(&session.session_id),
+    );
 
-            Ok(reply)
-        }
-        Err(err) => Err(warp::reject::custom(Whoops(err))),
-    };
+    log!(
+        LogLevel::Debug,
+        "session {} inserted in DB",
+        session.session_id
+    );
+
+    let body = format!("Logged in as {}.", session.user_id);
+    Ok(with_cookies(body, [session_cookie, csrf_cookie]))
 }
 
-pub async fn logout_handler(session: SessionData) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn logout_handler(
+    state: SharedState,
+    host: Option<String>,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
     log!(LogLevel::Info, "logout for session {}", session.session_id);
+    revoke_session_tokens(&state, &session).await;
     // Delete the row (if it exists):
-    if let Err(e) = sqlx::query("DELETE FROM sessions WHERE session_id = ?")
-        .bind(&session.session_id)
-        .execute(get_db_pool())
-        .await
-    {
+    if let Err(e) = state.sessions.delete(&session.session_id).await {
         log!(LogLevel::Error, "Error deleting session from DB: {}", e);
         // don't send an error so the frontend still clears the cookie
     }
 
-    get_state().session_cache.remove(&session.session_id).await;
+    state.session_cache.remove(&session.session_id).await;
 
-    // Build a “clear cookie”:
-    #[allow(deprecated)]
-    let clear = cookie::Cookie::build("session_id")
-        .max_age(cookie::time::Duration::seconds(0))
-        .path("/")
-        .http_only(true)
-        .secure(true)
-        .finish();
-
-    let set_clear_header = clear.to_string();
-
-    let header_value = HeaderValue::from_str(&set_clear_header)
-        .expect("clear.to_string() returned invalid header‐value");
-
-    let reply = warp::reply::with_header("", SET_COOKIE, header_value);
+    let reply = with_cookies("", state.config.session_cookie.clear(host.as_deref()));
     log!(LogLevel::Debug, "session {} logged out", session.session_id);
     Ok(reply)
 }
 
-pub async fn logout_all_handler(session: SessionData) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn logout_all_handler(
+    state: SharedState,
+    host: Option<String>,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
     log!(LogLevel::Info, "logout all for user {}", session.user_id);
-    if let Err(e) = sqlx::query("DELETE FROM sessions WHERE user_id = ?")
-        .bind(&session.user_id)
-        .execute(get_db_pool())
-        .await
-    {
+    match state.sessions.list_user(&session.user_id).await {
+        Ok(sessions) => {
+            let mut revocations = JoinSet::new();
+            for (data, _) in sessions {
+                let state = state.clone();
+                revocations.spawn(async move { revoke_session_tokens(&state, &data).await });
+            }
+            revocations.join_all().await;
+        }
+        // Sessions still go; only the current one's tokens can be revoked.
+        Err(e) => {
+            log!(
+                LogLevel::Error,
+                "listing sessions of {}: {}",
+                session.user_id,
+                e
+            );
+            revoke_session_tokens(&state, &session).await;
+        }
+    }
+    if let Err(e) = state.sessions.delete_user(&session.user_id).await {
         log!(LogLevel::Error, "Error deleting sessions from DB: {}", e);
     }
 
-    get_state()
-        .session_cache
-        .remove_user(&session.user_id)
-        .await;
-
-    let clear = cookie::Cookie::build("session_id")
-        .max_age(cookie::time::Duration::seconds(0))
-        .path("/")
-        .http_only(true)
-        .secure(true);
-
-    let header_value = HeaderValue::from_str(&clear.to_string())
-        .expect("clear.to_string() returned invalid header‐value");
+    state.session_cache.remove_user(&session.user_id).await;
 
-    let reply = warp::reply::with_header("", SET_COOKIE, header_value);
+    let reply = with_cookies("", state.config.session_cookie.clear(host.as_deref()));
     log!(
         LogLevel::Debug,
         "all sessions logged out for {}",
@@ -153,15 +260,60 @@ pub async fn logout_all_handler(session: SessionData) -> Result<impl warp::Reply
     Ok(reply)
 }
 
-pub async fn whoami_handler(session: SessionData) -> Result<impl warp::Reply, warp::Rejection> {
+/// The session's CSRF token, for a frontend that lost its cookie copy.
+pub async fn csrf_handler(
+    state: SharedState,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&serde_json::json!({
+        "csrf_token": state.csrf.token(&session.session_id),
+    })))
+}
+
+/// Rate limiter counters for Prometheus. Needs `METRICS_TOKEN` as a bearer
+/// token; without one configured the route doesn't exist.
+pub async fn metrics_handler(
+    state: SharedState,
+    authorization: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(expected) = &state.config.metrics_token else {
+        return Err(warp::reject::not_found());
+    };
+    let presented = authorization
+        .as_deref()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compared without an early exit, so timing doesn't leak the token.
+    let differs = presented.len() != expected.len()
+        || presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            != 0;
+    if differs {
+        return Err(warp::reject::custom(Unauthorized(
+            "Invalid metrics token".to_owned(),
+        )));
+    }
+    Ok(warp::reply::with_header(
+        state.rate_limiter.metrics(),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+pub async fn whoami_handler(
+    state: SharedState,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
     log!(LogLevel::Debug, "whoami for session {}", session.session_id);
-    match get_token(session.clone()).await {
+    match get_token(&state, session.clone()).await {
         Ok(token) => {
-            let client = get_state().http_client.clone();
+            let client = state.http_client.clone();
 
             // First: get user_id
             let response_me = client
-                .get(&format!("{}account/me", get_base_url()))
+                .get(state.upstream_url("account/me"))
                 .bearer_auth(token.clone())
                 .send()
                 .await
@@ -192,7 +344,7 @@ pub async fn whoami_handler(session: SessionData) -> Result<impl warp::Reply, wa
 
             // Then: get role and expiration
             let response = client
-                .post(&format!("{}whoami", get_base_url()))
+                .post(state.upstream_url("whoami"))
                 .bearer_auth(token)
                 .send()
                 .await
@@ -206,9 +358,17 @@ pub async fn whoami_handler(session: SessionData) -> Result<impl warp::Reply, wa
 
                 if let Some(data) = json.get("you") {
                     let expires = data.get("expires").and_then(|v| v.as_u64()).unwrap_or(30);
-                    let reply = warp::reply::json(
-                        &serde_json::json!({ "user_id": username, "expires": expires}),
-                    );
+                    let remaining = state
+                        .session_activity
+                        .check(&state, &session.session_id, false)
+                        .await
+                        .map_err(activity_rejection)?;
+                    let reply = warp::reply::json(&serde_json::json!({
+                        "user_id": username,
+                        "expires": expires,
+                        "idle_remaining": remaining.idle.map(|d| d.as_secs()),
+                        "lifetime_remaining": remaining.lifetime.map(|d| d.as_secs()),
+                    }));
                     log!(
                         LogLevel::Info,
                         "whoami success session {}",
@@ -236,23 +396,26 @@ pub async fn whoami_handler(session: SessionData) -> Result<impl warp::Reply, wa
                 )))
             }
         }
-        Err(err) => Err(warp::reject::custom(Whoops(err.err_mesg.to_string()))),
+        Err(err) => Err(token_rejection(err)),
     }
 }
 
-pub async fn me_handler(session: SessionData) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn me_handler(
+    state: SharedState,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
     log!(
         LogLevel::Debug,
         "me_handler for session {}",
         session.session_id
     );
-    match get_token(session.clone()).await {
+    match get_token(&state, session.clone()).await {
         Ok(token) => {
-            let client = get_state().http_client.clone();
+            let client = state.http_client.clone();
 
             // First: get user_id
             let response_me = client
-                .get(&format!("{}account/me", get_base_url()))
+                .get(state.upstream_url("account/me"))
                 .bearer_auth(token.clone())
                 .send()
                 .await
@@ -282,22 +445,25 @@ pub async fn me_handler(session: SessionData) -> Result<impl warp::Reply, warp::
             log!(LogLevel::Info, "me success session {}", session.session_id);
             Ok(reply)
         }
-        Err(err) => Err(warp::reject::custom(Whoops(err.err_mesg.to_string()))),
+        Err(err) => Err(token_rejection(err)),
     }
 }
 
-pub async fn runners_handler(session: SessionData) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn runners_handler(
+    state: SharedState,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
     log!(
         LogLevel::Debug,
         "runners_handler for session {}",
         session.session_id
     );
-    match get_token(session.clone()).await {
+    match get_token(&state, session.clone()).await {
         Ok(token) => {
-            let client = get_state().http_client.clone();
+            let client = state.http_client.clone();
 
             let response = client
-                .get(&format!("{}runners", get_base_url()))
+                .get(state.upstream_url("runners"))
                 .bearer_auth(token)
                 .send()
                 .await
@@ -326,11 +492,12 @@ pub async fn runners_handler(session: SessionData) -> Result<impl warp::Reply, w
                 )))
             }
         }
-        Err(err) => Err(warp::reject::custom(Whoops(err.err_mesg.to_string()))),
+        Err(err) => Err(token_rejection(err)),
     }
 }
 
 /// This is the “generic” proxy.  It receives:
+///   - state: the shared application state
 ///   - tail: everything after `/api/proxy/` (e.g. `"runners"`, `"account/me"`).
 ///   - method: GET / POST / PUT / DELETE / etc.
 ///   - raw_query: the string after `?`, e.g. `"limit=10&page=2"`.
@@ -338,6 +505,7 @@ pub async fn runners_handler(session: SessionData) -> Result<impl warp::Reply, w
 ///   - session: your SessionData extractor
 ///
 pub async fn generic_proxy_handler(
+    state: SharedState,
     tail: warp::path::Tail,
     method: warp::http::Method,
     raw_query: String,
@@ -353,14 +521,35 @@ pub async fn generic_proxy_handler(
         session.session_id
     );
 
-    let token = get_token(session.clone())
+    let token = get_token(&state, session.clone())
         .await
-        .map_err(|err| warp::reject::custom(Whoops(err.err_mesg.to_string())))?;
+        .map_err(token_rejection)?;
+
+    // The upstream URL would resolve dot segments, so the checks below
+    // could pass on a path other than the one forwarded.
+    if unresolved_path(tail.as_str()) {
+        log!(
+            LogLevel::Warn,
+            "proxy path {:?} refused for {}",
+            tail.as_str(),
+            session.user_id
+        );
+        return Err(warp::reject::custom(BadRequest(
+            "Path must not contain dot or empty segments".to_owned(),
+        )));
+    }
+
+    // Runner control commands are only forwarded for instances the user owns,
+    // judged on the decoded path the upstream will act on.
+    if let Some(rest) = percent_decoded(tail.as_str()).strip_prefix("control/") {
+        let identity = rest.split('/').next().unwrap_or_default();
+        require_instance(&state, &session, identity).await?;
+    }
 
     // ─── Step 2: Build the full backend URL ────────────────────────────────────
     //    e.g. if `tail.as_str()` is "nodes/42" and raw_query is "limit=5",
     //    we want "https://…/v1/nodes/42?limit=5"
-    let mut backend_url = format!("{}{}", get_base_url(), tail.as_str());
+    let mut backend_url = state.upstream_url(tail.as_str());
     if !raw_query.is_empty() {
         backend_url.push('?');
         backend_url.push_str(&raw_query);
@@ -368,7 +557,8 @@ pub async fn generic_proxy_handler(
 
     const TTL_SHORT: Duration = Duration::from_secs(5);
     const TTL_LONG: Duration = Duration::from_secs(30);
-    let cache_key = format!("{}?{}", tail.as_str(), raw_query);
+    // Replies are per user: the upstream answered with this user's token.
+    let cache_key = format!("{}:{}?{}", session.user_id, tail.as_str(), raw_query);
     let is_vm = tail.as_str().starts_with("vms") && !tail.as_str().contains("status");
     let is_runner = tail.as_str().starts_with("runners");
     let is_usage = tail.as_str().starts_with("usage");
@@ -380,7 +570,7 @@ pub async fn generic_proxy_handler(
         } else {
             TTL_SHORT
         };
-        if let Some(cached) = get_state().proxy_cache.get(&cache_key, ttl).await {
+        if let Some(cached) = state.proxy_cache.get(&cache_key, ttl).await {
             log!(LogLevel::Debug, "proxy cache hit {}", cache_key);
             let mut resp = Response::new(Body::from(cached.body));
             *resp.status_mut() = warp::http::StatusCode::from_u16(cached.status)
@@ -400,7 +590,7 @@ pub async fn generic_proxy_handler(
         .map_err(|e| warp::reject::custom(Whoops(e.to_string())))?;
 
     // ─── Step 4: Start building the Reqwest request ───────────────────────────
-    let client = get_state().http_client.clone();
+    let client = state.http_client.clone();
     let mut req_builder = client
         .request(reqwest_method, &backend_url)
         .bearer_auth(token);
@@ -472,7 +662,7 @@ pub async fn generic_proxy_handler(
     } else {
         log!(LogLevel::Debug, "proxy responded {}", status);
         if method == warp::http::Method::GET && (is_vm || is_runner || is_usage || is_logs) {
-            get_state()
+            state
                 .proxy_cache
                 .insert(
                     cache_key,
@@ -1,51 +1,288 @@
-use crate::{api::common::PortalRejection::Unauthorized, database::connection::get_db_pool};
-use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use crate::{
+    api::{
+        api_tokens::token_principal,
+        common::PortalRejection::{BadRequest, Forbidden, Unauthorized, Unavailable, Whoops},
+        rate_limit::RouteClass,
+        session_activity::{ActivityError, expire_session},
+    },
+    auth::ownership::{owns_instance, owns_runner},
+};
+use artisan_middleware::dusa_collection_utils::{
+    core::{
+        errors::{ErrorArrayItem, Errors},
+        logger::LogLevel,
+    },
+    log,
+};
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use serde_json::Value;
-use std::error::Error;
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+};
 use warp::{
     Filter,
+    http::Method,
+    path::FullPath,
     reject::{self, Rejection},
 };
 
-use super::cookie::{SessionData, lookup_session};
-use crate::state::get_state;
+use super::cookie::SessionData;
+use crate::state::{AppState, SharedState, with_state};
 use std::time::Duration;
 
-pub fn get_base_url() -> &'static str {
-    "https://api.artisanhosting.net/v1/"
+/// The request's session. Counts as activity for the idle timeout.
+pub fn with_session(
+    state: SharedState,
+) -> impl Filter<Extract = (SessionData,), Error = Rejection> + Clone {
+    session_filter(state, true)
+}
+
+/// Like `with_session`, for requests that only look at the session (such as
+/// a frontend polling `whoami`) and shouldn't keep it alive.
+pub fn peek_session(
+    state: SharedState,
+) -> impl Filter<Extract = (SessionData,), Error = Rejection> + Clone {
+    session_filter(state, false)
+}
+
+/// The caller on the proxy and secret routes: with `Authorization: Bearer`,
+/// the session of an access token whose scopes cover the route, otherwise
+/// the cookie session as for `with_session`.
+pub fn with_principal(
+    state: SharedState,
+) -> impl Filter<Extract = (SessionData,), Error = Rejection> + Clone {
+    with_state(state)
+        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(
+            |state: SharedState,
+             cookies: Option<String>,
+             authorization: Option<String>,
+             method: Method,
+             path: FullPath| async move {
+                let Some(token) = authorization
+                    .as_deref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                else {
+                    return cookie_session(&state, cookies, true).await;
+                };
+                let path = path.as_str();
+                let path = path.strip_prefix("/api/").unwrap_or(path);
+                // Scopes are checked on the path as forwarded; one that would
+                // be rewritten could pass as a read and land on a write.
+                if unresolved_path(path) {
+                    log!(LogLevel::Warn, "API token refused for path {:?}", path);
+                    return Err(reject::custom(BadRequest(
+                        "Path must not contain dot or empty segments".to_owned(),
+                    )));
+                }
+                let class = RouteClass::for_path(path, &method);
+                token_principal(&state, token.trim(), class).await
+            },
+        )
+}
+
+fn session_filter(
+    state: SharedState,
+    touch: bool,
+) -> impl Filter<Extract = (SessionData,), Error = Rejection> + Clone {
+    with_state(state)
+        .and(warp::header::optional::<String>("cookie"))
+        .and_then(
+            move |state: SharedState, cookies: Option<String>| async move {
+                cookie_session(&state, cookies, touch).await
+            },
+        )
+}
+
+async fn cookie_session(
+    state: &AppState,
+    cookies: Option<String>,
+    touch: bool,
+) -> Result<SessionData, Rejection> {
+    let Some(session_id) = cookies
+        .as_deref()
+        .and_then(|c| state.config.session_cookie.read(c))
+    else {
+        return Err(reject::custom(Unauthorized(
+            "Missing session cookie".to_owned(),
+        )));
+    };
+    // A cookie rotated out moments ago still finds its session.
+    let session_id = state.session_rotation.resolve(&session_id).await;
+    let session = find_session(state, &session_id).await?;
+
+    if let Err(err) = state
+        .session_activity
+        .check(state, &session_id, touch)
+        .await
+    {
+        if let ActivityError::Expired(reason) = err {
+            expire_session(state, &session, reason).await;
+        }
+        return Err(activity_rejection(err));
+    }
+    Ok(session)
 }
 
-pub fn with_session() -> impl Filter<Extract = (SessionData,), Error = Rejection> + Clone {
-    // First, try to grab the "session_id" cookie.
-    // If missing, warp will generate a BadRequest rejection.
-    warp::cookie("session_id").and_then(move |session_id: String| async move {
-        const TTL: Duration = Duration::from_secs(30 * 60);
-        let cache = &get_state().session_cache;
-        if let Some(cached) = cache.get(&session_id, TTL).await {
-            log!(LogLevel::Debug, "session cache hit {}", session_id);
-            return Ok(cached);
+pub(crate) async fn find_session(
+    state: &AppState,
+    session_id: &str,
+) -> Result<SessionData, Rejection> {
+    const TTL: Duration = Duration::from_secs(30 * 60);
+    let cache = &state.session_cache;
+    if let Some(cached) = cache.get(session_id, TTL).await {
+        log!(LogLevel::Debug, "session cache hit {}", session_id);
+        return Ok(cached);
+    }
+
+    match state.sessions.lookup(session_id).await {
+        Ok(user) => {
+            log!(
+                LogLevel::Debug,
+                "validated session {} for user {}",
+                user.session_id,
+                user.user_id
+            );
+            cache.insert(session_id.to_owned(), user.clone()).await;
+            Ok(user)
+        }
+        Err(_) => {
+            log!(LogLevel::Warn, "invalid session {}", session_id);
+            Err(reject::custom(Unauthorized(
+                "Invalid session data".to_owned(),
+            )))
         }
+    }
+}
+
+/// The client's address. Behind a proxy (`TRUST_FORWARDED_FOR`) it is the
+/// last `X-Forwarded-For` entry, the one our proxy appended.
+pub fn client_ip(
+    state: SharedState,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    with_state(state)
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            |state: SharedState, remote: Option<SocketAddr>, forwarded: Option<String>| {
+                let forwarded = forwarded
+                    .filter(|_| state.config.trust_forwarded_for)
+                    .and_then(|f| f.rsplit(',').next()?.trim().parse().ok());
+                forwarded.or(remote.map(|addr| addr.ip()))
+            },
+        )
+}
+
+/// Whether URL parsing would rewrite `path` before it reaches the
+/// upstream: `.` and `..` segments, also percent-encoded, empty segments,
+/// backslashes (a `/` in http URLs) and control characters. Checks made on
+/// such a path don't hold for the one that gets forwarded.
+pub fn unresolved_path(path: &str) -> bool {
+    let trimmed = path.strip_suffix('/').unwrap_or(path);
+    path.contains('\\')
+        || path.chars().any(|c| c.is_ascii_control())
+        || (!trimmed.is_empty()
+            && trimmed.split('/').any(|segment| {
+                let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+                matches!(segment.as_str(), "" | "." | "..")
+            }))
+}
 
-        match lookup_session(get_db_pool(), session_id.clone()).await {
-            Ok(user) => {
-                log!(
-                    LogLevel::Debug,
-                    "validated session {} for user {}",
-                    user.session_id,
-                    user.user_id
-                );
-                cache.insert(session_id.clone(), user.clone()).await;
-                Ok(user)
+/// `path` with its percent-encoded bytes decoded, the way the upstream
+/// reads it. Invalid escapes are kept as they are.
+pub fn percent_decoded(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
             }
-            Err(_) => {
-                log!(LogLevel::Warn, "invalid session {}", session_id);
-                Err(reject::custom(Unauthorized(
-                    "Invalid session data".to_owned(),
-                )))
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
             }
         }
-    })
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Maps a failure to get an access token onto a rejection: a refused
+/// refresh means the session is over and the user has to log in again.
+pub fn token_rejection(err: ErrorArrayItem) -> Rejection {
+    match err.err_type {
+        Errors::AuthenticationError => reject::custom(Unauthorized(err.err_mesg.to_string())),
+        _ => reject::custom(Whoops(err.err_mesg.to_string())),
+    }
+}
+
+/// Maps a refused activity check onto a rejection. A session whose
+/// activity couldn't be read is kept; only this request is refused.
+pub fn activity_rejection(err: ActivityError) -> Rejection {
+    match err {
+        ActivityError::Expired(reason) => {
+            reject::custom(Unauthorized(format!("Session ended by {}", reason)))
+        }
+        ActivityError::Unreadable(e) => {
+            log!(LogLevel::Error, "session activity unreadable: {}", e);
+            reject::custom(Unavailable(
+                "Session could not be checked, try again".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Rejects with `Forbidden` unless the session's user owns `runner_id`.
+pub async fn require_runner(
+    state: &AppState,
+    session: &SessionData,
+    runner_id: &str,
+) -> Result<(), Rejection> {
+    match owns_runner(state, session, runner_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            log!(
+                LogLevel::Warn,
+                "user {} denied access to runner {}",
+                session.user_id,
+                runner_id
+            );
+            Err(reject::custom(Forbidden))
+        }
+        Err(err) => Err(token_rejection(err)),
+    }
+}
+
+/// Rejects with `Forbidden` unless `instance_id` belongs to one of the
+/// session user's runners.
+pub async fn require_instance(
+    state: &AppState,
+    session: &SessionData,
+    instance_id: &str,
+) -> Result<(), Rejection> {
+    match owns_instance(state, session, instance_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            log!(
+                LogLevel::Warn,
+                "user {} denied access to instance {}",
+                session.user_id,
+                instance_id
+            );
+            Err(reject::custom(Forbidden))
+        }
+        Err(err) => Err(token_rejection(err)),
+    }
 }
 
 pub fn peek_exp_from_jwt_unverified(jwt: &str) -> Result<u64, Box<dyn Error>> {
@@ -1,7 +1,16 @@
+pub mod account;
+pub mod api_tokens;
 pub mod cache;
 pub mod common;
 pub mod cookie;
+pub mod csrf;
 mod handler;
 pub mod helper;
+pub mod rate_limit;
 pub mod routes;
 pub mod secret;
+pub mod secret_bulk;
+pub mod secret_view;
+pub mod session_activity;
+pub mod session_cookie;
+pub mod user_sessions;
@@ -0,0 +1,343 @@
+//! Token-bucket rate limiting for the proxy and secret routes, one bucket
+//! per user and route class so a runaway tab can't starve the upstream.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    future::Future,
+    pin::Pin,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use warp::{
+    Filter, Reply,
+    http::{HeaderValue, Method},
+    path::Peek,
+    reject::{self, Rejection},
+    reply::Response,
+};
+
+use crate::{
+    api::{
+        common::PortalRejection::TooManyRequests,
+        cookie::SessionData,
+        helper::{percent_decoded, with_principal},
+    },
+    config::env_or,
+    state::{SharedState, with_state},
+};
+
+/// Idle buckets are swept at most this often.
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RouteClass {
+    ProxyRead,
+    ProxyWrite,
+    Secrets,
+}
+
+impl RouteClass {
+    const ALL: [RouteClass; 3] = [
+        RouteClass::ProxyRead,
+        RouteClass::ProxyWrite,
+        RouteClass::Secrets,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RouteClass::ProxyRead => "proxy_read",
+            RouteClass::ProxyWrite => "proxy_write",
+            RouteClass::Secrets => "secrets",
+        }
+    }
+
+    /// The class called `name`, as in access token scopes.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|class| class.name() == name)
+    }
+
+    /// The class of a request for `path`, relative to `/api/`, judged on
+    /// the decoded segments the upstream sees. Control commands start and
+    /// stop instances, so they are writes whatever their method.
+    pub fn for_path(path: &str, method: &Method) -> Self {
+        let path = percent_decoded(path);
+        let mut segments = path.trim_start_matches('/').split('/');
+        match (segments.next(), segments.next(), method) {
+            (Some("secrets"), _, _) => RouteClass::Secrets,
+            (_, Some("control"), _) => RouteClass::ProxyWrite,
+            (_, _, &Method::GET | &Method::HEAD) => RouteClass::ProxyRead,
+            _ => RouteClass::ProxyWrite,
+        }
+    }
+}
+
+/// One class's bucket size and refill rate.
+#[derive(Clone, Copy)]
+pub struct BucketConfig {
+    /// Requests allowed in a burst. Zero turns limiting off for the class.
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl BucketConfig {
+    fn from_env(class: RouteClass, burst: u32, per_minute: u32) -> Self {
+        let prefix = format!("RATE_LIMIT_{}", class.name().to_uppercase());
+        Self {
+            burst: env_or(&format!("{}_BURST", prefix), burst),
+            per_minute: env_or(&format!("{}_PER_MIN", prefix), per_minute),
+        }
+    }
+
+    fn per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+/// `RATE_LIMIT_<CLASS>_BURST` and `RATE_LIMIT_<CLASS>_PER_MIN` for the
+/// `PROXY_READ`, `PROXY_WRITE` and `SECRETS` classes.
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    pub proxy_read: BucketConfig,
+    pub proxy_write: BucketConfig,
+    pub secrets: BucketConfig,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        Self {
+            proxy_read: BucketConfig::from_env(RouteClass::ProxyRead, 60, 120),
+            proxy_write: BucketConfig::from_env(RouteClass::ProxyWrite, 10, 30),
+            secrets: BucketConfig::from_env(RouteClass::Secrets, 20, 60),
+        }
+    }
+
+    fn bucket(&self, class: RouteClass) -> BucketConfig {
+        match class {
+            RouteClass::ProxyRead => self.proxy_read,
+            RouteClass::ProxyWrite => self.proxy_write,
+            RouteClass::Secrets => self.secrets,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What a request left in its bucket, sent back as `X-RateLimit-*`.
+pub struct Quota {
+    limit: u32,
+    remaining: u32,
+    /// Until the bucket is full again.
+    reset: Duration,
+}
+
+impl Quota {
+    fn apply(self, reply: impl Reply) -> Response {
+        let mut resp = reply.into_response();
+        let headers = resp.headers_mut();
+        headers.insert("x-ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert(
+            "x-ratelimit-reset",
+            HeaderValue::from(self.reset.as_secs_f64().ceil() as u64),
+        );
+        resp
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<(String, RouteClass), Bucket>,
+    pruned: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Counters {
+    allowed: AtomicU64,
+    throttled: AtomicU64,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+    counters: [Counters; 3],
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// Takes a token from the user's bucket for `class`, or says how long
+    /// until one is available.
+    pub fn take(&self, user_id: &str, class: RouteClass) -> Result<Option<Quota>, Duration> {
+        let config = self.config.bucket(class);
+        if config.burst == 0 {
+            return Ok(None);
+        }
+        let rate = config.per_second();
+        let capacity = f64::from(config.burst);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.pruned.is_none_or(|at| at.elapsed() >= PRUNE_EVERY) {
+            // A bucket that has refilled is no different from a new one.
+            buckets.map.retain(|(_, class), b| {
+                let rate = self.config.bucket(*class).per_second();
+                b.tokens + b.updated.elapsed().as_secs_f64() * rate
+                    < f64::from(self.config.bucket(*class).burst)
+            });
+            buckets.pruned = Some(now);
+        }
+
+        let bucket = buckets
+            .map
+            .entry((user_id.to_owned(), class))
+            .or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+        bucket.tokens = (bucket.tokens + (now - bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+
+        let counters = &self.counters[class as usize];
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            counters.allowed.fetch_add(1, Ordering::Relaxed);
+            Ok(Some(Quota {
+                limit: config.burst,
+                remaining: bucket.tokens as u32,
+                reset: refill_time(capacity - bucket.tokens, rate),
+            }))
+        } else {
+            counters.throttled.fetch_add(1, Ordering::Relaxed);
+            Err(refill_time(1.0 - bucket.tokens, rate))
+        }
+    }
+
+    /// Request counters in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        self.write_counter(
+            &mut out,
+            "dashboard_rate_limit_allowed_total",
+            "Requests let through by the rate limiter.",
+            |c| &c.allowed,
+        );
+        self.write_counter(
+            &mut out,
+            "dashboard_rate_limit_throttled_total",
+            "Requests refused with 429 by the rate limiter.",
+            |c| &c.throttled,
+        );
+        out
+    }
+
+    fn write_counter(
+        &self,
+        out: &mut String,
+        name: &str,
+        help: &str,
+        pick: impl Fn(&Counters) -> &AtomicU64,
+    ) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for class in RouteClass::ALL {
+            let value = pick(&self.counters[class as usize]).load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}{{class=\"{}\"}} {}", name, class.name(), value);
+        }
+    }
+}
+
+fn refill_time(tokens: f64, rate: f64) -> Duration {
+    match rate > 0.0 {
+        true => Duration::from_secs_f64(tokens / rate),
+        false => Duration::MAX,
+    }
+}
+
+/// A matched route still waiting for its caller, who is resolved once by
+/// [`rate_limited`] and handed in.
+pub type Deferred = Box<
+    dyn FnOnce(SessionData) -> Pin<Box<dyn Future<Output = Result<Response, Rejection>> + Send>>
+        + Send,
+>;
+
+/// Wraps `handler` as the [`Deferred`] end of a rate limited route.
+pub fn deferred<H, Fut, R>(handler: H) -> Deferred
+where
+    H: FnOnce(SessionData) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<R, Rejection>> + Send + 'static,
+    R: Reply,
+{
+    Box::new(move |session| {
+        Box::pin(async move { handler(session).await.map(Reply::into_response) })
+    })
+}
+
+/// Puts `route` behind the rate limiter for requests under `/api/<prefix>`.
+/// The caller is resolved here, charged against their bucket, and passed to
+/// the route's handler.
+pub fn rate_limited<F>(
+    state: SharedState,
+    prefix: &'static str,
+    route: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (Deferred,), Error = Rejection> + Clone + Send + Sync,
+{
+    warp::path::peek()
+        .and_then(move |peek: Peek| async move {
+            match peek.segments().next() == Some(prefix) {
+                true => Ok(()),
+                false => Err(reject::not_found()),
+            }
+        })
+        .untuple_one()
+        .and(with_state(state.clone()))
+        .and(warp::method())
+        .and(warp::path::peek())
+        .and(with_principal(state))
+        .and_then(
+            |state: SharedState, method: Method, path: Peek, session: SessionData| async move {
+                let class = RouteClass::for_path(path.as_str(), &method);
+                state
+                    .rate_limiter
+                    .take(&session.user_id, class)
+                    .map(|quota| (quota, session.clone()))
+                    .map_err(|wait| {
+                        log!(
+                            LogLevel::Warn,
+                            "{} throttled on {} for {:?}",
+                            session.user_id,
+                            class.name(),
+                            wait
+                        );
+                        reject::custom(TooManyRequests(wait))
+                    })
+            },
+        )
+        .untuple_one()
+        .and(route)
+        .and_then(
+            |quota: Option<Quota>, session: SessionData, handler: Deferred| async move {
+                let reply = handler(session).await?;
+                Ok::<_, Rejection>(match quota {
+                    Some(quota) => quota.apply(reply),
+                    None => reply,
+                })
+            },
+        )
+}
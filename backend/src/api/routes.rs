@@ -2,29 +2,53 @@ use artisan_middleware::api::token::SimpleLoginRequest;
 use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
 use warp::{Filter, http::header, reject::Rejection, reply::Reply};
 
-use crate::api::{
-    handler::{generic_proxy_handler, me_handler, runners_handler},
-    secret::secret_routes,
+use crate::{
+    api::{
+        account::{
+            ResetPasswordConfirm, ResetPasswordRequest, UpdateEmail, UpdatePassword,
+            change_password_handler, password_reset_confirm_handler,
+            password_reset_request_handler, update_email_handler,
+        },
+        api_tokens::{
+            CreateToken, create_token_handler, list_tokens_handler, revoke_token_handler,
+        },
+        common::handle_rejection,
+        csrf::{CSRF_HEADER, csrf_guard},
+        handler::{generic_proxy_handler, me_handler, runners_handler},
+        rate_limit::{deferred, rate_limited},
+        secret::secret_routes,
+        session_cookie::rotate_session_id,
+        user_sessions::{list_sessions_handler, revoke_session_handler},
+    },
+    state::{SharedState, with_state},
 };
 
 use super::{
-    handler::{login_handler, logout_all_handler, logout_handler, whoami_handler},
-    helper::with_session,
+    handler::{
+        VerifyLogin, csrf_handler, login_handler, login_verify_handler, logout_all_handler,
+        logout_handler, metrics_handler, whoami_handler,
+    },
+    helper::{client_ip, peek_session, with_session},
 };
 
-pub async fn create_api_routes() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+pub async fn create_api_routes(
+    state: SharedState,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     log!(LogLevel::Debug, "creating API routes");
-    let testing_origin = "http://localhost:3800";
-    let release = "https://dashboard.artisanhosting.net";
-
     let cors = warp::cors()
-        .allow_origin(testing_origin)
-        .allow_origin(release)
+        .allow_origins(state.config.allowed_origins.iter().map(String::as_str))
         .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
         .allow_headers(vec![
             header::CONTENT_TYPE,
             header::COOKIE,
             header::AUTHORIZATION,
+            header::HeaderName::from_static(CSRF_HEADER),
+        ])
+        .expose_headers(vec![
+            "retry-after",
+            "x-ratelimit-limit",
+            "x-ratelimit-remaining",
+            "x-ratelimit-reset",
         ])
         .allow_credentials(true);
 
@@ -36,37 +60,97 @@ pub async fn create_api_routes() -> impl Filter<Extract = impl Reply, Error = Re
     // login
     let login = warp::post()
         .and(warp::path!("auth" / "login"))
+        .and(with_state(state.clone()))
+        .and(warp::header::optional::<String>("host"))
+        .and(client_ip(state.clone()))
+        .and(warp::header::optional::<String>("user-agent"))
         .and(warp::body::json::<SimpleLoginRequest>())
         .and_then(login_handler);
 
+    let login_verify = warp::post()
+        .and(warp::path!("auth" / "login" / "verify"))
+        .and(with_state(state.clone()))
+        .and(warp::header::optional::<String>("host"))
+        .and(client_ip(state.clone()))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::body::json::<VerifyLogin>())
+        .and_then(login_verify_handler);
+
     // login
     let logout = warp::post()
         .and(warp::path!("auth" / "logout"))
-        .and(with_session())
+        .and(with_state(state.clone()))
+        .and(warp::header::optional::<String>("host"))
+        .and(with_session(state.clone()))
         .and_then(logout_handler);
 
     let logout_all = warp::post()
         .and(warp::path!("auth" / "logout_all"))
-        .and(with_session())
+        .and(with_state(state.clone()))
+        .and(warp::header::optional::<String>("host"))
+        .and(with_session(state.clone()))
         .and_then(logout_all_handler);
 
+    let csrf = warp::get()
+        .and(warp::path!("auth" / "csrf"))
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
+        .and_then(csrf_handler);
+
+    let sessions = warp::get()
+        .and(warp::path!("auth" / "sessions"))
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
+        .and_then(list_sessions_handler);
+
+    let revoke_session = warp::delete()
+        .and(warp::path!("auth" / "sessions" / String))
+        .and(with_state(state.clone()))
+        .and(warp::header::optional::<String>("host"))
+        .and(with_session(state.clone()))
+        .and_then(revoke_session_handler);
+
+    let tokens = warp::get()
+        .and(warp::path!("auth" / "tokens"))
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
+        .and_then(list_tokens_handler);
+
+    let create_token = warp::post()
+        .and(warp::path!("auth" / "tokens"))
+        .and(with_state(state.clone()))
+        .and(client_ip(state.clone()))
+        .and(with_session(state.clone()))
+        .and(warp::body::json::<CreateToken>())
+        .and_then(create_token_handler);
+
+    let revoke_token = warp::delete()
+        .and(warp::path!("auth" / "tokens" / String))
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
+        .and_then(revoke_token_handler);
+
     let whoami = warp::get()
         .and(warp::path!("auth" / "whoami"))
-        .and(with_session())
+        .and(with_state(state.clone()))
+        .and(peek_session(state.clone()))
         .and_then(whoami_handler);
 
     let me = warp::get()
         .and(warp::path!("auth" / "me"))
-        .and(with_session())
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
         .and_then(me_handler);
 
     let runners = warp::get()
         .and(warp::path!("runners"))
-        .and(with_session())
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
         .and_then(runners_handler);
 
     let proxy_route = warp::path("proxy")
         // .and(warp::path("proxy"))
+        .and(with_state(state.clone()))
         .and(warp::path::tail())
         .and(warp::method())
         .and(warp::query::raw().or_else(|_| async { Ok::<_, warp::Rejection>((String::new(),)) }))
@@ -74,51 +158,81 @@ pub async fn create_api_routes() -> impl Filter<Extract = impl Reply, Error = Re
             warp::body::bytes()
                 .or_else(|_| async { Ok::<_, warp::Rejection>((bytes::Bytes::new(),)) }),
         )
-        .and(with_session())
-        .and_then(generic_proxy_handler);
-
-    // // update email
-    // let update_email = warp::put()
-    //     .and(warp::path!("account" / "email"))
-    //     .and(with_auth().await)
-    //     .and(warp::body::json::<UpdateEmail>())
-    //     .and_then(update_email_handler);
-
-    // // change password
-    // let change_password = warp::put()
-    //     .and(warp::path!("account" / "password"))
-    //     .and(with_auth().await)
-    //     .and(warp::body::json::<UpdatePassword>())
-    //     .and_then(change_password_handler);
-
-    // // password reset request
-    // let pw_reset_req = warp::post()
-    //     .and(warp::path!("auth" / "password-reset" / "request"))
-    //     .and(warp::body::json::<ResetPasswordRequest>())
-    //     .and_then(password_reset_request_handler);
-
-    // // password reset confirm
-    // let pw_reset_conf = warp::post()
-    //     .and(warp::path!("auth" / "password-reset" / "confirm"))
-    //     .and(warp::body::json::<ResetPasswordResponse>())
-    //     .and_then(password_reset_confirm_handler);
-
-    let routes = warp::path("api")
+        .map(|state, tail, method, query, body| {
+            deferred(move |session| {
+                generic_proxy_handler(state, tail, method, query, body, session)
+            })
+        });
+
+    let metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .and(with_state(state.clone()))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(metrics_handler);
+
+    let update_email = warp::put()
+        .and(warp::path!("account" / "email"))
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
+        .and(warp::body::json::<UpdateEmail>())
+        .and_then(update_email_handler);
+
+    let change_password = warp::put()
+        .and(warp::path!("account" / "password"))
+        .and(with_state(state.clone()))
+        .and(with_session(state.clone()))
+        .and(warp::body::json::<UpdatePassword>())
+        .and_then(change_password_handler);
+
+    let pw_reset_req = warp::post()
+        .and(warp::path!("auth" / "password-reset" / "request"))
+        .and(with_state(state.clone()))
+        .and(client_ip(state.clone()))
+        .and(warp::body::json::<ResetPasswordRequest>())
+        .and_then(password_reset_request_handler);
+
+    let pw_reset_conf = warp::post()
+        .and(warp::path!("auth" / "password-reset" / "confirm"))
+        .and(with_state(state.clone()))
+        .and(client_ip(state.clone()))
+        .and(warp::body::json::<ResetPasswordConfirm>())
+        .and_then(password_reset_confirm_handler);
+
+    let routes = with_state(state.clone())
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::path("api"))
+        .and(csrf_guard(state.clone()))
         .and(
             login
+                .or(login_verify)
                 .or(logout)
                 .or(logout_all)
+                .or(csrf)
+                .or(sessions)
+                .or(revoke_session)
+                .or(tokens)
+                .or(create_token)
+                .or(revoke_token)
                 .or(whoami)
                 .or(runners)
-                .or(proxy_route)
+                .or(rate_limited(state.clone(), "proxy", proxy_route))
                 .or(me)
-                .or(secret_routes()), // .or(get_pretty)
-                                      // .or(update_email)
-                                      // .or(change_password)
-                                      // .or(pw_reset_req)
-                                      // .or(pw_reset_conf),
+                .or(metrics)
+                .or(rate_limited(
+                    state.clone(),
+                    "secrets",
+                    secret_routes(state.clone()),
+                ))
+                // .or(get_pretty)
+                .or(update_email)
+                .or(change_password)
+                .or(pw_reset_req)
+                .or(pw_reset_conf),
         )
+        .and_then(rotate_session_id)
         // .or(v1_preflight)
+        .recover(handle_rejection)
         .with(cors);
 
     log!(LogLevel::Debug, "API routes ready");
@@ -1,12 +1,213 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::{
-    api::{common::PortalRejection::Whoops, helper::with_session},
-    grpc::secret_service,
-    state::get_state,
+    api::{
+        common::{
+            FieldError,
+            PortalRejection::{
+                BadRequest, Forbidden, Invalid, NotImplemented, Timeout, Unavailable, Whoops,
+            },
+        },
+        helper::{require_runner, token_rejection},
+        rate_limit::{Deferred, deferred},
+        secret_bulk::bulk_routes,
+        secret_view::{MaskMode, SecretEntry, SecretListResponse, ValueEncoding},
+    },
+    auth::token::get_token,
+    config::{env_list, env_or},
+    grpc::{Caller, SecretAuthMode, SecretClient, secret_service},
+    state::{AppState, SharedState, with_state},
 };
 use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use regex::Regex;
 use serde::Deserialize;
 use warp::Filter;
 
+const DEFAULT_KEY_PATTERN: &str = "^[A-Z_][A-Z0-9_]{0,127}$";
+const DEFAULT_RESERVED_KEYS: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "SHELL",
+    "PWD",
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+];
+
+/// Rules every key and value must pass before it is forwarded to the secret
+/// service.
+///
+/// | Variable                   | Default                  |
+/// |----------------------------|--------------------------|
+/// | `SECRET_KEY_PATTERN`       | `^[A-Z_][A-Z0-9_]{0,127}$` |
+/// | `SECRET_RESERVED_KEYS`     | `PATH,HOME,USER,...`     |
+/// | `SECRET_MAX_VALUE_BYTES`   | `65536`                  |
+/// | `SECRET_MAX_KEYS_PER_ENV`  | `200`                    |
+/// | `SECRET_ENV_KEY_LIMITS`    | unset, e.g. `prod=500,dev=50` |
+pub struct SecretPolicy {
+    pub key_pattern: Regex,
+    pub reserved_keys: HashSet<String>,
+    pub max_value_bytes: usize,
+    pub max_keys_per_env: usize,
+    pub env_key_limits: HashMap<String, usize>,
+}
+
+impl SecretPolicy {
+    pub fn from_env() -> Result<Self, regex::Error> {
+        let pattern =
+            std::env::var("SECRET_KEY_PATTERN").unwrap_or_else(|_| DEFAULT_KEY_PATTERN.to_owned());
+        let reserved = env_list("SECRET_RESERVED_KEYS").unwrap_or_else(|| {
+            DEFAULT_RESERVED_KEYS
+                .iter()
+                .map(|k| k.to_string())
+                .collect()
+        });
+        let env_key_limits = env_list("SECRET_ENV_KEY_LIMITS")
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|entry| {
+                let (env, limit) = entry.split_once('=')?;
+                Some((env.trim().to_owned(), limit.trim().parse().ok()?))
+            })
+            .collect();
+
+        Ok(Self {
+            key_pattern: Regex::new(&pattern)?,
+            reserved_keys: reserved.iter().map(|k| k.to_uppercase()).collect(),
+            max_value_bytes: env_or("SECRET_MAX_VALUE_BYTES", 64 * 1024),
+            max_keys_per_env: env_or("SECRET_MAX_KEYS_PER_ENV", 200),
+            env_key_limits,
+        })
+    }
+
+    pub fn key_limit(&self, environment_id: &str) -> usize {
+        self.env_key_limits
+            .get(environment_id)
+            .copied()
+            .unwrap_or(self.max_keys_per_env)
+    }
+
+    /// Checks a key and the value about to be written under it. `value_field`
+    /// is the body field the value came from, so errors point at it.
+    pub fn validate(&self, key: &str, value: &[u8], value_field: &str) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if key.is_empty() {
+            errors.push(FieldError::new(
+                "secret_key",
+                "required",
+                "key must not be empty".to_owned(),
+            ));
+        } else if !self.key_pattern.is_match(key) {
+            errors.push(FieldError::new(
+                "secret_key",
+                "pattern",
+                format!("key must match {}", self.key_pattern.as_str()),
+            ));
+        }
+        if self.reserved_keys.contains(&key.to_uppercase()) {
+            errors.push(FieldError::new(
+                "secret_key",
+                "reserved",
+                format!("{} is a reserved name", key),
+            ));
+        }
+        if value.len() > self.max_value_bytes {
+            errors.push(FieldError::new(
+                value_field,
+                "too_large",
+                format!("value exceeds {} bytes", self.max_value_bytes),
+            ));
+        }
+        errors
+    }
+}
+
+/// The secret service client acting for the session's user, or 503 when
+/// the dashboard runs without one.
+pub(crate) async fn secret_client(
+    state: &AppState,
+    session: &crate::api::cookie::SessionData,
+) -> Result<SecretClient, warp::Rejection> {
+    let client = state.secret_client.as_ref().ok_or_else(|| {
+        warp::reject::custom(Unavailable("secret service is not configured".to_owned()))
+    })?;
+
+    let bearer = match client.auth_mode() {
+        SecretAuthMode::Bearer => Some(
+            get_token(state, session.clone())
+                .await
+                .map_err(token_rejection)?,
+        ),
+        _ => None,
+    };
+
+    Ok(client.for_caller(Caller {
+        user_id: session.user_id.clone(),
+        session_id: session.session_id.clone(),
+        bearer,
+    }))
+}
+
+/// Maps a gRPC failure onto the closest HTTP error.
+pub(crate) fn secret_error(status: tonic::Status) -> warp::Rejection {
+    let message = status.message().to_owned();
+    warp::reject::custom(match status.code() {
+        tonic::Code::Unavailable => Unavailable(message),
+        tonic::Code::DeadlineExceeded => Timeout(message),
+        tonic::Code::PermissionDenied => Forbidden,
+        tonic::Code::InvalidArgument => BadRequest(message),
+        tonic::Code::Unimplemented => NotImplemented(message),
+        _ => Whoops(status.to_string()),
+    })
+}
+
+/// Refuses to add `secret_key` if the environment is already at its limit.
+async fn check_key_limit(
+    state: &AppState,
+    client: &SecretClient,
+    runner_id: &str,
+    environment_id: &str,
+    secret_key: &str,
+) -> Result<(), warp::Rejection> {
+    let limit = state.config.secret_policy.key_limit(environment_id);
+    // Keys only: counting them shouldn't pull every value through here.
+    let current = client
+        .get_all_secrets(secret_service::GetAllSecretsRequest {
+            runner_id: runner_id.to_owned(),
+            environment_id: environment_id.to_owned(),
+            version: 0,
+            keys_only: true,
+        })
+        .await
+        .map_err(secret_error)?
+        .vals;
+    if !current.iter().any(|kv| kv.key == secret_key) && current.len() >= limit {
+        return Err(warp::reject::custom(Invalid(vec![FieldError::new(
+            "environment_id",
+            "key_limit",
+            format!("{} already holds {} keys", environment_id, limit),
+        )])));
+    }
+    Ok(())
+}
+
+fn check_entry(
+    state: &AppState,
+    key: &str,
+    value: &str,
+    value_field: &str,
+) -> Result<(), warp::Rejection> {
+    let errors = state
+        .config
+        .secret_policy
+        .validate(key, value.as_bytes(), value_field);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(Invalid(errors)))
+    }
+}
+
 #[derive(Deserialize)]
 pub struct SecretQuery {
     pub runner_id: String,
@@ -14,35 +215,150 @@ pub struct SecretQuery {
     pub version: Option<i64>,
 }
 
-pub fn secret_routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+/// Query of `GET /api/secrets/versions`, which lists every version of a
+/// key and so takes no version or rendering options.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretVersionsQuery {
+    pub runner_id: String,
+    pub environment_id: String,
+    pub secret_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct SecretKeyQuery {
+    pub runner_id: String,
+    pub environment_id: String,
+    pub secret_key: String,
+    /// Omitted (or `0`) resolves to the latest version.
+    pub version: Option<i64>,
+    #[serde(default)]
+    pub encoding: ValueEncoding,
+    #[serde(default)]
+    pub mask: MaskMode,
+}
+
+/// Body of `POST /api/secrets/create`. The actor is taken from the session,
+/// never from the client.
+#[derive(Deserialize)]
+pub struct CreateSecretBody {
+    pub runner_id: String,
+    pub environment_id: String,
+    pub secret_key: String,
+    pub value: String,
+}
+
+impl CreateSecretBody {
+    fn into_request(self, actor: &str) -> secret_service::CreateSecretRequest {
+        secret_service::CreateSecretRequest {
+            runner_id: self.runner_id,
+            environment_id: self.environment_id,
+            secret_key: self.secret_key,
+            value: self.value.into_bytes(),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+/// Body of `PUT /api/secrets/update`.
+#[derive(Deserialize)]
+pub struct UpdateSecretBody {
+    pub runner_id: String,
+    pub environment_id: String,
+    pub secret_key: String,
+    pub new_value: String,
+}
+
+impl UpdateSecretBody {
+    fn into_request(self, actor: &str) -> secret_service::UpdateSecretRequest {
+        secret_service::UpdateSecretRequest {
+            runner_id: self.runner_id,
+            environment_id: self.environment_id,
+            secret_key: self.secret_key,
+            new_value: self.new_value.into_bytes(),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+/// Body of `DELETE /api/secrets/delete`.
+#[derive(Deserialize)]
+pub struct DeleteSecretBody {
+    pub runner_id: String,
+    pub environment_id: String,
+    pub secret_key: String,
+}
+
+impl DeleteSecretBody {
+    fn into_request(self, actor: &str) -> secret_service::DeleteSecretRequest {
+        secret_service::DeleteSecretRequest {
+            runner_id: self.runner_id,
+            environment_id: self.environment_id,
+            secret_key: self.secret_key,
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+/// The secret routes, to be wrapped in `rate_limited`, which supplies the
+/// caller.
+pub fn secret_routes(
+    state: SharedState,
+) -> impl Filter<Extract = (Deferred,), Error = warp::Rejection> + Clone {
     let list = warp::get()
         .and(warp::path!("secrets" / "list"))
+        .and(with_state(state.clone()))
         .and(warp::query::<SecretQuery>())
-        .and(with_session())
-        .and_then(list_handler);
+        .map(|state, query| deferred(move |session| list_handler(state, query, session)));
+
+    let get = warp::get()
+        .and(warp::path!("secrets" / "get"))
+        .and(with_state(state.clone()))
+        .and(warp::query::<SecretKeyQuery>())
+        .map(|state, query| deferred(move |session| get_handler(state, query, session)));
+
+    let versions = warp::get()
+        .and(warp::path!("secrets" / "versions"))
+        .and(with_state(state.clone()))
+        .and(warp::query::<SecretVersionsQuery>())
+        .map(|state, query| deferred(move |session| versions_handler(state, query, session)));
 
     let create = warp::post()
         .and(warp::path!("secrets" / "create"))
-        .and(warp::body::json::<secret_service::CreateSecretRequest>())
-        .and(with_session())
-        .and_then(create_handler);
+        .and(with_state(state.clone()))
+        .and(warp::body::json::<CreateSecretBody>())
+        .map(|state, body| deferred(move |session| create_handler(state, body, session)));
 
     let update = warp::put()
         .and(warp::path!("secrets" / "update"))
-        .and(warp::body::json::<secret_service::UpdateSecretRequest>())
-        .and(with_session())
-        .and_then(update_handler);
+        .and(with_state(state.clone()))
+        .and(warp::body::json::<UpdateSecretBody>())
+        .map(|state, body| deferred(move |session| update_handler(state, body, session)));
 
     let delete = warp::delete()
         .and(warp::path!("secrets" / "delete"))
-        .and(warp::body::json::<secret_service::DeleteSecretRequest>())
-        .and(with_session())
-        .and_then(delete_handler);
+        .and(with_state(state.clone()))
+        .and(warp::body::json::<DeleteSecretBody>())
+        .map(|state, body| deferred(move |session| delete_handler(state, body, session)));
 
-    list.or(create).or(update).or(delete)
+    list.or(get)
+        .unify()
+        .or(versions)
+        .unify()
+        .or(create)
+        .unify()
+        .or(update)
+        .unify()
+        .or(delete)
+        .unify()
+        .or(bulk_routes(state))
+        .unify()
 }
 
+/// Keys and metadata only. Values are revealed one key at a time by
+/// `get_handler`, so every reveal is audited on its own.
 async fn list_handler(
+    state: SharedState,
     query: SecretQuery,
     session: crate::api::cookie::SessionData,
 ) -> Result<impl warp::Reply, warp::Rejection> {
@@ -51,11 +367,13 @@ async fn list_handler(
         "list secrets session {}",
         session.session_id
     );
-    let mut client = get_state().secret_client.clone();
+    require_runner(&state, &session, &query.runner_id).await?;
+    let client = secret_client(&state, &session).await?;
     let req = secret_service::GetAllSecretsRequest {
-        runner_id: query.runner_id,
-        environment_id: query.environment_id,
+        runner_id: query.runner_id.clone(),
+        environment_id: query.environment_id.clone(),
         version: query.version.unwrap_or_default(),
+        keys_only: true,
     };
     match client.get_all_secrets(req).await {
         Ok(resp) => {
@@ -64,7 +382,12 @@ async fn list_handler(
                 "list secrets success session {}",
                 session.session_id
             );
-            Ok(warp::reply::json(&resp))
+            let secrets = resp.vals.iter().map(SecretEntry::metadata).collect();
+            Ok(warp::reply::json(&SecretListResponse {
+                runner_id: query.runner_id,
+                environment_id: query.environment_id,
+                secrets,
+            }))
         }
         Err(e) => {
             log!(
@@ -73,22 +396,145 @@ async fn list_handler(
                 session.session_id,
                 e
             );
-            Err(warp::reject::custom(Whoops(e.to_string())))
+            Err(secret_error(e))
+        }
+    }
+}
+
+/// Every request that returns secret values leaves an audit line naming the
+/// user, session and key. The secret service records the actor as well.
+pub(crate) fn audit_reveal(
+    session: &crate::api::cookie::SessionData,
+    runner_id: &str,
+    environment_id: &str,
+    secret_key: &str,
+) {
+    log!(
+        LogLevel::Info,
+        "audit: user {} session {} revealed {}/{}/{}",
+        session.user_id,
+        session.session_id,
+        runner_id,
+        environment_id,
+        secret_key
+    );
+}
+
+/// Reveals a single value. This is the only per-key read, so it is audited.
+async fn get_handler(
+    state: SharedState,
+    query: SecretKeyQuery,
+    session: crate::api::cookie::SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log!(
+        LogLevel::Debug,
+        "get secret {} v{} session {}",
+        query.secret_key,
+        query.version.unwrap_or_default(),
+        session.session_id
+    );
+    require_runner(&state, &session, &query.runner_id).await?;
+    audit_reveal(
+        &session,
+        &query.runner_id,
+        &query.environment_id,
+        &query.secret_key,
+    );
+    let client = secret_client(&state, &session).await?;
+    let req = secret_service::GetSecretRequest {
+        runner_id: query.runner_id,
+        environment_id: query.environment_id,
+        secret_key: query.secret_key,
+        version: query.version.unwrap_or_default(),
+        actor: session.user_id.clone(),
+    };
+    match client.get_secret(req).await {
+        Ok(resp) => {
+            log!(
+                LogLevel::Info,
+                "get secret success session {}",
+                session.session_id
+            );
+            Ok(warp::reply::json(&SecretEntry::from_get(
+                &resp,
+                query.encoding,
+                query.mask,
+            )))
+        }
+        Err(e) => {
+            log!(
+                LogLevel::Error,
+                "get secret failed for {}: {}",
+                session.session_id,
+                e
+            );
+            Err(secret_error(e))
+        }
+    }
+}
+
+async fn versions_handler(
+    state: SharedState,
+    query: SecretVersionsQuery,
+    session: crate::api::cookie::SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log!(
+        LogLevel::Debug,
+        "list versions of {} session {}",
+        query.secret_key,
+        session.session_id
+    );
+    require_runner(&state, &session, &query.runner_id).await?;
+    let client = secret_client(&state, &session).await?;
+    let req = secret_service::ListSecretVersionsRequest {
+        runner_id: query.runner_id,
+        environment_id: query.environment_id,
+        secret_key: query.secret_key,
+    };
+    match client.list_secret_versions(req).await {
+        Ok(resp) => {
+            log!(
+                LogLevel::Info,
+                "list versions success session {}",
+                session.session_id
+            );
+            Ok(warp::reply::json(&resp))
+        }
+        Err(e) => {
+            log!(
+                LogLevel::Error,
+                "list versions failed for {}: {}",
+                session.session_id,
+                e
+            );
+            Err(secret_error(e))
         }
     }
 }
 
 async fn create_handler(
-    req: secret_service::CreateSecretRequest,
+    state: SharedState,
+    body: CreateSecretBody,
     session: crate::api::cookie::SessionData,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     log!(
         LogLevel::Debug,
         "create secret {} session {}",
-        req.secret_key,
+        body.secret_key,
         session.session_id
     );
-    let mut client = get_state().secret_client.clone();
+    require_runner(&state, &session, &body.runner_id).await?;
+    check_entry(&state, &body.secret_key, &body.value, "value")?;
+    let client = secret_client(&state, &session).await?;
+    check_key_limit(
+        &state,
+        &client,
+        &body.runner_id,
+        &body.environment_id,
+        &body.secret_key,
+    )
+    .await?;
+    let req = body.into_request(&session.user_id);
     match client.create_secret(req).await {
         Ok(resp) => {
             log!(
@@ -105,22 +551,26 @@ async fn create_handler(
                 session.session_id,
                 e
             );
-            Err(warp::reject::custom(Whoops(e.to_string())))
+            Err(secret_error(e))
         }
     }
 }
 
 async fn update_handler(
-    req: secret_service::UpdateSecretRequest,
+    state: SharedState,
+    body: UpdateSecretBody,
     session: crate::api::cookie::SessionData,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     log!(
         LogLevel::Debug,
         "update secret {} session {}",
-        req.secret_key,
+        body.secret_key,
         session.session_id
     );
-    let mut client = get_state().secret_client.clone();
+    require_runner(&state, &session, &body.runner_id).await?;
+    check_entry(&state, &body.secret_key, &body.new_value, "new_value")?;
+    let req = body.into_request(&session.user_id);
+    let client = secret_client(&state, &session).await?;
     match client.update_secret(req).await {
         Ok(resp) => {
             log!(
@@ -137,22 +587,25 @@ async fn update_handler(
                 session.session_id,
                 e
             );
-            Err(warp::reject::custom(Whoops(e.to_string())))
+            Err(secret_error(e))
         }
     }
 }
 
 async fn delete_handler(
-    req: secret_service::DeleteSecretRequest,
+    state: SharedState,
+    body: DeleteSecretBody,
     session: crate::api::cookie::SessionData,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     log!(
         LogLevel::Debug,
         "delete secret {} session {}",
-        req.secret_key,
+        body.secret_key,
         session.session_id
     );
-    let mut client = get_state().secret_client.clone();
+    require_runner(&state, &session, &body.runner_id).await?;
+    let req = body.into_request(&session.user_id);
+    let client = secret_client(&state, &session).await?;
     match client.delete_secret(req).await {
         Ok(resp) => {
             log!(
@@ -169,7 +622,7 @@ async fn delete_handler(
                 session.session_id,
                 e
             );
-            Err(warp::reject::custom(Whoops(e.to_string())))
+            Err(secret_error(e))
         }
     }
 }
@@ -0,0 +1,690 @@
+use std::collections::{BTreeMap, HashMap};
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use warp::{
+    Filter,
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+};
+
+use crate::{
+    api::{
+        common::PortalRejection::{BadRequest, Whoops},
+        cookie::SessionData,
+        helper::require_runner,
+        rate_limit::{Deferred, deferred},
+        secret::{SecretPolicy, audit_reveal, secret_client, secret_error},
+    },
+    grpc::{SecretClient, secret_service},
+    state::{SharedState, with_state},
+};
+
+/// Largest import body we accept.
+const MAX_IMPORT_BYTES: u64 = 1024 * 1024;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretFormat {
+    #[default]
+    Dotenv,
+    Json,
+}
+
+/// What to do with keys that already exist with a different value.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    Overwrite,
+    #[default]
+    Skip,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub runner_id: String,
+    pub environment_id: String,
+    #[serde(default)]
+    pub format: SecretFormat,
+    #[serde(default)]
+    pub policy: ConflictPolicy,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub runner_id: String,
+    pub environment_id: String,
+    #[serde(default)]
+    pub format: SecretFormat,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeAction {
+    Create,
+    Update,
+    Unchanged,
+    Skip,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeStatus {
+    /// Dry run: nothing was sent to the secret service.
+    Planned,
+    Applied,
+    /// Already held this value; nothing to write.
+    Unchanged,
+    /// Held a different value and the conflict policy kept it.
+    Skipped,
+    Failed,
+}
+
+/// The outcome for a single key of a bulk operation.
+#[derive(Serialize)]
+pub struct KeyResult {
+    pub key: String,
+    pub action: ChangeAction,
+    pub status: ChangeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Byte sizes of the stored and incoming value, where they differ.
+    /// Values themselves are never echoed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_size: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct BulkReport {
+    pub runner_id: String,
+    pub environment_id: String,
+    pub dry_run: bool,
+    pub results: Vec<KeyResult>,
+}
+
+/// Body of `POST /api/secrets/copy`.
+#[derive(Deserialize)]
+pub struct CopyRequest {
+    pub source_runner_id: String,
+    pub source_environment_id: String,
+    /// Defaults to the source runner.
+    pub target_runner_id: Option<String>,
+    pub target_environment_id: String,
+    /// Only copy these keys. Everything is copied when omitted.
+    pub keys: Option<Vec<String>>,
+    /// Values to use instead of the source's, applied after the key filter.
+    /// Copied values are written byte for byte; overrides are text.
+    #[serde(default)]
+    pub overrides: BTreeMap<String, String>,
+    #[serde(default)]
+    pub policy: ConflictPolicy,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+pub struct PlannedChange {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub action: ChangeAction,
+    /// Size of the stored value this would replace, if it differs.
+    pub old_size: Option<usize>,
+    /// Set when the change breaks the secret policy and must not be applied.
+    pub rejected: Option<String>,
+}
+
+pub fn bulk_routes(
+    state: SharedState,
+) -> impl Filter<Extract = (Deferred,), Error = warp::Rejection> + Clone {
+    let import = warp::post()
+        .and(warp::path!("secrets" / "import"))
+        .and(with_state(state.clone()))
+        .and(warp::query::<ImportQuery>())
+        .and(warp::body::content_length_limit(MAX_IMPORT_BYTES))
+        .and(warp::body::bytes())
+        .map(|state, query, body| {
+            deferred(move |session| import_handler(state, query, body, session))
+        });
+
+    let export = warp::get()
+        .and(warp::path!("secrets" / "export"))
+        .and(with_state(state.clone()))
+        .and(warp::query::<ExportQuery>())
+        .map(|state, query| deferred(move |session| export_handler(state, query, session)));
+
+    let copy = warp::post()
+        .and(warp::path!("secrets" / "copy"))
+        .and(with_state(state))
+        .and(warp::body::json::<CopyRequest>())
+        .map(|state, req| deferred(move |session| copy_handler(state, req, session)));
+
+    import.or(export).unify().or(copy).unify()
+}
+
+/// Parses `.env` content. Supports `export` prefixes, `#` comments (also
+/// after a value), and single (literal) or double (escaped) quoted values.
+pub fn parse_dotenv(input: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut out = BTreeMap::new();
+    for (idx, raw) in input.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=VALUE", idx + 1))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("line {}: missing key", idx + 1));
+        }
+
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let rest = &value[1..];
+                let end = closing_quote(rest, quote)
+                    .ok_or_else(|| format!("line {}: unterminated quote", idx + 1))?;
+                let after = rest[end + 1..].trim_start();
+                if !after.is_empty() && !after.starts_with('#') {
+                    return Err(format!("line {}: unexpected text after quote", idx + 1));
+                }
+                match quote {
+                    '"' => unescape_double_quoted(&rest[..end]),
+                    _ => rest[..end].to_owned(),
+                }
+            }
+            // Unquoted values may carry a trailing comment.
+            _ => match value.find(" #") {
+                Some(pos) => value[..pos].trim_end().to_owned(),
+                None => value.to_owned(),
+            },
+        };
+
+        out.insert(key.to_owned(), value);
+    }
+    Ok(out)
+}
+
+/// Byte offset of the quote ending a value that opened with `quote`.
+/// Inside double quotes a backslash escapes the next character.
+fn closing_quote(rest: &str, quote: char) -> Option<usize> {
+    let mut chars = rest.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '\\' if quote == '"' => {
+                chars.next();
+            }
+            c if c == quote => return Some(pos),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unescape_double_quoted(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Parses a flat JSON object. Numbers and booleans are accepted and stored
+/// in their JSON text form, `{"base64": "..."}` carries a binary value as
+/// `export_handler` writes it, and anything else nested is refused.
+pub fn parse_json(input: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, String> {
+    let map: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(input).map_err(|e| e.to_string())?;
+    map.into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(s) => Ok((key, s.into_bytes())),
+            serde_json::Value::Number(n) => Ok((key, n.to_string().into_bytes())),
+            serde_json::Value::Bool(b) => Ok((key, b.to_string().into_bytes())),
+            serde_json::Value::Object(obj) => match (obj.len(), obj.get("base64")) {
+                (1, Some(serde_json::Value::String(encoded))) => STANDARD
+                    .decode(encoded)
+                    .map(|raw| (key.clone(), raw))
+                    .map_err(|e| format!("{}: invalid base64: {}", key, e)),
+                _ => Err(format!("{}: value must be a string", key)),
+            },
+            _ => Err(format!("{}: value must be a string", key)),
+        })
+        .collect()
+}
+
+/// JSON form of a stored value: a string when it is UTF-8, otherwise
+/// `{"base64": "..."}` so the bytes survive a round trip through import.
+fn json_value(value: Vec<u8>) -> serde_json::Value {
+    match String::from_utf8(value) {
+        Ok(text) => serde_json::Value::String(text),
+        Err(e) => serde_json::json!({ "base64": STANDARD.encode(e.as_bytes()) }),
+    }
+}
+
+pub fn render_dotenv(secrets: &BTreeMap<String, String>) -> String {
+    let mut out = String::new();
+    for (key, value) in secrets {
+        let plain = !value.is_empty()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-./:@,+".contains(c));
+        if plain {
+            out.push_str(&format!("{}={}\n", key, value));
+        } else {
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+            out.push_str(&format!("{}=\"{}\"\n", key, escaped));
+        }
+    }
+    out
+}
+
+/// Latest values of every key in an environment, as stored.
+pub async fn fetch_current(
+    client: &SecretClient,
+    runner_id: &str,
+    environment_id: &str,
+) -> Result<HashMap<String, Vec<u8>>, tonic::Status> {
+    let resp = client
+        .get_all_secrets(secret_service::GetAllSecretsRequest {
+            runner_id: runner_id.to_owned(),
+            environment_id: environment_id.to_owned(),
+            version: 0,
+            keys_only: false,
+        })
+        .await?;
+    Ok(resp.vals.into_iter().map(|kv| (kv.key, kv.value)).collect())
+}
+
+/// Diffs incoming values against what is stored, checking every write
+/// against the secret policy and the environment's key limit.
+pub fn plan_changes(
+    current: &HashMap<String, Vec<u8>>,
+    incoming: BTreeMap<String, Vec<u8>>,
+    policy: ConflictPolicy,
+    secret_policy: &SecretPolicy,
+    environment_id: &str,
+) -> Vec<PlannedChange> {
+    let limit = secret_policy.key_limit(environment_id);
+    let mut key_count = current.len();
+    incoming
+        .into_iter()
+        .map(|(key, value)| {
+            let action = match current.get(&key) {
+                None => ChangeAction::Create,
+                Some(existing) if *existing == value => ChangeAction::Unchanged,
+                Some(_) if policy == ConflictPolicy::Overwrite => ChangeAction::Update,
+                Some(_) => ChangeAction::Skip,
+            };
+
+            let mut rejected = None;
+            if matches!(action, ChangeAction::Create | ChangeAction::Update) {
+                let errors = secret_policy.validate(&key, &value, "value");
+                if !errors.is_empty() {
+                    let messages: Vec<String> = errors.into_iter().map(|e| e.message).collect();
+                    rejected = Some(messages.join("; "));
+                } else if action == ChangeAction::Create {
+                    if key_count >= limit {
+                        rejected = Some(format!("{} already holds {} keys", environment_id, limit));
+                    } else {
+                        key_count += 1;
+                    }
+                }
+            }
+
+            let old_size = current
+                .get(&key)
+                .filter(|existing| **existing != value)
+                .map(Vec::len);
+            PlannedChange {
+                key,
+                value,
+                action,
+                old_size,
+                rejected,
+            }
+        })
+        .collect()
+}
+
+/// Applies a plan key by key. A failing key doesn't stop the rest.
+pub async fn apply_changes(
+    client: &SecretClient,
+    runner_id: &str,
+    environment_id: &str,
+    actor: &str,
+    plan: Vec<PlannedChange>,
+    dry_run: bool,
+) -> Vec<KeyResult> {
+    let mut results = Vec::with_capacity(plan.len());
+    for change in plan {
+        let old_size = change.old_size;
+        let new_size = old_size.map(|_| change.value.len());
+        if let Some(reason) = change.rejected {
+            results.push(KeyResult {
+                key: change.key,
+                action: change.action,
+                status: ChangeStatus::Failed,
+                error: Some(reason),
+                old_size,
+                new_size,
+            });
+            continue;
+        }
+
+        let outcome = match (dry_run, change.action) {
+            (_, ChangeAction::Unchanged) => Ok(ChangeStatus::Unchanged),
+            (_, ChangeAction::Skip) => Ok(ChangeStatus::Skipped),
+            (true, _) => Ok(ChangeStatus::Planned),
+            (false, ChangeAction::Create) => client
+                .create_secret(secret_service::CreateSecretRequest {
+                    runner_id: runner_id.to_owned(),
+                    environment_id: environment_id.to_owned(),
+                    secret_key: change.key.clone(),
+                    value: change.value,
+                    actor: actor.to_owned(),
+                })
+                .await
+                .map(|_| ChangeStatus::Applied),
+            (false, ChangeAction::Update) => client
+                .update_secret(secret_service::UpdateSecretRequest {
+                    runner_id: runner_id.to_owned(),
+                    environment_id: environment_id.to_owned(),
+                    secret_key: change.key.clone(),
+                    new_value: change.value,
+                    actor: actor.to_owned(),
+                })
+                .await
+                .map(|_| ChangeStatus::Applied),
+        };
+
+        results.push(match outcome {
+            Ok(status) => KeyResult {
+                key: change.key,
+                action: change.action,
+                status,
+                error: None,
+                old_size,
+                new_size,
+            },
+            Err(e) => {
+                log!(
+                    LogLevel::Warn,
+                    "bulk {:?} of {} failed: {}",
+                    change.action,
+                    change.key,
+                    e
+                );
+                KeyResult {
+                    key: change.key,
+                    action: change.action,
+                    status: ChangeStatus::Failed,
+                    error: Some(e.message().to_owned()),
+                    old_size,
+                    new_size,
+                }
+            }
+        });
+    }
+    results
+}
+
+async fn import_handler(
+    state: SharedState,
+    query: ImportQuery,
+    body: Bytes,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log!(
+        LogLevel::Debug,
+        "import secrets into {}/{} session {}",
+        query.runner_id,
+        query.environment_id,
+        session.session_id
+    );
+    require_runner(&state, &session, &query.runner_id).await?;
+
+    let incoming = match query.format {
+        SecretFormat::Dotenv => std::str::from_utf8(&body)
+            .map_err(|e| e.to_string())
+            .and_then(parse_dotenv)
+            .map(|parsed| {
+                parsed
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into_bytes()))
+                    .collect()
+            }),
+        SecretFormat::Json => parse_json(&body),
+    }
+    .map_err(|e| warp::reject::custom(BadRequest(format!("invalid import: {}", e))))?;
+
+    let client = secret_client(&state, &session).await?;
+    let current = fetch_current(&client, &query.runner_id, &query.environment_id)
+        .await
+        .map_err(secret_error)?;
+
+    let plan = plan_changes(
+        &current,
+        incoming,
+        query.policy,
+        &state.config.secret_policy,
+        &query.environment_id,
+    );
+    let results = apply_changes(
+        &client,
+        &query.runner_id,
+        &query.environment_id,
+        &session.user_id,
+        plan,
+        query.dry_run,
+    )
+    .await;
+
+    log!(
+        LogLevel::Info,
+        "import of {} keys into {}/{} by {} (dry run: {})",
+        results.len(),
+        query.runner_id,
+        query.environment_id,
+        session.user_id,
+        query.dry_run
+    );
+    Ok(warp::reply::json(&BulkReport {
+        runner_id: query.runner_id,
+        environment_id: query.environment_id,
+        dry_run: query.dry_run,
+        results,
+    }))
+}
+
+async fn export_handler(
+    state: SharedState,
+    query: ExportQuery,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log!(
+        LogLevel::Debug,
+        "export secrets of {}/{} session {}",
+        query.runner_id,
+        query.environment_id,
+        session.session_id
+    );
+    require_runner(&state, &session, &query.runner_id).await?;
+    audit_reveal(&session, &query.runner_id, &query.environment_id, "*");
+
+    let client = secret_client(&state, &session).await?;
+    let secrets: BTreeMap<String, Vec<u8>> =
+        fetch_current(&client, &query.runner_id, &query.environment_id)
+            .await
+            .map_err(secret_error)?
+            .into_iter()
+            .collect();
+
+    let (body, content_type, extension) = match query.format {
+        SecretFormat::Dotenv => {
+            // A .env file has no way to mark a value as encoded, so binary
+            // values are refused rather than mangled.
+            let mut text = BTreeMap::new();
+            let mut binary = Vec::new();
+            for (key, value) in secrets {
+                match String::from_utf8(value) {
+                    Ok(value) => {
+                        text.insert(key, value);
+                    }
+                    Err(_) => binary.push(key),
+                }
+            }
+            if !binary.is_empty() {
+                return Err(warp::reject::custom(BadRequest(format!(
+                    "{} not valid UTF-8, export with format=json instead",
+                    binary.join(", ")
+                ))));
+            }
+            (render_dotenv(&text), "text/plain; charset=utf-8", "env")
+        }
+        SecretFormat::Json => {
+            let values: serde_json::Map<String, serde_json::Value> = secrets
+                .into_iter()
+                .map(|(key, value)| (key, json_value(value)))
+                .collect();
+            (
+                serde_json::to_string_pretty(&values)
+                    .map_err(|e| warp::reject::custom(Whoops(e.to_string())))?,
+                "application/json",
+                "json",
+            )
+        }
+    };
+    let filename: String = format!("{}-{}", query.runner_id, query.environment_id)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    let disposition = format!("attachment; filename=\"{}.{}\"", filename, extension);
+
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(body, CONTENT_TYPE, content_type),
+        CONTENT_DISPOSITION,
+        disposition,
+    ))
+}
+
+async fn copy_handler(
+    state: SharedState,
+    req: CopyRequest,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let target_runner_id = req
+        .target_runner_id
+        .clone()
+        .unwrap_or_else(|| req.source_runner_id.clone());
+    log!(
+        LogLevel::Debug,
+        "copy secrets {}/{} -> {}/{} session {}",
+        req.source_runner_id,
+        req.source_environment_id,
+        target_runner_id,
+        req.target_environment_id,
+        session.session_id
+    );
+    require_runner(&state, &session, &req.source_runner_id).await?;
+    if target_runner_id != req.source_runner_id {
+        require_runner(&state, &session, &target_runner_id).await?;
+    }
+    if target_runner_id == req.source_runner_id
+        && req.target_environment_id == req.source_environment_id
+    {
+        return Err(warp::reject::custom(BadRequest(
+            "source and target are the same environment".to_owned(),
+        )));
+    }
+
+    let client = secret_client(&state, &session).await?;
+    let mut source = fetch_current(&client, &req.source_runner_id, &req.source_environment_id)
+        .await
+        .map_err(secret_error)?;
+
+    let mut missing = Vec::new();
+    let mut incoming: BTreeMap<String, Vec<u8>> = match &req.keys {
+        Some(keys) => keys
+            .iter()
+            .filter_map(|key| match source.remove(key) {
+                Some(value) => Some((key.clone(), value)),
+                None => {
+                    missing.push(key.clone());
+                    None
+                }
+            })
+            .collect(),
+        None => source.into_iter().collect(),
+    };
+    incoming.extend(
+        req.overrides
+            .into_iter()
+            .map(|(key, value)| (key, value.into_bytes())),
+    );
+
+    let current = fetch_current(&client, &target_runner_id, &req.target_environment_id)
+        .await
+        .map_err(secret_error)?;
+    let plan = plan_changes(
+        &current,
+        incoming,
+        req.policy,
+        &state.config.secret_policy,
+        &req.target_environment_id,
+    );
+    let mut results = apply_changes(
+        &client,
+        &target_runner_id,
+        &req.target_environment_id,
+        &session.user_id,
+        plan,
+        req.dry_run,
+    )
+    .await;
+    results.extend(missing.into_iter().map(|key| KeyResult {
+        key,
+        action: ChangeAction::Skip,
+        status: ChangeStatus::Failed,
+        error: Some("not present in source environment".to_owned()),
+        old_size: None,
+        new_size: None,
+    }));
+
+    log!(
+        LogLevel::Info,
+        "copy of {} keys {}/{} -> {}/{} by {} (dry run: {})",
+        results.len(),
+        req.source_runner_id,
+        req.source_environment_id,
+        target_runner_id,
+        req.target_environment_id,
+        session.user_id,
+        req.dry_run
+    );
+    Ok(warp::reply::json(&BulkReport {
+        runner_id: target_runner_id,
+        environment_id: req.target_environment_id,
+        dry_run: req.dry_run,
+        results,
+    }))
+}
@@ -0,0 +1,110 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+
+use crate::grpc::secret_service;
+
+/// How a revealed value is encoded in the JSON reply.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueEncoding {
+    /// Plain text. Values that are not valid UTF-8 fall back to base64.
+    #[default]
+    Utf8,
+    Base64,
+}
+
+/// How much of a revealed value is shown.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskMode {
+    #[default]
+    None,
+    /// Only the last four characters are shown, for values long enough that
+    /// this doesn't give most of it away.
+    Partial,
+    /// A fixed placeholder, which also hides the value's length.
+    Full,
+}
+
+const MASK: &str = "********";
+const PARTIAL_VISIBLE: usize = 4;
+const PARTIAL_MIN_LEN: usize = 12;
+
+/// One secret. `value` and `encoding` are only present on single-key
+/// reads, never in listings.
+#[derive(Serialize)]
+pub struct SecretEntry {
+    pub key: String,
+    pub version: i64,
+    pub updated_at: i64,
+    pub size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<ValueEncoding>,
+}
+
+#[derive(Serialize)]
+pub struct SecretListResponse {
+    pub runner_id: String,
+    pub environment_id: String,
+    pub secrets: Vec<SecretEntry>,
+}
+
+impl SecretEntry {
+    pub fn metadata(kv: &secret_service::KeyValuePair) -> Self {
+        Self {
+            key: kv.key.clone(),
+            version: kv.version,
+            updated_at: kv.updated_at,
+            // Services that predate `keys_only` send the value instead.
+            size: (kv.size.max(0) as usize).max(kv.value.len()),
+            value: None,
+            encoding: None,
+        }
+    }
+
+    pub fn from_get(
+        resp: &secret_service::GetSecretResponse,
+        encoding: ValueEncoding,
+        mask: MaskMode,
+    ) -> Self {
+        let (value, encoding) = render_value(&resp.value, encoding, mask);
+        Self {
+            key: resp.secret_key.clone(),
+            version: resp.version,
+            updated_at: resp.updated_at,
+            size: resp.value.len(),
+            value: Some(value),
+            encoding: Some(encoding),
+        }
+    }
+}
+
+/// Encodes and masks a raw value, returning the encoding actually used.
+pub fn render_value(
+    raw: &[u8],
+    encoding: ValueEncoding,
+    mask: MaskMode,
+) -> (String, ValueEncoding) {
+    let (text, encoding) = match (encoding, std::str::from_utf8(raw)) {
+        (ValueEncoding::Utf8, Ok(text)) => (text.to_owned(), ValueEncoding::Utf8),
+        _ => (STANDARD.encode(raw), ValueEncoding::Base64),
+    };
+
+    let text = match mask {
+        MaskMode::None => text,
+        MaskMode::Full => MASK.to_owned(),
+        MaskMode::Partial => {
+            let chars: Vec<char> = text.chars().collect();
+            if chars.len() < PARTIAL_MIN_LEN {
+                MASK.to_owned()
+            } else {
+                let tail: String = chars[chars.len() - PARTIAL_VISIBLE..].iter().collect();
+                format!("{}{}", MASK, tail)
+            }
+        }
+    };
+
+    (text, encoding)
+}
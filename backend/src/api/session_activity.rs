@@ -0,0 +1,172 @@
+//! When each session was opened and last used, for the idle timeout and the
+//! absolute lifetime. Activity is tracked in memory and written to the
+//! session store in batches by `spawn_activity_flush`.
+
+use std::{collections::HashMap, time::Duration};
+
+use artisan_middleware::dusa_collection_utils::{
+    core::{logger::LogLevel, types::rwarc::LockWithTimeout},
+    log,
+};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    api::{cookie::SessionData, user_sessions::end_session},
+    state::AppState,
+};
+
+/// Entries with nothing left to write are dropped from memory after this
+/// long; the store has their latest activity by then.
+const FORGET_AFTER: Duration = Duration::from_secs(600);
+
+struct Activity {
+    created_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    /// `last_seen` hasn't been written yet.
+    dirty: bool,
+}
+
+/// How long a session has left. `None` where the limit is off.
+pub struct Remaining {
+    pub idle: Option<Duration>,
+    pub lifetime: Option<Duration>,
+}
+
+/// Why [`SessionActivity::check`] refused a session.
+pub enum ActivityError {
+    /// Ran past the named limit; the session is over.
+    Expired(&'static str),
+    /// The session's activity couldn't be read, so its limits couldn't be
+    /// checked either.
+    Unreadable(String),
+}
+
+pub struct SessionActivity {
+    sessions: LockWithTimeout<HashMap<String, Activity>>,
+}
+
+impl Default for SessionActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionActivity {
+    pub fn new() -> Self {
+        Self {
+            sessions: LockWithTimeout::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the session against `SESSION_IDLE_TIMEOUT_SECS` and
+    /// `SESSION_MAX_LIFETIME_SECS`, and with `touch` counts this request as
+    /// activity. A session whose activity can't be read is refused rather
+    /// than let through unchecked.
+    pub async fn check(
+        &self,
+        state: &AppState,
+        session_id: &str,
+        touch: bool,
+    ) -> Result<Remaining, ActivityError> {
+        let known = match self.sessions.try_read().await {
+            Ok(sessions) => sessions
+                .get(session_id)
+                .map(|a| (a.created_at, a.last_seen)),
+            Err(_) => None,
+        };
+        let (created_at, last_seen) = match known {
+            Some(times) => times,
+            None => match state.sessions.meta(session_id).await {
+                Ok(meta) => (meta.created_at, meta.last_seen),
+                Err(e) => return Err(ActivityError::Unreadable(e)),
+            },
+        };
+
+        let now = Utc::now();
+        let idle_timeout = state.config.session_idle_timeout;
+        let max_lifetime = state.config.session_max_lifetime;
+        let idle = (now - last_seen).to_std().unwrap_or_default();
+        let age = (now - created_at).to_std().unwrap_or_default();
+        if !idle_timeout.is_zero() && idle >= idle_timeout {
+            return Err(ActivityError::Expired("idle timeout"));
+        }
+        if !max_lifetime.is_zero() && age >= max_lifetime {
+            return Err(ActivityError::Expired("maximum lifetime"));
+        }
+
+        let last_seen = match touch {
+            true => now,
+            false => last_seen,
+        };
+        if let Ok(mut sessions) = self.sessions.try_write().await {
+            let entry = sessions.entry(session_id.to_owned()).or_insert(Activity {
+                created_at,
+                last_seen,
+                dirty: false,
+            });
+            if touch {
+                entry.last_seen = now;
+                entry.dirty = true;
+            }
+        }
+
+        let idle = (now - last_seen).to_std().unwrap_or_default();
+        Ok(Remaining {
+            idle: (!idle_timeout.is_zero()).then(|| idle_timeout.saturating_sub(idle)),
+            lifetime: (!max_lifetime.is_zero()).then(|| max_lifetime.saturating_sub(age)),
+        })
+    }
+
+    pub async fn forget(&self, session_id: &str) {
+        if let Ok(mut sessions) = self.sessions.try_write().await {
+            sessions.remove(session_id);
+        }
+    }
+
+    /// Writes out activity recorded since the last flush.
+    pub async fn flush(&self, state: &AppState) {
+        let pending: Vec<(String, DateTime<Utc>)> = {
+            let Ok(mut sessions) = self.sessions.try_write().await else {
+                return;
+            };
+            let now = Utc::now();
+            sessions.retain(|_, a| {
+                a.dirty || (now - a.last_seen).to_std().unwrap_or_default() < FORGET_AFTER
+            });
+            sessions
+                .iter_mut()
+                .filter(|(_, a)| a.dirty)
+                .map(|(id, a)| {
+                    a.dirty = false;
+                    (id.clone(), a.last_seen)
+                })
+                .collect()
+        };
+
+        for (session_id, last_seen) in &pending {
+            if let Err(e) = state.sessions.touch(session_id, *last_seen).await {
+                log!(LogLevel::Warn, "last_seen update for {}: {}", session_id, e);
+            }
+        }
+        if !pending.is_empty() {
+            log!(
+                LogLevel::Debug,
+                "flushed activity for {} sessions",
+                pending.len()
+            );
+        }
+    }
+}
+
+/// Ends a session that ran past one of its limits.
+pub async fn expire_session(state: &AppState, session: &SessionData, reason: &str) {
+    log!(
+        LogLevel::Info,
+        "session of {} ended by {}",
+        session.user_id,
+        reason
+    );
+    if let Err(e) = end_session(state, session).await {
+        log!(LogLevel::Error, "Error deleting session from DB: {}", e);
+    }
+}
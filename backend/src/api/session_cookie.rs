@@ -0,0 +1,328 @@
+//! The `session_id` cookie: how it is built, read back and periodically
+//! swapped for a fresh id.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use artisan_middleware::dusa_collection_utils::{
+    core::{logger::LogLevel, types::rwarc::LockWithTimeout},
+    log,
+};
+use chrono::Utc;
+use cookie::{Cookie, CookieBuilder, SameSite};
+use uuid::Uuid;
+use warp::{
+    Reply,
+    http::header::{HeaderValue, SET_COOKIE},
+    reply::Response,
+};
+
+use crate::{
+    api::cookie::SessionData,
+    config::env_or,
+    state::{AppState, SharedState},
+    updater::spawn_session_refresh,
+};
+
+const COOKIE_NAME: &str = "session_id";
+const HOST_PREFIXED_NAME: &str = "__Host-session_id";
+const CSRF_COOKIE_NAME: &str = "csrf_token";
+const CSRF_HOST_PREFIXED_NAME: &str = "__Host-csrf_token";
+
+/// How long a rotated-out id keeps working, for requests already in flight
+/// when the new cookie was set.
+const ROTATION_GRACE: Duration = Duration::from_secs(60);
+
+pub struct CookieConfig {
+    /// `SESSION_COOKIE_SAMESITE`: `strict`, `lax` (default) or `none`.
+    pub same_site: SameSite,
+    /// `SESSION_COOKIE_HOST_PREFIX`: name the cookie `__Host-session_id`,
+    /// which browsers only accept when Secure, host-only and on `/`.
+    pub host_prefix: bool,
+    /// `SESSION_COOKIE_DEV`: drop Secure (and the prefix) for requests to
+    /// localhost, so the dashboard works over plain http in development.
+    pub dev: bool,
+    /// `SESSION_ROTATE_SECS`: swap the session id for a new one once it has
+    /// been in use this long. Zero disables rotation.
+    pub rotate_every: Duration,
+}
+
+impl CookieConfig {
+    pub fn from_env() -> Self {
+        let same_site = match std::env::var("SESSION_COOKIE_SAMESITE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "strict" => SameSite::Strict,
+            "none" => SameSite::None,
+            "lax" | "" => SameSite::Lax,
+            other => {
+                log!(LogLevel::Warn, "unknown SameSite {}, using Lax", other);
+                SameSite::Lax
+            }
+        };
+
+        Self {
+            same_site,
+            host_prefix: env_or("SESSION_COOKIE_HOST_PREFIX", false),
+            dev: env_or("SESSION_COOKIE_DEV", false),
+            rotate_every: Duration::from_secs(env_or("SESSION_ROTATE_SECS", 900)),
+        }
+    }
+
+    /// Whether a request for `host` gets the relaxed development cookie.
+    fn relaxed(&self, host: Option<&str>) -> bool {
+        self.dev && host.is_some_and(is_localhost)
+    }
+
+    fn prefixed(&self, host: Option<&str>) -> bool {
+        self.host_prefix && !self.relaxed(host)
+    }
+
+    fn builder(
+        &self,
+        host: Option<&str>,
+        name: &'static str,
+        value: String,
+    ) -> CookieBuilder<'static> {
+        let relaxed = self.relaxed(host);
+        // SameSite=None is refused on cookies that aren't Secure.
+        let same_site = match self.same_site {
+            SameSite::None if relaxed => SameSite::Lax,
+            same_site => same_site,
+        };
+        Cookie::build((name, value))
+            .path("/")
+            .secure(!relaxed)
+            .same_site(same_site)
+    }
+
+    fn session_name(&self, host: Option<&str>) -> &'static str {
+        match self.prefixed(host) {
+            true => HOST_PREFIXED_NAME,
+            false => COOKIE_NAME,
+        }
+    }
+
+    fn csrf_name(&self, host: Option<&str>) -> &'static str {
+        match self.prefixed(host) {
+            true => CSRF_HOST_PREFIXED_NAME,
+            false => CSRF_COOKIE_NAME,
+        }
+    }
+
+    /// A cookie for `session`, expiring with it.
+    pub fn session(&self, host: Option<&str>, session: &SessionData) -> HeaderValue {
+        let cookie = self
+            .builder(host, self.session_name(host), session.session_id.clone())
+            .http_only(true)
+            .max_age(max_age(session))
+            .build();
+        HeaderValue::from_str(&cookie.to_string()).expect("session cookie is a valid header")
+    }
+
+    /// The session's CSRF token, readable by the frontend so it can echo it
+    /// back in `X-CSRF-Token`.
+    pub fn csrf(&self, host: Option<&str>, session: &SessionData, token: String) -> HeaderValue {
+        let cookie = self
+            .builder(host, self.csrf_name(host), token)
+            .max_age(max_age(session))
+            .build();
+        HeaderValue::from_str(&cookie.to_string()).expect("CSRF cookie is a valid header")
+    }
+
+    /// Cookies telling the browser to forget the session and its CSRF token.
+    pub fn clear(&self, host: Option<&str>) -> [HeaderValue; 2] {
+        [self.session_name(host), self.csrf_name(host)].map(|name| {
+            let cookie = self
+                .builder(host, name, String::new())
+                .http_only(name == self.session_name(host))
+                .max_age(cookie::time::Duration::seconds(0))
+                .build();
+            HeaderValue::from_str(&cookie.to_string()).expect("clear cookie is a valid header")
+        })
+    }
+
+    /// The session id in a `Cookie` header. The prefixed name wins; the
+    /// plain one is only read when it could have been set.
+    pub fn read(&self, cookie_header: &str) -> Option<String> {
+        let mut plain = None;
+        for cookie in Cookie::split_parse(cookie_header).flatten() {
+            match cookie.name() {
+                HOST_PREFIXED_NAME if self.host_prefix => return Some(cookie.value().to_owned()),
+                COOKIE_NAME if !self.host_prefix || self.dev => {
+                    plain = Some(cookie.value().to_owned())
+                }
+                _ => {}
+            }
+        }
+        plain
+    }
+
+    fn sets_session(&self, resp: &Response) -> bool {
+        resp.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.starts_with(COOKIE_NAME) || v.starts_with(HOST_PREFIXED_NAME))
+    }
+}
+
+/// `reply` with each of `cookies` in its own `Set-Cookie` header.
+pub fn with_cookies(reply: impl Reply, cookies: impl IntoIterator<Item = HeaderValue>) -> Response {
+    let mut resp = reply.into_response();
+    for cookie in cookies {
+        resp.headers_mut().append(SET_COOKIE, cookie);
+    }
+    resp
+}
+
+fn max_age(session: &SessionData) -> cookie::time::Duration {
+    cookie::time::Duration::seconds((session.expires_at - Utc::now()).num_seconds().max(0))
+}
+
+fn is_localhost(host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    matches!(name, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+/// Tracks how long each session id has been in use, and ids that were just
+/// rotated out. In memory: after a restart ids start their clock again.
+pub struct SessionRotation {
+    first_seen: LockWithTimeout<HashMap<String, Instant>>,
+    retired: LockWithTimeout<HashMap<String, (String, Instant)>>,
+}
+
+impl Default for SessionRotation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionRotation {
+    pub fn new() -> Self {
+        Self {
+            first_seen: LockWithTimeout::new(HashMap::new()),
+            retired: LockWithTimeout::new(HashMap::new()),
+        }
+    }
+
+    /// The id to use for `session_id`: its replacement if it was rotated out
+    /// moments ago, otherwise itself.
+    pub async fn resolve(&self, session_id: &str) -> String {
+        if let Ok(retired) = self.retired.try_read().await
+            && let Some((new_id, at)) = retired.get(session_id)
+            && at.elapsed() < ROTATION_GRACE
+        {
+            return new_id.clone();
+        }
+        session_id.to_owned()
+    }
+
+    async fn due(&self, session_id: &str, every: Duration) -> bool {
+        let Ok(mut first_seen) = self.first_seen.try_write().await else {
+            return false;
+        };
+        // Ids that have gone quiet are forgotten; they just restart their
+        // clock if they come back.
+        first_seen.retain(|_, seen| seen.elapsed() < every * 4);
+        first_seen
+            .entry(session_id.to_owned())
+            .or_insert_with(Instant::now)
+            .elapsed()
+            >= every
+    }
+
+    async fn retire(&self, old_id: &str, new_id: &str) {
+        if let Ok(mut first_seen) = self.first_seen.try_write().await {
+            first_seen.remove(old_id);
+            first_seen.insert(new_id.to_owned(), Instant::now());
+        }
+        if let Ok(mut retired) = self.retired.try_write().await {
+            retired.retain(|_, (_, at)| at.elapsed() < ROTATION_GRACE);
+            retired.insert(old_id.to_owned(), (new_id.to_owned(), Instant::now()));
+        }
+    }
+}
+
+/// Moves the session to a fresh id, under its refresh lock so a token
+/// refresh can't land on the old row.
+async fn rotate(state: &AppState, old_id: &str) -> Option<SessionData> {
+    let current = state
+        .refresh_locks
+        .with_lock(old_id, async {
+            let current = state.sessions.lookup(old_id).await.ok()?;
+            let session = SessionData {
+                session_id: Uuid::new_v4().to_string(),
+                ..current
+            };
+            if let Err(e) = state.sessions.rename(old_id, &session.session_id).await {
+                log!(LogLevel::Error, "session rotation failed: {}", e);
+                return None;
+            }
+            state
+                .session_rotation
+                .retire(old_id, &session.session_id)
+                .await;
+            Some(session)
+        })
+        .await?;
+
+    state.session_cache.remove(old_id).await;
+    state
+        .session_cache
+        .insert(current.session_id.clone(), current.clone())
+        .await;
+    log!(
+        LogLevel::Info,
+        "rotated session {} to {}",
+        old_id,
+        current.session_id
+    );
+    Some(current)
+}
+
+/// Runs after every API reply: a successful request on a session whose id
+/// is due gets the session moved to a new id and the new cookies attached.
+/// Replies that set the cookie themselves (login, logout) are left alone.
+pub async fn rotate_session_id(
+    state: SharedState,
+    host: Option<String>,
+    cookie_header: Option<String>,
+    reply: impl Reply,
+) -> Result<Response, warp::Rejection> {
+    let mut resp = reply.into_response();
+    let config = &state.config.session_cookie;
+    if config.rotate_every.is_zero() || !resp.status().is_success() || config.sets_session(&resp) {
+        return Ok(resp);
+    }
+    let Some(session_id) = cookie_header.as_deref().and_then(|h| config.read(h)) else {
+        return Ok(resp);
+    };
+    if !state
+        .session_rotation
+        .due(&session_id, config.rotate_every)
+        .await
+    {
+        return Ok(resp);
+    }
+
+    if let Some(session) = rotate(&state, &session_id).await {
+        spawn_session_refresh(state.clone(), session.clone());
+        let token = state.csrf.token(&session.session_id);
+        resp = with_cookies(
+            resp,
+            [
+                config.session(host.as_deref(), &session),
+                config.csrf(host.as_deref(), &session, token),
+            ],
+        );
+    }
+    Ok(resp)
+}
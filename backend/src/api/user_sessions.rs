@@ -0,0 +1,135 @@
+//! A user's view of their own sessions: where they are signed in, and a
+//! way to end any one of them.
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::digest::{SHA256, digest};
+use serde::Serialize;
+use warp::reject;
+
+use crate::{
+    api::{
+        common::PortalRejection::{NotFound, Whoops},
+        cookie::SessionData,
+        session_cookie::with_cookies,
+    },
+    auth::revoke::revoke_session_tokens,
+    state::{AppState, SharedState},
+};
+
+/// How a session is named to its user. Session ids are credentials, so the
+/// list shows a digest of them instead.
+pub(crate) fn session_handle(session_id: &str) -> String {
+    let hash = digest(&SHA256, session_id.as_bytes());
+    URL_SAFE_NO_PAD.encode(&hash.as_ref()[..12])
+}
+
+#[derive(Serialize)]
+struct SessionEntry {
+    id: String,
+    created_at: i64,
+    last_seen: i64,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    current: bool,
+}
+
+pub async fn list_sessions_handler(
+    state: SharedState,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let sessions = state
+        .sessions
+        .list_user(&session.user_id)
+        .await
+        .map_err(|e| {
+            log!(
+                LogLevel::Error,
+                "listing sessions of {}: {}",
+                session.user_id,
+                e
+            );
+            reject::custom(Whoops(e))
+        })?;
+
+    let entries: Vec<SessionEntry> = sessions
+        .into_iter()
+        .map(|(data, meta)| SessionEntry {
+            id: session_handle(&data.session_id),
+            created_at: meta.created_at.timestamp(),
+            last_seen: meta.last_seen.timestamp(),
+            ip: meta.ip,
+            user_agent: meta.user_agent,
+            current: data.session_id == session.session_id,
+        })
+        .collect();
+    Ok(warp::reply::json(
+        &serde_json::json!({ "sessions": entries }),
+    ))
+}
+
+/// Ends one of the user's sessions. Revoking the current one also clears
+/// its cookies.
+pub async fn revoke_session_handler(
+    handle: String,
+    state: SharedState,
+    host: Option<String>,
+    session: SessionData,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let sessions = state
+        .sessions
+        .list_user(&session.user_id)
+        .await
+        .map_err(|e| reject::custom(Whoops(e)))?;
+    let Some((target, _)) = sessions
+        .into_iter()
+        .find(|(data, _)| session_handle(&data.session_id) == handle)
+    else {
+        return Err(reject::custom(NotFound("No such session".to_owned())));
+    };
+
+    log!(
+        LogLevel::Info,
+        "{} revoked session {}",
+        session.user_id,
+        handle
+    );
+    end_session(&state, &target)
+        .await
+        .map_err(|e| reject::custom(Whoops(e)))?;
+
+    let body = warp::reply::json(&serde_json::json!({ "revoked": handle }));
+    let cookies = match target.session_id == session.session_id {
+        true => state.config.session_cookie.clear(host.as_deref()).to_vec(),
+        false => Vec::new(),
+    };
+    Ok(with_cookies(body, cookies))
+}
+
+/// Revokes the session's tokens and removes every trace of it.
+pub(crate) async fn end_session(state: &AppState, session: &SessionData) -> Result<(), String> {
+    revoke_session_tokens(state, session).await;
+    state.sessions.delete(&session.session_id).await?;
+    state.session_cache.remove(&session.session_id).await;
+    state.session_activity.forget(&session.session_id).await;
+    Ok(())
+}
+
+/// Ends every session of `user_id` but `keep`, e.g. after a password
+/// change. Returns how many were ended.
+pub async fn end_user_sessions(
+    state: &AppState,
+    user_id: &str,
+    keep: Option<&str>,
+) -> Result<usize, String> {
+    let mut ended = 0;
+    for (session, _) in state.sessions.list_user(user_id).await? {
+        if Some(session.session_id.as_str()) == keep {
+            continue;
+        }
+        end_session(state, &session).await?;
+        ended += 1;
+    }
+    log!(LogLevel::Info, "ended {} sessions of {}", ended, user_id);
+    Ok(ended)
+}
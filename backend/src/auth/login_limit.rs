@@ -0,0 +1,285 @@
+//! Brute-force protection for `auth/login`. Failures are counted per client
+//! address and per email; past a few free attempts each further one has to
+//! wait twice as long as the last, and enough of them lock the key out for
+//! a while.
+//!
+//! An attempt let through counts as a failure until its outcome is known,
+//! so guesses sent in parallel are held to the same limits as sequential
+//! ones.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::env_or,
+    database::login_attempts::{AttemptRecord, AttemptStore},
+};
+
+/// Thresholds for one kind of key.
+#[derive(Clone, Copy)]
+pub struct KeyLimits {
+    /// Failures allowed before backoff starts.
+    pub free: u32,
+    /// Failures that lock the key out.
+    pub lockout_after: u32,
+}
+
+#[derive(Clone)]
+pub struct LoginLimitConfig {
+    /// `LOGIN_LIMIT_EMAIL_FREE` / `LOGIN_LIMIT_EMAIL_LOCKOUT`.
+    pub email: KeyLimits,
+    /// `LOGIN_LIMIT_IP_FREE` / `LOGIN_LIMIT_IP_LOCKOUT`. Higher than the
+    /// email limits, since many users can share an address.
+    pub ip: KeyLimits,
+    /// First backoff delay (`LOGIN_BACKOFF_SECS`), doubling per failure.
+    pub backoff: Duration,
+    /// Longest backoff delay (`LOGIN_BACKOFF_MAX_SECS`).
+    pub backoff_max: Duration,
+    /// How long a lockout lasts (`LOGIN_LOCKOUT_SECS`).
+    pub lockout: Duration,
+    /// Failures older than this are forgotten (`LOGIN_FAILURE_WINDOW_SECS`).
+    pub window: Duration,
+    /// `LOGIN_LIMIT_PERSIST`: keep counters in MySQL when sessions are.
+    pub persist: bool,
+}
+
+impl LoginLimitConfig {
+    pub fn from_env() -> Self {
+        Self {
+            email: KeyLimits {
+                free: env_or("LOGIN_LIMIT_EMAIL_FREE", 3),
+                lockout_after: env_or("LOGIN_LIMIT_EMAIL_LOCKOUT", 10),
+            },
+            ip: KeyLimits {
+                free: env_or("LOGIN_LIMIT_IP_FREE", 10),
+                lockout_after: env_or("LOGIN_LIMIT_IP_LOCKOUT", 50),
+            },
+            backoff: Duration::from_secs(env_or("LOGIN_BACKOFF_SECS", 1)),
+            backoff_max: Duration::from_secs(env_or("LOGIN_BACKOFF_MAX_SECS", 300)),
+            lockout: Duration::from_secs(env_or("LOGIN_LOCKOUT_SECS", 900)),
+            window: Duration::from_secs(env_or("LOGIN_FAILURE_WINDOW_SECS", 3600)),
+            persist: env_or("LOGIN_LIMIT_PERSIST", false),
+        }
+    }
+}
+
+/// The keys one login attempt counts against.
+pub struct AttemptKeys {
+    email: Option<String>,
+    ip: Option<String>,
+}
+
+impl AttemptKeys {
+    pub fn new(email: &str, ip: Option<IpAddr>) -> Self {
+        Self::prefixed("", Some(email), ip)
+    }
+
+    /// Keys for password reset requests and confirmations, held to the same
+    /// limits as logins but counted apart from them. A confirmation names
+    /// no email, only a token.
+    pub fn password_reset(email: Option<&str>, ip: Option<IpAddr>) -> Self {
+        Self::prefixed("reset:", email, ip)
+    }
+
+    fn prefixed(prefix: &str, email: Option<&str>, ip: Option<IpAddr>) -> Self {
+        Self {
+            email: email.map(|email| format!("{}email:{}", prefix, email.trim().to_lowercase())),
+            ip: ip.map(|ip| format!("{}ip:{}", prefix, ip)),
+        }
+    }
+
+    fn with_limits<'a>(&'a self, config: &LoginLimitConfig) -> Vec<(&'a str, KeyLimits)> {
+        let mut keys = Vec::new();
+        if let Some(email) = &self.email {
+            keys.push((email.as_str(), config.email));
+        }
+        if let Some(ip) = &self.ip {
+            keys.push((ip.as_str(), config.ip));
+        }
+        keys
+    }
+}
+
+/// An attempt let through by [`LoginLimiter::check`]. Until it is settled
+/// with `failed` or `succeeded` it counts as a failure of its keys; dropping
+/// it unsettled releases it without recording anything.
+pub struct Reservation<'a> {
+    limiter: &'a LoginLimiter,
+    keys: AttemptKeys,
+}
+
+impl Reservation<'_> {
+    /// Counts a refused login against every key.
+    pub async fn failed(self) {
+        self.limiter.record_failure(&self.keys).await;
+    }
+
+    /// A successful login clears the email's record. The address keeps its
+    /// count, so one good account doesn't reset a spray from the same host.
+    pub async fn succeeded(self) {
+        let Some(email) = &self.keys.email else {
+            return;
+        };
+        if let Err(e) = self.limiter.store.remove(email).await {
+            log!(LogLevel::Error, "login attempts reset for {}: {}", email, e);
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut pending = self
+            .limiter
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (key, _) in self.keys.with_limits(&self.limiter.config) {
+            if let Some(count) = pending.get_mut(key) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    pending.remove(key);
+                }
+            }
+        }
+    }
+}
+
+pub struct LoginLimiter {
+    config: LoginLimitConfig,
+    store: AttemptStore,
+    /// Attempts let through whose outcome isn't known yet, per key.
+    pending: Mutex<HashMap<String, u32>>,
+}
+
+impl LoginLimiter {
+    pub fn new(config: LoginLimitConfig, store: AttemptStore) -> Self {
+        Self {
+            config,
+            store,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserves an attempt for `keys`, or `Err` with how long to wait when
+    /// any of them may not try yet. A store that can't be read lets the
+    /// attempt through.
+    pub async fn check(&self, keys: AttemptKeys) -> Result<Reservation<'_>, Duration> {
+        let mut records = Vec::new();
+        for (key, limits) in keys.with_limits(&self.config) {
+            let record = self.store.get(key).await.unwrap_or_else(|e| {
+                log!(LogLevel::Error, "login attempts lookup for {}: {}", key, e);
+                None
+            });
+            records.push((key, limits, record));
+        }
+
+        // Checking and reserving happen under one lock, so of several
+        // parallel attempts only as many pass as the limits allow.
+        let now = Utc::now();
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut wait = Duration::ZERO;
+        for (key, limits, record) in &records {
+            let in_flight = pending.get(*key).copied().unwrap_or(0);
+            if let Some(record) = self.with_pending(record.clone(), in_flight, now)
+                && let Some(until) = self.blocked_until(&record, *limits)
+                && until > now
+            {
+                wait = wait.max((until - now).to_std().unwrap_or_default());
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (key, _, _) in &records {
+            *pending.entry((*key).to_owned()).or_default() += 1;
+        }
+        drop(pending);
+        Ok(Reservation {
+            limiter: self,
+            keys,
+        })
+    }
+
+    /// `record` with attempts still in flight counted as failures just now.
+    fn with_pending(
+        &self,
+        record: Option<AttemptRecord>,
+        in_flight: u32,
+        now: DateTime<Utc>,
+    ) -> Option<AttemptRecord> {
+        if in_flight == 0 {
+            return record;
+        }
+        let stale = saturating_sub(now, self.config.window);
+        let recent = record
+            .as_ref()
+            .filter(|r| r.last_failure > stale)
+            .map_or(0, |r| r.failures);
+        Some(AttemptRecord {
+            failures: recent.saturating_add(in_flight),
+            last_failure: now,
+            locked_until: record.and_then(|r| r.locked_until),
+        })
+    }
+
+    async fn record_failure(&self, keys: &AttemptKeys) {
+        let now = Utc::now();
+        let stale = saturating_sub(now, self.config.window);
+        let lockout_until = saturating_add(now, self.config.lockout);
+        for (key, limits) in keys.with_limits(&self.config) {
+            match self
+                .store
+                .add_failure(key, now, stale, limits.lockout_after, lockout_until)
+                .await
+            {
+                Ok(record) if record.failures >= limits.lockout_after => log!(
+                    LogLevel::Warn,
+                    "{} locked out after {} failed logins",
+                    key,
+                    record.failures
+                ),
+                Ok(_) => {}
+                Err(e) => log!(LogLevel::Error, "login attempts update for {}: {}", key, e),
+            }
+        }
+    }
+
+    /// When `record` next allows an attempt, if it is limited at all.
+    fn blocked_until(&self, record: &AttemptRecord, limits: KeyLimits) -> Option<DateTime<Utc>> {
+        let recent = record.last_failure > saturating_sub(Utc::now(), self.config.window);
+        let backoff = match recent {
+            true => record
+                .failures
+                .checked_sub(limits.free.saturating_add(1))
+                .map(|n| {
+                    let delay = self.config.backoff.saturating_mul(2u32.saturating_pow(n));
+                    saturating_add(record.last_failure, delay.min(self.config.backoff_max))
+                }),
+            false => None,
+        };
+        backoff.max(record.locked_until)
+    }
+}
+
+/// `at + by`, stopping at the latest representable time. The limits come
+/// from the environment and may be set absurdly high.
+fn saturating_add(at: DateTime<Utc>, by: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(by)
+        .ok()
+        .and_then(|by| at.checked_add_signed(by))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+fn saturating_sub(at: DateTime<Utc>, by: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(by)
+        .ok()
+        .and_then(|by| at.checked_sub_signed(by))
+        .unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
@@ -0,0 +1,105 @@
+//! Logins the upstream answered with an MFA challenge. The password step
+//! leaves a short-lived pending login behind, and `auth/login/verify`
+//! completes it with a code. Pending logins live in memory, so the second
+//! step has to reach the same instance as the first.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use artisan_middleware::dusa_collection_utils::{
+    core::{logger::LogLevel, types::rwarc::LockWithTimeout},
+    log,
+};
+use uuid::Uuid;
+
+/// Wrong codes a pending login survives before the password step has to
+/// be repeated.
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+#[derive(Clone)]
+pub struct PendingLogin {
+    /// The upstream's challenge id, sent back with the code.
+    pub challenge: String,
+    /// For the login limiter, which counts code failures like password
+    /// failures.
+    pub email: String,
+    expires: Instant,
+    attempts: u32,
+}
+
+pub struct PendingLogins {
+    logins: LockWithTimeout<HashMap<String, PendingLogin>>,
+}
+
+impl Default for PendingLogins {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingLogins {
+    pub fn new() -> Self {
+        Self {
+            logins: LockWithTimeout::new(HashMap::new()),
+        }
+    }
+
+    /// Records a challenge and returns the id the client completes it with.
+    pub async fn create(
+        &self,
+        challenge: String,
+        email: String,
+        ttl: Duration,
+    ) -> Result<String, String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Instant::now();
+        let mut logins = self.logins.try_write().await.map_err(|e| e.to_string())?;
+        logins.retain(|_, login| login.expires > now);
+        logins.insert(
+            id.clone(),
+            PendingLogin {
+                challenge,
+                email,
+                expires: now + ttl,
+                attempts: 0,
+            },
+        );
+        Ok(id)
+    }
+
+    pub async fn get(&self, id: &str) -> Option<PendingLogin> {
+        let logins = self.logins.try_read().await.ok()?;
+        logins
+            .get(id)
+            .filter(|login| login.expires > Instant::now())
+            .cloned()
+    }
+
+    /// Counts a wrong code, dropping the pending login after too many.
+    pub async fn failed(&self, id: &str) {
+        let Ok(mut logins) = self.logins.try_write().await else {
+            return;
+        };
+        let Some(login) = logins.get_mut(id) else {
+            return;
+        };
+        login.attempts += 1;
+        if login.attempts >= MAX_CODE_ATTEMPTS {
+            log!(
+                LogLevel::Warn,
+                "pending login for {} dropped after {} wrong codes",
+                login.email,
+                login.attempts
+            );
+            logins.remove(id);
+        }
+    }
+
+    pub async fn remove(&self, id: &str) {
+        if let Ok(mut logins) = self.logins.try_write().await {
+            logins.remove(id);
+        }
+    }
+}
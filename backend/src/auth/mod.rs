@@ -1 +1,6 @@
+pub mod login_limit;
+pub mod mfa;
+pub mod ownership;
+pub mod revoke;
 pub mod token;
+pub mod verify;
@@ -0,0 +1,141 @@
+use std::{collections::HashSet, time::Duration};
+
+use artisan_middleware::{
+    dusa_collection_utils::{
+        core::{
+            errors::{ErrorArrayItem, Errors},
+            logger::LogLevel,
+        },
+        log,
+    },
+    portal::ApiResponse,
+};
+use serde::Deserialize;
+
+use crate::{
+    api::{cache::OwnedRunners, cookie::SessionData},
+    auth::token::get_token,
+    state::AppState,
+};
+
+const OWNERSHIP_TTL: Duration = Duration::from_secs(60);
+
+/// The only part of a `runners` listing entry we care about.
+#[derive(Deserialize)]
+struct RunnerRef {
+    name: String,
+}
+
+/// The only part of a `runner/{id}` instance entry we care about.
+#[derive(Deserialize)]
+struct InstanceRef {
+    id: String,
+}
+
+/// Runner names come back from upstream as `ais_<id>` while the dashboard
+/// addresses them by the bare id.
+pub fn runner_key(name: &str) -> &str {
+    name.strip_prefix("ais_").unwrap_or(name)
+}
+
+async fn fetch_json<T: for<'de> Deserialize<'de>>(
+    state: &AppState,
+    path: &str,
+    token: &str,
+) -> Result<Option<T>, ErrorArrayItem> {
+    let response = state
+        .http_client
+        .clone()
+        .get(state.upstream_url(path))
+        .bearer_auth(token)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(ErrorArrayItem::new(
+            Errors::Network,
+            format!("{} returned status {}", path, response.status()),
+        ));
+    }
+
+    let body: ApiResponse<T> = response
+        .json()
+        .await
+        .map_err(|err| ErrorArrayItem::new(Errors::JsonReading, err.to_string()))?;
+    Ok(body.data)
+}
+
+/// Returns the runners owned by the session's user, from cache when fresh.
+pub async fn owned_runners(
+    state: &AppState,
+    session: &SessionData,
+) -> Result<OwnedRunners, ErrorArrayItem> {
+    let cache = &state.ownership_cache;
+    if let Some(owned) = cache.get(&session.user_id, OWNERSHIP_TTL).await {
+        return Ok(owned);
+    }
+
+    let token = get_token(state, session.clone()).await?;
+    let runners: Vec<RunnerRef> = fetch_json(state, "runners", &token)
+        .await?
+        .unwrap_or_default();
+    let owned = OwnedRunners {
+        runners: runners
+            .iter()
+            .map(|r| runner_key(&r.name).to_owned())
+            .collect(),
+        instances: None,
+    };
+
+    log!(
+        LogLevel::Debug,
+        "resolved {} runners for user {}",
+        owned.runners.len(),
+        session.user_id
+    );
+    cache.insert(session.user_id.clone(), owned.clone()).await;
+    Ok(owned)
+}
+
+pub async fn owns_runner(
+    state: &AppState,
+    session: &SessionData,
+    runner_id: &str,
+) -> Result<bool, ErrorArrayItem> {
+    let owned = owned_runners(state, session).await?;
+    Ok(owned.runners.contains(runner_key(runner_id)))
+}
+
+/// Instances are only resolved the first time a control command needs them,
+/// since it takes one upstream call per runner.
+pub async fn owns_instance(
+    state: &AppState,
+    session: &SessionData,
+    instance_id: &str,
+) -> Result<bool, ErrorArrayItem> {
+    let owned = owned_runners(state, session).await?;
+    if let Some(instances) = &owned.instances {
+        return Ok(instances.contains(instance_id));
+    }
+
+    let token = get_token(state, session.clone()).await?;
+    let mut instances = HashSet::new();
+    for runner in &owned.runners {
+        match fetch_json::<Vec<InstanceRef>>(state, &format!("runner/{}", runner), &token).await {
+            Ok(list) => instances.extend(list.unwrap_or_default().into_iter().map(|i| i.id)),
+            Err(err) => log!(
+                LogLevel::Warn,
+                "failed to resolve instances of {}: {}",
+                runner,
+                err.err_mesg
+            ),
+        }
+    }
+
+    let found = instances.contains(instance_id);
+    state
+        .ownership_cache
+        .set_instances(&session.user_id, instances)
+        .await;
+    Ok(found)
+}
@@ -0,0 +1,125 @@
+//! Revoking a session's tokens at the management API when it ends, and a
+//! local list of tokens that couldn't be revoked there, so `get_token`
+//! still refuses them until they expire.
+
+use std::{collections::HashMap, time::Duration};
+
+use artisan_middleware::{
+    dusa_collection_utils::{
+        core::{logger::LogLevel, types::rwarc::LockWithTimeout},
+        log,
+    },
+    timestamp::current_timestamp,
+};
+use ring::digest::{SHA256, digest};
+use serde_json::json;
+
+use crate::{
+    api::{cookie::SessionData, helper::peek_exp_from_jwt_unverified},
+    state::AppState,
+};
+
+/// Logout shouldn't hang on an unresponsive upstream.
+const REVOKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tokens refused locally, by fingerprint, with their `exp`.
+pub struct RevokedTokens {
+    tokens: LockWithTimeout<HashMap<String, u64>>,
+}
+
+impl Default for RevokedTokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RevokedTokens {
+    pub fn new() -> Self {
+        Self {
+            tokens: LockWithTimeout::new(HashMap::new()),
+        }
+    }
+
+    fn fingerprint(token: &str) -> String {
+        hex::encode(digest(&SHA256, token.as_bytes()))
+    }
+
+    /// Refuses `token` until it expires. Tokens without a readable `exp`
+    /// are kept until restart.
+    pub async fn add(&self, token: &str) {
+        let exp = peek_exp_from_jwt_unverified(token).unwrap_or(u64::MAX);
+        let now = current_timestamp();
+        match self.tokens.try_write().await {
+            Ok(mut tokens) => {
+                tokens.retain(|_, exp| *exp > now);
+                tokens.insert(Self::fingerprint(token), exp);
+            }
+            Err(e) => log!(LogLevel::Error, "revocation list unavailable: {}", e),
+        }
+    }
+
+    /// Fails closed: a list that can't be read refuses everything.
+    pub async fn contains(&self, token: &str) -> bool {
+        match self.tokens.try_read().await {
+            Ok(tokens) => tokens
+                .get(&Self::fingerprint(token))
+                .is_some_and(|exp| *exp > current_timestamp()),
+            Err(e) => {
+                log!(LogLevel::Error, "revocation list unavailable: {}", e);
+                true
+            }
+        }
+    }
+}
+
+/// Revokes the session's refresh token (and the access token presenting it)
+/// upstream. Whatever the outcome, the tokens are no longer wanted, so
+/// failures only put them on the local list.
+pub async fn revoke_session_tokens(state: &AppState, session: &SessionData) {
+    let revoked = match &state.config.revoke_path {
+        Some(path) => match revoke_upstream(state, path, session).await {
+            Ok(()) => true,
+            Err(e) => {
+                log!(
+                    LogLevel::Warn,
+                    "upstream revocation for session {} failed, refusing its tokens locally: {}",
+                    session.session_id,
+                    e
+                );
+                false
+            }
+        },
+        None => false,
+    };
+    if !revoked {
+        state.revoked_tokens.add(&session.auth_jwt).await;
+        state.revoked_tokens.add(&session.refresh_jwt).await;
+    }
+}
+
+async fn revoke_upstream(
+    state: &AppState,
+    path: &str,
+    session: &SessionData,
+) -> Result<(), String> {
+    let response = state
+        .http_client
+        .post(state.upstream_url(path))
+        .bearer_auth(&session.auth_jwt)
+        .json(&json!({ "refresh_token": session.refresh_jwt }))
+        .timeout(REVOKE_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    match response.status() {
+        status if status.is_success() => {
+            log!(
+                LogLevel::Debug,
+                "tokens of session {} revoked upstream",
+                session.session_id
+            );
+            Ok(())
+        }
+        status => Err(format!("upstream answered {}", status)),
+    }
+}
@@ -1,4 +1,4 @@
-use crate::state::get_state;
+use crate::state::AppState;
 use artisan_middleware::{
     dusa_collection_utils::{
         core::{
@@ -9,84 +9,295 @@ use artisan_middleware::{
     },
     timestamp::current_timestamp,
 };
+use chrono::DateTime;
+use reqwest::StatusCode;
 use serde_json::json;
-
-use crate::api::{
-    cookie::{SessionData, update_session_auth},
-    helper::{get_base_url, peek_exp_from_jwt_unverified},
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
 };
 
-pub async fn get_token(session: SessionData) -> Result<String, ErrorArrayItem> {
+use crate::api::{cookie::SessionData, helper::peek_exp_from_jwt_unverified};
+
+/// One lock per session with a refresh in flight, so concurrent requests
+/// wait for that refresh instead of starting their own.
+#[derive(Default)]
+pub struct RefreshLocks {
+    inner: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl RefreshLocks {
+    fn acquire(&self, session_id: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        locks.entry(session_id.to_owned()).or_default().clone()
+    }
+
+    /// Runs `f` while holding the session's lock.
+    pub async fn with_lock<T>(&self, session_id: &str, f: impl Future<Output = T>) -> T {
+        let lock = self.acquire(session_id);
+        let result = {
+            let _guard = lock.lock().await;
+            f.await
+        };
+        self.release(session_id, lock);
+        result
+    }
+
+    /// Forgets the session's lock once nobody else holds or waits on it.
+    fn release(&self, session_id: &str, lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut locks = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        // One reference in the map, one here.
+        if Arc::strong_count(&lock) <= 2 {
+            locks.remove(session_id);
+        }
+    }
+}
+
+fn token_exp(token: &str) -> Result<u64, ErrorArrayItem> {
+    peek_exp_from_jwt_unverified(token)
+        .map_err(|err| ErrorArrayItem::new(Errors::AppState, err.to_string()))
+}
+
+/// Refuses sessions holding tokens that logout couldn't revoke upstream.
+async fn ensure_not_revoked(state: &AppState, session: &SessionData) -> Result<(), ErrorArrayItem> {
+    let revoked = &state.revoked_tokens;
+    if revoked.contains(&session.auth_jwt).await || revoked.contains(&session.refresh_jwt).await {
+        log!(
+            LogLevel::Warn,
+            "session {} presented a revoked token",
+            session.session_id
+        );
+        return Err(ErrorArrayItem::new(
+            Errors::AuthenticationError,
+            format!("tokens of session {} were revoked", session.session_id),
+        ));
+    }
+    Ok(())
+}
+
+/// The session's access token, refreshed first when it expires within
+/// `token_refresh_skew`. Stored tokens were verified on the way in, so
+/// their `exp` is read without checking the signature again.
+pub async fn get_token(state: &AppState, session: SessionData) -> Result<String, ErrorArrayItem> {
     log!(
         LogLevel::Debug,
         "get_token for session {}",
         session.session_id
     );
-    let auth_token = session.auth_jwt;
-    let refresh_token = session.refresh_jwt;
+    ensure_not_revoked(state, &session).await?;
+    let skew = state.config.token_refresh_skew.as_secs();
+    if token_exp(&session.auth_jwt)? > current_timestamp() + skew {
+        log!(
+            LogLevel::Debug,
+            "token still valid for {}",
+            session.session_id
+        );
+        return Ok(session.auth_jwt);
+    }
 
-    // check auth token exp
-    let auth_expire_time = peek_exp_from_jwt_unverified(&auth_token)
-        .map_err(|err| ErrorArrayItem::new(Errors::AppState, err.to_string()))?;
+    state
+        .refresh_locks
+        .with_lock(&session.session_id, refresh_locked(state, &session, skew))
+        .await
+}
 
-    let current_time = current_timestamp();
-    if auth_expire_time < current_time {
+/// Runs under the session's refresh lock. Whoever held it before may have
+/// refreshed already (or moved the session to a new id), so the stored
+/// session is consulted first.
+async fn refresh_locked(
+    state: &AppState,
+    session: &SessionData,
+    skew: u64,
+) -> Result<String, ErrorArrayItem> {
+    let session_id = state.session_rotation.resolve(&session.session_id).await;
+    let current = state.sessions.lookup(&session_id).await.map_err(|_| {
+        ErrorArrayItem::new(
+            Errors::AuthenticationError,
+            format!("session {} no longer exists", session.session_id),
+        )
+    })?;
+    ensure_not_revoked(state, &current).await?;
+    let auth_expire_time = token_exp(&current.auth_jwt)?;
+    let now = current_timestamp();
+    if auth_expire_time > now + skew {
         log!(
-            LogLevel::Info,
-            "{} token expired, refreshing...",
-            session.user_id
+            LogLevel::Debug,
+            "token for session {} already refreshed",
+            session.session_id
         );
+        return Ok(current.auth_jwt);
+    }
 
-        let request_body = json!({
-            "expired_token": auth_token,
-            "refresh_token": refresh_token
-        });
-
-        let response = get_state()
-            .http_client
-            .clone()
-            .post(&format!("{}auth/refresh", get_base_url()))
-            .json(&request_body)
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            let json: serde_json::Value = response.json().await?;
-            if let Some(new_token) = json.get("auth").and_then(|t| t.as_str()) {
-                return match update_session_auth(new_token.to_owned(), session.session_id.clone())
-                    .await
-                {
-                    Ok(token) => {
-                        log!(
-                            LogLevel::Info,
-                            "token refreshed for session {}",
-                            session.session_id
-                        );
-                        Ok(token)
-                    }
-                    Err(err) => Err(ErrorArrayItem::new(
-                        Errors::AuthenticationError,
-                        err.to_string(),
-                    )),
-                };
-            } else {
-                return Err(ErrorArrayItem::new(
-                    Errors::JsonReading,
-                    "Failed to de-serialize the servers response",
-                ));
+    log!(
+        LogLevel::Info,
+        "{} token expires at {}, refreshing...",
+        current.user_id,
+        auth_expire_time
+    );
+    match refresh(state, &current).await {
+        Ok(token) => Ok(token),
+        // Refreshing ahead of time failed for a transient reason; the old
+        // token is still good for now and the next request tries again. A
+        // refusal has already ended the session.
+        Err(err) if auth_expire_time > now && err.err_type != Errors::AuthenticationError => {
+            log!(
+                LogLevel::Warn,
+                "early refresh for session {} failed, keeping current token: {}",
+                session.session_id,
+                err.err_mesg
+            );
+            Ok(current.auth_jwt)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Error code the upstream answers with when a rotated-out refresh token is
+/// presented again. The token family is then considered stolen.
+const REFRESH_REUSED: &str = "RefreshTokenReused";
+
+async fn refresh(state: &AppState, session: &SessionData) -> Result<String, ErrorArrayItem> {
+    let request_body = json!({
+        "expired_token": session.auth_jwt,
+        "refresh_token": session.refresh_jwt
+    });
+
+    let response = state
+        .http_client
+        .clone()
+        .post(state.upstream_url("auth/refresh"))
+        .json(&request_body)
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            let body: serde_json::Value = response.json().await.unwrap_or_default();
+            let reused = body["errors"]
+                .as_array()
+                .is_some_and(|errors| errors.iter().any(|e| e["code"] == REFRESH_REUSED));
+            end_sessions(state, session, reused).await;
+        }
+        return Err(ErrorArrayItem::new(
+            Errors::AuthenticationError,
+            format!("Failed to refresh token for: {}", session.user_id),
+        ));
+    }
+
+    let json: serde_json::Value = response.json().await?;
+    let Some(new_token) = json.get("auth").and_then(|t| t.as_str()) else {
+        return Err(ErrorArrayItem::new(
+            Errors::JsonReading,
+            "Failed to de-serialize the servers response",
+        ));
+    };
+    check_refreshed(state, session, new_token, true).await?;
+
+    // Upstreams that rotate refresh tokens hand back a new one each time.
+    let rotated = json
+        .get("refresh")
+        .and_then(|t| t.as_str())
+        .filter(|t| *t != session.refresh_jwt);
+    let (token, rotated) = match rotated {
+        Some(new_refresh) => {
+            let exp = check_refreshed(state, session, new_refresh, false).await?;
+            let expires_at = DateTime::from_timestamp(exp as i64, 0).unwrap_or_default();
+            state
+                .sessions
+                .rotate(&session.session_id, new_token, new_refresh, expires_at)
+                .await
+                .map_err(|err| ErrorArrayItem::new(Errors::AuthenticationError, err))?;
+            (
+                new_token.to_owned(),
+                Some((new_refresh.to_owned(), expires_at)),
+            )
+        }
+        None => (
+            state
+                .sessions
+                .update_auth(&session.session_id, new_token.to_owned())
+                .await
+                .map_err(|err| ErrorArrayItem::new(Errors::AuthenticationError, err))?,
+            None,
+        ),
+    };
+
+    state
+        .session_cache
+        .update(&session.session_id, |cached| {
+            cached.auth_jwt = token.clone();
+            if let Some((refresh_jwt, expires_at)) = rotated {
+                cached.refresh_jwt = refresh_jwt;
+                cached.expires_at = expires_at;
             }
-        } else {
-            return Err(ErrorArrayItem::new(
-                Errors::AuthenticationError,
-                format!("Failed to refresh token for: {}", session.user_id),
-            ));
+        })
+        .await;
+    log!(
+        LogLevel::Info,
+        "token refreshed for session {}",
+        session.session_id
+    );
+    Ok(token)
+}
+
+/// Verifies a token from a refresh response when verification is on and
+/// returns its `exp`. Access tokens must also belong to the session's user.
+async fn check_refreshed(
+    state: &AppState,
+    session: &SessionData,
+    token: &str,
+    access: bool,
+) -> Result<u64, ErrorArrayItem> {
+    let Some(verifier) = &state.token_verifier else {
+        return token_exp(token);
+    };
+    let claims = verifier.verify(token, true).await.map_err(|err| {
+        log!(
+            LogLevel::Error,
+            "refreshed token for {} {}",
+            session.user_id,
+            err
+        );
+        ErrorArrayItem::new(Errors::AuthenticationError, err)
+    })?;
+    if access && claims.sub.as_deref() != Some(session.user_id.as_str()) {
+        log!(
+            LogLevel::Error,
+            "refreshed token for {} names another subject",
+            session.user_id
+        );
+        return Err(ErrorArrayItem::new(
+            Errors::AuthenticationError,
+            "refreshed token subject mismatch",
+        ));
+    }
+    Ok(claims.exp)
+}
+
+/// The upstream refused to refresh, so the session is over. On refresh
+/// token reuse every session of the user goes, since any of them may hold
+/// the stolen token family.
+async fn end_sessions(state: &AppState, session: &SessionData, reused: bool) {
+    if reused {
+        log!(
+            LogLevel::Warn,
+            "refresh token reuse reported for {}, ending all their sessions",
+            session.user_id
+        );
+        if let Err(e) = state.sessions.delete_user(&session.user_id).await {
+            log!(LogLevel::Error, "Error deleting sessions from DB: {}", e);
         }
+        state.session_cache.remove_user(&session.user_id).await;
     } else {
         log!(
-            LogLevel::Debug,
-            "token still valid for {}",
+            LogLevel::Info,
+            "refresh rejected, ending session {}",
             session.session_id
         );
-        return Ok(auth_token);
+        if let Err(e) = state.sessions.delete(&session.session_id).await {
+            log!(LogLevel::Error, "Error deleting session from DB: {}", e);
+        }
+        state.session_cache.remove(&session.session_id).await;
     }
 }
@@ -0,0 +1,323 @@
+//! Verification of tokens issued by the management API. Off unless a key
+//! source is configured; once on, login and refresh reject any token whose
+//! signature or claims don't check out.
+
+use std::{
+    error::Error,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use artisan_middleware::dusa_collection_utils::{
+    core::{logger::LogLevel, types::rwarc::LockWithTimeout},
+    log,
+};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Header, Validation, decode, decode_header,
+    jwk::{AlgorithmParameters, Jwk, JwkSet},
+};
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::config::{env_list, env_or};
+
+/// A token naming a key that isn't in the cached set triggers a refetch, at
+/// most this often.
+const MIN_REFETCH: Duration = Duration::from_secs(10);
+
+/// Where the upstream's verification keys come from.
+#[derive(Clone)]
+pub enum KeySource {
+    /// Shared HMAC secret (`UPSTREAM_JWT_SECRET`).
+    Secret(String),
+    /// PEM encoded RSA, EC or Ed25519 public key file
+    /// (`UPSTREAM_JWT_PUBLIC_KEY`).
+    PublicKey(String),
+    /// JWKS document served over HTTP (`UPSTREAM_JWKS_URL`).
+    JwksUrl(String),
+    /// JWKS document on disk (`UPSTREAM_JWKS_FILE`), re-read on refresh.
+    JwksFile(String),
+}
+
+pub struct TokenVerifyConfig {
+    /// `None` leaves verification off.
+    pub keys: Option<KeySource>,
+    /// Accepted `iss` values (`UPSTREAM_JWT_ISSUER`). When set the claim is
+    /// required.
+    pub issuer: Option<Vec<String>>,
+    /// Accepted `aud` values (`UPSTREAM_JWT_AUDIENCE`). When set the claim is
+    /// required.
+    pub audience: Option<Vec<String>>,
+    /// Narrows the algorithms the key allows (`UPSTREAM_JWT_ALGORITHMS`).
+    pub algorithms: Option<Vec<Algorithm>>,
+    /// Clock skew tolerated on `exp` and `nbf`.
+    pub leeway: u64,
+    /// How long a fetched JWKS is trusted before it is fetched again.
+    pub jwks_refresh: Duration,
+}
+
+impl TokenVerifyConfig {
+    pub fn from_env() -> Self {
+        let keys = std::env::var("UPSTREAM_JWKS_URL")
+            .map(KeySource::JwksUrl)
+            .or_else(|_| std::env::var("UPSTREAM_JWKS_FILE").map(KeySource::JwksFile))
+            .or_else(|_| std::env::var("UPSTREAM_JWT_PUBLIC_KEY").map(KeySource::PublicKey))
+            .or_else(|_| std::env::var("UPSTREAM_JWT_SECRET").map(KeySource::Secret))
+            .ok();
+
+        let algorithms = env_list("UPSTREAM_JWT_ALGORITHMS").map(|names| {
+            names
+                .iter()
+                .filter_map(|name| match Algorithm::from_str(name) {
+                    Ok(alg) => Some(alg),
+                    Err(_) => {
+                        log!(LogLevel::Warn, "ignoring unknown JWT algorithm {}", name);
+                        None
+                    }
+                })
+                .collect()
+        });
+
+        Self {
+            keys,
+            issuer: env_list("UPSTREAM_JWT_ISSUER"),
+            audience: env_list("UPSTREAM_JWT_AUDIENCE"),
+            algorithms,
+            leeway: env_or("UPSTREAM_JWT_LEEWAY_SECS", 30),
+            jwks_refresh: Duration::from_secs(env_or("UPSTREAM_JWKS_REFRESH_SECS", 3600)),
+        }
+    }
+}
+
+/// The claims the dashboard relies on.
+#[derive(Debug, Deserialize)]
+pub struct VerifiedClaims {
+    #[serde(default)]
+    pub sub: Option<String>,
+    pub exp: u64,
+}
+
+#[derive(Clone)]
+struct CachedJwks {
+    set: JwkSet,
+    fetched: Instant,
+}
+
+enum Keys {
+    Static {
+        key: DecodingKey,
+        algorithms: Vec<Algorithm>,
+    },
+    Jwks {
+        source: KeySource,
+        cache: LockWithTimeout<Option<CachedJwks>>,
+    },
+}
+
+pub struct TokenVerifier {
+    keys: Keys,
+    issuer: Option<Vec<String>>,
+    audience: Option<Vec<String>>,
+    algorithms: Option<Vec<Algorithm>>,
+    leeway: u64,
+    jwks_refresh: Duration,
+    http_client: Client,
+}
+
+impl TokenVerifier {
+    /// `None` when no key source is configured. Static keys and JWKS files
+    /// are loaded here so a bad one fails startup; a JWKS URL is fetched on
+    /// first use.
+    pub fn new(
+        config: &TokenVerifyConfig,
+        http_client: Client,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let keys = match &config.keys {
+            None => return Ok(None),
+            Some(KeySource::Secret(secret)) => Keys::Static {
+                key: DecodingKey::from_secret(secret.as_bytes()),
+                algorithms: vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+            },
+            Some(KeySource::PublicKey(path)) => {
+                let pem = std::fs::read(path)?;
+                let (key, algorithms) = pem_key(&pem)
+                    .ok_or_else(|| format!("{} is not an RSA, EC or Ed25519 public key", path))?;
+                Keys::Static { key, algorithms }
+            }
+            Some(source @ KeySource::JwksFile(path)) => {
+                let set = read_jwks_file(path)?;
+                Keys::Jwks {
+                    source: source.clone(),
+                    cache: LockWithTimeout::new(Some(CachedJwks {
+                        set,
+                        fetched: Instant::now(),
+                    })),
+                }
+            }
+            Some(source @ KeySource::JwksUrl(_)) => Keys::Jwks {
+                source: source.clone(),
+                cache: LockWithTimeout::new(None),
+            },
+        };
+
+        log!(LogLevel::Info, "upstream token verification enabled");
+        Ok(Some(Self {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            algorithms: config.algorithms.clone(),
+            leeway: config.leeway,
+            jwks_refresh: config.jwks_refresh,
+            http_client,
+        }))
+    }
+
+    /// Checks the signature, `nbf` and, when configured, `iss` and `aud`.
+    /// `exp` is only enforced when `check_exp`, since an expired access
+    /// token is still good for a refresh.
+    pub async fn verify(&self, token: &str, check_exp: bool) -> Result<VerifiedClaims, String> {
+        let header = decode_header(token).map_err(|e| format!("malformed token: {}", e))?;
+        let (key, allowed) = self.key_for(&header).await?;
+
+        let alg = header.alg;
+        if !allowed.contains(&alg) || self.algorithms.as_ref().is_some_and(|a| !a.contains(&alg)) {
+            return Err(format!("token algorithm {:?} not accepted", alg));
+        }
+
+        let mut validation = Validation::new(alg);
+        validation.leeway = self.leeway;
+        validation.validate_exp = check_exp;
+        validation.validate_nbf = true;
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(issuer);
+            required.push("iss");
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(audience);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
+
+        decode::<VerifiedClaims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| format!("token rejected: {}", e))
+    }
+
+    async fn key_for(&self, header: &Header) -> Result<(DecodingKey, Vec<Algorithm>), String> {
+        let (source, cache) = match &self.keys {
+            Keys::Static { key, algorithms } => return Ok((key.clone(), algorithms.clone())),
+            Keys::Jwks { source, cache } => (source, cache),
+        };
+
+        let cached = {
+            let guard = cache.try_read().await.map_err(|e| e.err_mesg.to_string())?;
+            (*guard).clone()
+        };
+        let fetch = match &cached {
+            None => true,
+            Some(c) => {
+                c.fetched.elapsed() >= self.jwks_refresh
+                    || (find_jwk(&c.set, header).is_none() && c.fetched.elapsed() >= MIN_REFETCH)
+            }
+        };
+
+        let set = match (fetch, cached) {
+            (false, Some(c)) => c.set,
+            (_, cached) => match self.fetch_jwks(source).await {
+                Ok(set) => {
+                    let mut guard = cache
+                        .try_write()
+                        .await
+                        .map_err(|e| e.err_mesg.to_string())?;
+                    *guard = Some(CachedJwks {
+                        set: set.clone(),
+                        fetched: Instant::now(),
+                    });
+                    log!(LogLevel::Debug, "JWKS refreshed, {} keys", set.keys.len());
+                    set
+                }
+                Err(e) => {
+                    log!(LogLevel::Warn, "JWKS refresh failed: {}", e);
+                    cached.map(|c| c.set).ok_or(e)?
+                }
+            },
+        };
+
+        let jwk = find_jwk(&set, header).ok_or("no JWKS key matches the token")?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("unusable JWKS key: {}", e))?;
+        let algorithms = match &jwk.common.key_algorithm {
+            Some(alg) => Algorithm::from_str(&alg.to_string()).into_iter().collect(),
+            None => family_algorithms(&jwk.algorithm),
+        };
+        Ok((key, algorithms))
+    }
+
+    async fn fetch_jwks(&self, source: &KeySource) -> Result<JwkSet, String> {
+        match source {
+            KeySource::JwksUrl(url) => self
+                .http_client
+                .get(url)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map_err(|e| e.to_string())?
+                .json()
+                .await
+                .map_err(|e| e.to_string()),
+            KeySource::JwksFile(path) => read_jwks_file(path).map_err(|e| e.to_string()),
+            _ => Err("not a JWKS source".to_owned()),
+        }
+    }
+}
+
+fn read_jwks_file(path: &str) -> Result<JwkSet, Box<dyn Error>> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// The key with the token's `kid`, or the only key when the token has none.
+fn find_jwk<'a>(set: &'a JwkSet, header: &Header) -> Option<&'a Jwk> {
+    match &header.kid {
+        Some(kid) => set.find(kid),
+        None if set.keys.len() == 1 => set.keys.first(),
+        None => None,
+    }
+}
+
+fn pem_key(pem: &[u8]) -> Option<(DecodingKey, Vec<Algorithm>)> {
+    if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+        return Some((key, rsa_algorithms()));
+    }
+    if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+        return Some((key, vec![Algorithm::ES256, Algorithm::ES384]));
+    }
+    DecodingKey::from_ed_pem(pem)
+        .ok()
+        .map(|key| (key, vec![Algorithm::EdDSA]))
+}
+
+fn rsa_algorithms() -> Vec<Algorithm> {
+    vec![
+        Algorithm::RS256,
+        Algorithm::RS384,
+        Algorithm::RS512,
+        Algorithm::PS256,
+        Algorithm::PS384,
+        Algorithm::PS512,
+    ]
+}
+
+/// Everything a JWK without an `alg` may sign with, going by its key type.
+fn family_algorithms(params: &AlgorithmParameters) -> Vec<Algorithm> {
+    match params {
+        AlgorithmParameters::OctetKey(_) => {
+            vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+        }
+        AlgorithmParameters::RSA(_) => rsa_algorithms(),
+        AlgorithmParameters::EllipticCurve(_) => vec![Algorithm::ES256, Algorithm::ES384],
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+    }
+}
@@ -0,0 +1,54 @@
+//! Runs the mock management API and secret service for local development,
+//! with `cargo run --features mock --bin mock_upstream`.
+//!
+//! | Variable             | Default           |
+//! |----------------------|-------------------|
+//! | `MOCK_UPSTREAM_ADDR` | `127.0.0.1:3900`  |
+//! | `MOCK_SECRET_ADDR`   | `127.0.0.1:50051` |
+//! | `MOCK_FIXTURES`      | unset: built-in users `alice` and `bob` |
+//!
+//! Then start the dashboard with `UPSTREAM_BASE_URL=http://127.0.0.1:3900/v1/`,
+//! `SECRET_GRPC_ADDR=http://127.0.0.1:50051` and `SECRET_GRPC_AUTH=none`.
+//! Without that last one secrets stay disabled, since the default assertion
+//! mode needs `SECRET_GRPC_SIGNING_KEY`. Add
+//! `UPSTREAM_JWKS_URL=http://127.0.0.1:3900/v1/.well-known/jwks.json` to
+//! have it verify the mock's tokens.
+
+use std::{error::Error, net::SocketAddr};
+
+use artisan_dashboard::mock::{MockFixtures, MockSecretService, MockUpstream};
+use artisan_middleware::dusa_collection_utils::{
+    core::logger::{LogLevel, set_log_level},
+    log,
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    dotenv::dotenv().ok();
+    set_log_level(LogLevel::Debug);
+
+    let fixtures = match std::env::var("MOCK_FIXTURES") {
+        Ok(path) => serde_json::from_slice(&tokio::fs::read(&path).await?)?,
+        Err(_) => MockFixtures::default(),
+    };
+
+    let upstream_addr: SocketAddr = std::env::var("MOCK_UPSTREAM_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:3900".to_owned())
+        .parse()?;
+    let secret_addr: SocketAddr = std::env::var("MOCK_SECRET_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:50051".to_owned())
+        .parse()?;
+
+    let upstream = MockUpstream::bind(upstream_addr, fixtures)?;
+    let secrets = MockSecretService::new().bind(secret_addr).await?;
+    log!(
+        LogLevel::Info,
+        "UPSTREAM_BASE_URL={} SECRET_GRPC_ADDR={}",
+        upstream.base_url(),
+        secrets.url()
+    );
+
+    tokio::signal::ctrl_c().await?;
+    log!(LogLevel::Info, "mock servers shutting down");
+    Ok(())
+}
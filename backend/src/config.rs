@@ -0,0 +1,121 @@
+use std::{env, str::FromStr, time::Duration};
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+
+use crate::{
+    api::{rate_limit::RateLimitConfig, secret::SecretPolicy, session_cookie::CookieConfig},
+    auth::{login_limit::LoginLimitConfig, verify::TokenVerifyConfig},
+    grpc::SecretClientConfig,
+};
+
+const DEFAULT_UPSTREAM_BASE_URL: &str = "https://api.artisanhosting.net/v1/";
+const DEFAULT_ALLOWED_ORIGINS: [&str; 2] = [
+    "http://localhost:3800",
+    "https://dashboard.artisanhosting.net",
+];
+
+/// Settings read from the environment (and `.env`) at startup.
+pub struct AppConfig {
+    /// Management API root, always ending in `/`. `UPSTREAM_BASE_URL`
+    /// points it at a mock or staging server.
+    pub upstream_base_url: String,
+    pub secret_policy: SecretPolicy,
+    pub secret_grpc: SecretClientConfig,
+    pub token_verify: TokenVerifyConfig,
+    /// Access tokens are refreshed once they expire within this long
+    /// (`TOKEN_REFRESH_SKEW_SECS`).
+    pub token_refresh_skew: Duration,
+    pub session_cookie: CookieConfig,
+    /// Origins the frontend is served from (`ALLOWED_ORIGINS`), for CORS and
+    /// for refusing cross-site writes.
+    pub allowed_origins: Vec<String>,
+    /// Key for CSRF tokens (`CSRF_SECRET`). Must be shared by every
+    /// instance behind a load balancer.
+    pub csrf_secret: Option<String>,
+    pub login_limit: LoginLimitConfig,
+    /// Take the client address from `X-Forwarded-For`
+    /// (`TRUST_FORWARDED_FOR`). Only safe behind a proxy that sets it.
+    pub trust_forwarded_for: bool,
+    pub rate_limit: RateLimitConfig,
+    /// Bearer token for `GET /api/metrics` (`METRICS_TOKEN`). The route
+    /// stays off without one.
+    pub metrics_token: Option<String>,
+    /// Sessions unused this long end (`SESSION_IDLE_TIMEOUT_SECS`). Zero
+    /// turns the idle timeout off.
+    pub session_idle_timeout: Duration,
+    /// Sessions end this long after login however active they are
+    /// (`SESSION_MAX_LIFETIME_SECS`). Zero turns the limit off.
+    pub session_max_lifetime: Duration,
+    /// How often recorded activity is written to the session store
+    /// (`SESSION_ACTIVITY_FLUSH_SECS`).
+    pub activity_flush: Duration,
+    /// Management API path that revokes a session's tokens at logout
+    /// (`UPSTREAM_REVOKE_PATH`). Set it empty for upstreams without one;
+    /// tokens are then only refused locally.
+    pub revoke_path: Option<String>,
+    /// How long a login answered with an MFA challenge waits for its code
+    /// (`MFA_PENDING_TTL_SECS`).
+    pub mfa_pending_ttl: Duration,
+}
+
+impl AppConfig {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut upstream_base_url =
+            env::var("UPSTREAM_BASE_URL").unwrap_or_else(|_| DEFAULT_UPSTREAM_BASE_URL.to_owned());
+        if !upstream_base_url.ends_with('/') {
+            upstream_base_url.push('/');
+        }
+
+        Ok(Self {
+            upstream_base_url,
+            secret_policy: SecretPolicy::from_env()?,
+            secret_grpc: SecretClientConfig::from_env(),
+            token_verify: TokenVerifyConfig::from_env(),
+            token_refresh_skew: Duration::from_secs(env_or("TOKEN_REFRESH_SKEW_SECS", 60)),
+            session_cookie: CookieConfig::from_env(),
+            allowed_origins: env_list("ALLOWED_ORIGINS").unwrap_or_else(|| {
+                DEFAULT_ALLOWED_ORIGINS
+                    .iter()
+                    .map(|o| o.to_string())
+                    .collect()
+            }),
+            csrf_secret: env::var("CSRF_SECRET").ok(),
+            login_limit: LoginLimitConfig::from_env(),
+            trust_forwarded_for: env_or("TRUST_FORWARDED_FOR", false),
+            rate_limit: RateLimitConfig::from_env(),
+            metrics_token: env::var("METRICS_TOKEN").ok(),
+            session_idle_timeout: Duration::from_secs(env_or("SESSION_IDLE_TIMEOUT_SECS", 3600)),
+            session_max_lifetime: Duration::from_secs(env_or(
+                "SESSION_MAX_LIFETIME_SECS",
+                7 * 24 * 3600,
+            )),
+            activity_flush: Duration::from_secs(env_or("SESSION_ACTIVITY_FLUSH_SECS", 30)),
+            revoke_path: Some(env_or("UPSTREAM_REVOKE_PATH", "auth/revoke".to_owned()))
+                .map(|path| path.trim_matches('/').to_owned())
+                .filter(|path| !path.is_empty()),
+            mfa_pending_ttl: Duration::from_secs(env_or("MFA_PENDING_TTL_SECS", 300)),
+        })
+    }
+}
+
+/// Reads and parses `name`, falling back to `default` when it is unset or
+/// doesn't parse.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(raw) => raw.trim().parse().unwrap_or_else(|_| {
+            log!(LogLevel::Warn, "ignoring invalid value for {}", name);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Reads a comma separated list, dropping empty entries.
+pub fn env_list(name: &str) -> Option<Vec<String>> {
+    env::var(name).ok().map(|raw| {
+        raw.split(',')
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, HashSet};
+
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use chrono::{DateTime, Utc};
+use sqlx::{MySqlPool, Row};
+
+use crate::api::rate_limit::RouteClass;
+
+/// A personal access token. Only a digest of the secret is kept; the
+/// upstream tokens it acts with belong to its own session, `session_id`.
+#[derive(Clone, Debug)]
+pub struct ApiToken {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<RouteClass>,
+    pub session_id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+/// Where access tokens live: next to the sessions in
+///
+/// ```sql
+/// CREATE TABLE api_tokens (
+///     id         CHAR(36)     PRIMARY KEY,
+///     user_id    VARCHAR(255) NOT NULL,
+///     name       VARCHAR(64)  NOT NULL,
+///     token_hash CHAR(64)     NOT NULL UNIQUE,
+///     scopes     VARCHAR(255) NOT NULL,
+///     session_id VARCHAR(255) NOT NULL,
+///     created_at DATETIME     NOT NULL,
+///     expires_at DATETIME     NOT NULL,
+///     last_used  DATETIME     NULL,
+///     INDEX (user_id)
+/// );
+/// ```
+///
+/// or in memory when sessions are.
+pub enum ApiTokenStore {
+    MySql(MySqlPool),
+    Memory(LockWithTimeout<HashMap<String, ApiToken>>),
+}
+
+fn scopes_to_str(scopes: &[RouteClass]) -> String {
+    scopes
+        .iter()
+        .map(|s| s.name())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn scopes_from_str(raw: &str) -> Vec<RouteClass> {
+    raw.split(',').filter_map(RouteClass::from_name).collect()
+}
+
+fn from_row(r: sqlx::mysql::MySqlRow) -> Result<ApiToken, sqlx::Error> {
+    let scopes: String = r.try_get("scopes")?;
+    Ok(ApiToken {
+        id: r.try_get("id")?,
+        user_id: r.try_get("user_id")?,
+        name: r.try_get("name")?,
+        token_hash: r.try_get("token_hash")?,
+        scopes: scopes_from_str(&scopes),
+        session_id: r.try_get("session_id")?,
+        created_at: r.try_get("created_at")?,
+        expires_at: r.try_get("expires_at")?,
+        last_used: r.try_get("last_used")?,
+    })
+}
+
+const COLUMNS: &str =
+    "id, user_id, name, token_hash, scopes, session_id, created_at, expires_at, last_used";
+
+impl ApiTokenStore {
+    pub fn memory() -> Self {
+        ApiTokenStore::Memory(LockWithTimeout::new(HashMap::new()))
+    }
+
+    pub async fn insert(&self, token: &ApiToken) -> Result<(), String> {
+        match self {
+            ApiTokenStore::MySql(pool) => sqlx::query(&format!(
+                "INSERT INTO api_tokens ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                COLUMNS
+            ))
+            .bind(&token.id)
+            .bind(&token.user_id)
+            .bind(&token.name)
+            .bind(&token.token_hash)
+            .bind(scopes_to_str(&token.scopes))
+            .bind(&token.session_id)
+            .bind(token.created_at)
+            .bind(token.expires_at)
+            .bind(token.last_used)
+            .execute(pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+            ApiTokenStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                let now = Utc::now();
+                guard.retain(|_, t| t.expires_at > now);
+                guard.insert(token.id.clone(), token.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// The unexpired token whose secret digests to `token_hash`.
+    pub async fn by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, String> {
+        let token = match self {
+            ApiTokenStore::MySql(pool) => sqlx::query(&format!(
+                "SELECT {} FROM api_tokens WHERE token_hash = ?",
+                COLUMNS
+            ))
+            .bind(token_hash)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| e.to_string())?
+            .map(from_row)
+            .transpose()
+            .map_err(|e| e.to_string())?,
+            ApiTokenStore::Memory(map) => {
+                let guard = map.try_read().await.map_err(|e| e.err_mesg.to_string())?;
+                guard.values().find(|t| t.token_hash == token_hash).cloned()
+            }
+        };
+        Ok(token.filter(|t| t.expires_at > Utc::now()))
+    }
+
+    /// The user's unexpired tokens, newest first.
+    pub async fn list_user(&self, user_id: &str) -> Result<Vec<ApiToken>, String> {
+        let mut tokens = match self {
+            ApiTokenStore::MySql(pool) => sqlx::query(&format!(
+                "SELECT {} FROM api_tokens WHERE user_id = ? AND expires_at > ?",
+                COLUMNS
+            ))
+            .bind(user_id)
+            .bind(Utc::now())
+            .fetch_all(pool)
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(from_row)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?,
+            ApiTokenStore::Memory(map) => {
+                let guard = map.try_read().await.map_err(|e| e.err_mesg.to_string())?;
+                let now = Utc::now();
+                guard
+                    .values()
+                    .filter(|t| t.user_id == user_id && t.expires_at > now)
+                    .cloned()
+                    .collect()
+            }
+        };
+        tokens.sort_by_key(|t| std::cmp::Reverse(t.created_at));
+        Ok(tokens)
+    }
+
+    /// Sessions that unexpired tokens act through.
+    pub async fn session_ids(&self) -> Result<HashSet<String>, String> {
+        match self {
+            ApiTokenStore::MySql(pool) => {
+                sqlx::query("SELECT session_id FROM api_tokens WHERE expires_at > ?")
+                    .bind(Utc::now())
+                    .fetch_all(pool)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .into_iter()
+                    .map(|r| r.try_get("session_id").map_err(|e| e.to_string()))
+                    .collect()
+            }
+            ApiTokenStore::Memory(map) => {
+                let guard = map.try_read().await.map_err(|e| e.err_mesg.to_string())?;
+                let now = Utc::now();
+                Ok(guard
+                    .values()
+                    .filter(|t| t.expires_at > now)
+                    .map(|t| t.session_id.clone())
+                    .collect())
+            }
+        }
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), String> {
+        match self {
+            ApiTokenStore::MySql(pool) => sqlx::query("DELETE FROM api_tokens WHERE id = ?")
+                .bind(id)
+                .execute(pool)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            ApiTokenStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                guard.remove(id);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), String> {
+        match self {
+            ApiTokenStore::MySql(pool) => {
+                sqlx::query("UPDATE api_tokens SET last_used = ? WHERE id = ?")
+                    .bind(at)
+                    .bind(id)
+                    .execute(pool)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+            ApiTokenStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                if let Some(token) = guard.get_mut(id) {
+                    token.last_used = Some(at);
+                }
+                Ok(())
+            }
+        }
+    }
+}
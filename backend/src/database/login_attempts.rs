@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use chrono::{DateTime, Utc};
+use sqlx::{MySqlPool, Row};
+
+/// Failed logins recorded against one key (an address or an email).
+#[derive(Clone, Debug)]
+pub struct AttemptRecord {
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// Where login failure counters live. `Memory` is the default; `MySql`
+/// keeps them across restarts and shares them between instances, in
+///
+/// ```sql
+/// CREATE TABLE login_attempts (
+///     attempt_key  VARCHAR(320) PRIMARY KEY,
+///     failures     INT UNSIGNED NOT NULL,
+///     last_failure DATETIME NOT NULL,
+///     locked_until DATETIME NULL
+/// );
+/// ```
+pub enum AttemptStore {
+    MySql(MySqlPool),
+    Memory(LockWithTimeout<HashMap<String, AttemptRecord>>),
+}
+
+impl AttemptStore {
+    pub fn memory() -> Self {
+        AttemptStore::Memory(LockWithTimeout::new(HashMap::new()))
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<AttemptRecord>, String> {
+        match self {
+            AttemptStore::MySql(pool) => {
+                let row = sqlx::query(
+                    r#"SELECT failures, last_failure, locked_until
+                    FROM login_attempts
+                    WHERE attempt_key = ?"#,
+                )
+                .bind(key)
+                .fetch_optional(pool)
+                .await
+                .map_err(|e| e.to_string())?;
+
+                row.map(|r| -> Result<AttemptRecord, sqlx::Error> {
+                    Ok(AttemptRecord {
+                        failures: r.try_get("failures")?,
+                        last_failure: r.try_get("last_failure")?,
+                        locked_until: r.try_get("locked_until")?,
+                    })
+                })
+                .transpose()
+                .map_err(|e| e.to_string())
+            }
+            AttemptStore::Memory(map) => {
+                let guard = map.try_read().await.map_err(|e| e.err_mesg.to_string())?;
+                Ok(guard.get(key).cloned())
+            }
+        }
+    }
+
+    /// Counts a failure at `now` against `key` in one step, so parallel
+    /// failures can't overwrite each other. Failures before `stale` are
+    /// forgotten first, and reaching `lockout_after` locks the key until
+    /// `lockout_until`. Returns the updated record. The in-memory store also
+    /// drops records last touched before `stale` that aren't locked, so it
+    /// doesn't grow without bound.
+    pub async fn add_failure(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+        stale: DateTime<Utc>,
+        lockout_after: u32,
+        lockout_until: DateTime<Utc>,
+    ) -> Result<AttemptRecord, String> {
+        match self {
+            AttemptStore::MySql(pool) => {
+                // Assignments apply left to right: `locked_until` sees the
+                // new count and `failures` the old `last_failure`.
+                sqlx::query(
+                    r#"INSERT INTO login_attempts (attempt_key, failures, last_failure, locked_until)
+                    VALUES (?, 1, ?, IF(1 >= ?, ?, NULL))
+                    ON DUPLICATE KEY UPDATE
+                        failures = IF(last_failure > ?, failures + 1, 1),
+                        locked_until = IF(failures >= ?, ?, locked_until),
+                        last_failure = VALUES(last_failure)"#,
+                )
+                .bind(key)
+                .bind(now)
+                .bind(lockout_after)
+                .bind(lockout_until)
+                .bind(stale)
+                .bind(lockout_after)
+                .bind(lockout_until)
+                .execute(pool)
+                .await
+                .map_err(|e| e.to_string())?;
+                self.get(key)
+                    .await?
+                    .ok_or_else(|| format!("no login attempts for {}", key))
+            }
+            AttemptStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                guard.retain(|_, r| {
+                    r.last_failure > stale || r.locked_until.is_some_and(|t| t > now)
+                });
+                let record = guard.entry(key.to_owned()).or_insert(AttemptRecord {
+                    failures: 0,
+                    last_failure: now,
+                    locked_until: None,
+                });
+                if record.last_failure <= stale {
+                    record.failures = 0;
+                }
+                record.failures = record.failures.saturating_add(1);
+                record.last_failure = now;
+                if record.failures >= lockout_after {
+                    record.locked_until = Some(lockout_until);
+                }
+                Ok(record.clone())
+            }
+        }
+    }
+
+    pub async fn remove(&self, key: &str) -> Result<(), String> {
+        match self {
+            AttemptStore::MySql(pool) => {
+                sqlx::query("DELETE FROM login_attempts WHERE attempt_key = ?")
+                    .bind(key)
+                    .execute(pool)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+            AttemptStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                guard.remove(key);
+                Ok(())
+            }
+        }
+    }
+}
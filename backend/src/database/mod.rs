@@ -1 +1,4 @@
+pub mod api_tokens;
 pub mod connection;
+pub mod login_attempts;
+pub mod sessions;
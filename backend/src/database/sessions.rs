@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use artisan_middleware::dusa_collection_utils::{
+    core::{logger::LogLevel, types::rwarc::LockWithTimeout},
+    log,
+};
+use chrono::{DateTime, Utc};
+use sqlx::MySqlPool;
+
+use crate::api::cookie::{
+    SessionData, SessionMeta, delete_session, delete_user_sessions, insert_session,
+    list_user_sessions, load_active_sessions, lookup_session, rename_session,
+    rotate_session_tokens, session_meta, touch_session, update_session_auth,
+};
+
+/// Where sessions live. Production uses MySQL; `Memory` keeps them in
+/// process for tests and database-less development.
+pub enum SessionStore {
+    MySql(MySqlPool),
+    Memory(LockWithTimeout<HashMap<String, (SessionData, SessionMeta)>>),
+}
+
+impl SessionStore {
+    pub fn memory() -> Self {
+        SessionStore::Memory(LockWithTimeout::new(HashMap::new()))
+    }
+
+    /// Stores a session opened now with no client details.
+    pub async fn insert(&self, session: &SessionData) -> Result<(), String> {
+        self.insert_with_meta(session, &SessionMeta::new(None, None))
+            .await
+    }
+
+    pub async fn insert_with_meta(
+        &self,
+        session: &SessionData,
+        meta: &SessionMeta,
+    ) -> Result<(), String> {
+        match self {
+            SessionStore::MySql(pool) => insert_session(pool, session, meta)
+                .await
+                .map_err(|e| e.to_string()),
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                guard.insert(session.session_id.clone(), (session.clone(), meta.clone()));
+                Ok(())
+            }
+        }
+    }
+
+    /// The session, unless it is unknown or past `expires_at`.
+    pub async fn lookup(&self, session_id: &str) -> Result<SessionData, ()> {
+        match self {
+            SessionStore::MySql(pool) => lookup_session(pool, session_id.to_owned()).await,
+            SessionStore::Memory(map) => {
+                let guard = map.try_read().await.map_err(|_| ())?;
+                match guard.get(session_id) {
+                    Some((session, _)) if session.expires_at > Utc::now() => Ok(session.clone()),
+                    _ => {
+                        log!(LogLevel::Warn, "no live session {}", session_id);
+                        Err(())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stores a refreshed access token, returning it back.
+    pub async fn update_auth(&self, session_id: &str, auth: String) -> Result<String, String> {
+        match self {
+            SessionStore::MySql(pool) => {
+                update_session_auth(pool, auth, session_id.to_owned()).await
+            }
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                if let Some((session, _)) = guard.get_mut(session_id) {
+                    session.auth_jwt = auth.clone();
+                }
+                Ok(auth)
+            }
+        }
+    }
+
+    /// Stores both tokens after a refresh that rotated the refresh token.
+    pub async fn rotate(
+        &self,
+        session_id: &str,
+        auth: &str,
+        refresh: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), String> {
+        match self {
+            SessionStore::MySql(pool) => {
+                rotate_session_tokens(pool, session_id, auth, refresh, expires_at)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                if let Some((session, _)) = guard.get_mut(session_id) {
+                    session.auth_jwt = auth.to_owned();
+                    session.refresh_jwt = refresh.to_owned();
+                    session.expires_at = expires_at;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn delete(&self, session_id: &str) -> Result<(), String> {
+        match self {
+            SessionStore::MySql(pool) => delete_session(pool, session_id)
+                .await
+                .map_err(|e| e.to_string()),
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                guard.remove(session_id);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn delete_user(&self, user_id: &str) -> Result<(), String> {
+        match self {
+            SessionStore::MySql(pool) => delete_user_sessions(pool, user_id)
+                .await
+                .map_err(|e| e.to_string()),
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                guard.retain(|_, (s, _)| s.user_id != user_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Drops expired sessions and returns the rest, for warming caches at
+    /// startup.
+    pub async fn load_active(&self) -> Result<Vec<SessionData>, String> {
+        match self {
+            SessionStore::MySql(pool) => {
+                load_active_sessions(pool).await.map_err(|e| e.to_string())
+            }
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                let now = Utc::now();
+                guard.retain(|_, (s, _)| s.expires_at > now);
+                Ok(guard.values().map(|(s, _)| s.clone()).collect())
+            }
+        }
+    }
+
+    /// Moves the session to `new_id`, keeping its tokens and metadata.
+    pub async fn rename(&self, old_id: &str, new_id: &str) -> Result<(), String> {
+        match self {
+            SessionStore::MySql(pool) => rename_session(pool, old_id, new_id)
+                .await
+                .map_err(|e| e.to_string()),
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                let (mut session, meta) = guard
+                    .remove(old_id)
+                    .ok_or_else(|| format!("no session {}", old_id))?;
+                session.session_id = new_id.to_owned();
+                guard.insert(new_id.to_owned(), (session, meta));
+                Ok(())
+            }
+        }
+    }
+
+    /// Records that the session was last used at `at`.
+    pub async fn touch(&self, session_id: &str, at: DateTime<Utc>) -> Result<(), String> {
+        match self {
+            SessionStore::MySql(pool) => touch_session(pool, session_id, at)
+                .await
+                .map_err(|e| e.to_string()),
+            SessionStore::Memory(map) => {
+                let mut guard = map.try_write().await.map_err(|e| e.err_mesg.to_string())?;
+                if let Some((_, meta)) = guard.get_mut(session_id) {
+                    meta.last_seen = at;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn meta(&self, session_id: &str) -> Result<SessionMeta, String> {
+        match self {
+            SessionStore::MySql(pool) => session_meta(pool, session_id)
+                .await
+                .map_err(|e| e.to_string()),
+            SessionStore::Memory(map) => {
+                let guard = map.try_read().await.map_err(|e| e.err_mesg.to_string())?;
+                guard
+                    .get(session_id)
+                    .map(|(_, meta)| meta.clone())
+                    .ok_or_else(|| format!("no session {}", session_id))
+            }
+        }
+    }
+
+    /// The user's live sessions, most recently used first.
+    pub async fn list_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(SessionData, SessionMeta)>, String> {
+        match self {
+            SessionStore::MySql(pool) => list_user_sessions(pool, user_id)
+                .await
+                .map_err(|e| e.to_string()),
+            SessionStore::Memory(map) => {
+                let guard = map.try_read().await.map_err(|e| e.err_mesg.to_string())?;
+                let now = Utc::now();
+                let mut sessions: Vec<_> = guard
+                    .values()
+                    .filter(|(s, _)| s.user_id == user_id && s.expires_at > now)
+                    .cloned()
+                    .collect();
+                sessions.sort_by_key(|(_, meta)| std::cmp::Reverse(meta.last_seen));
+                Ok(sessions)
+            }
+        }
+    }
+}
@@ -2,52 +2,381 @@ pub mod secret_service {
     tonic::include_proto!("secret_service");
 }
 
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
 use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use chrono::Utc;
+use jsonwebtoken::{EncodingKey, Header};
 use secret_service::secret_service_client::SecretServiceClient;
-use tonic::transport::Channel;
+use serde::Serialize;
+use tonic::{
+    Code, Request, Response, Status,
+    metadata::MetadataValue,
+    service::{Interceptor, interceptor::InterceptedService},
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity},
+};
+
+use crate::config::env_or;
+
+/// PEM files for talking to the secret service over TLS.
+pub struct SecretTlsConfig {
+    pub ca_cert: String,
+    /// Client certificate and key, for services that require mutual TLS.
+    pub client_identity: Option<(String, String)>,
+    /// Overrides the name checked against the server certificate.
+    pub domain: Option<String>,
+}
+
+/// What the dashboard presents to the secret service on each call.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SecretAuthMode {
+    /// No credentials; the service has to trust the network.
+    None,
+    /// A short-lived HS256 JWT signed by the dashboard naming the user and
+    /// session.
+    Assertion,
+    /// The user's own upstream bearer token.
+    Bearer,
+}
+
+/// The user a call is made on behalf of.
+#[derive(Clone)]
+pub struct Caller {
+    pub user_id: String,
+    pub session_id: String,
+    /// Only needed in [`SecretAuthMode::Bearer`].
+    pub bearer: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CallerAssertion<'a> {
+    iss: &'static str,
+    aud: &'static str,
+    sub: &'a str,
+    sid: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+pub const ASSERTION_ISSUER: &str = "artisan-dashboard";
+pub const ASSERTION_AUDIENCE: &str = "secret-service";
+
+/// Adds an `authorization` header built from the [`Caller`] that
+/// [`SecretClient::call`] stores in the request extensions.
+#[derive(Clone)]
+pub struct AuthInterceptor {
+    mode: SecretAuthMode,
+    key: Option<EncodingKey>,
+    ttl: i64,
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        if self.mode == SecretAuthMode::None {
+            return Ok(req);
+        }
+        let caller = req
+            .extensions()
+            .get::<Caller>()
+            .ok_or_else(|| Status::unauthenticated("secret call without a caller"))?;
+
+        let token = match self.mode {
+            SecretAuthMode::Bearer => caller
+                .bearer
+                .clone()
+                .ok_or_else(|| Status::unauthenticated("no bearer token for caller"))?,
+            _ => {
+                let key = self
+                    .key
+                    .as_ref()
+                    .ok_or_else(|| Status::internal("no signing key configured"))?;
+                let now = Utc::now().timestamp();
+                let claims = CallerAssertion {
+                    iss: ASSERTION_ISSUER,
+                    aud: ASSERTION_AUDIENCE,
+                    sub: &caller.user_id,
+                    sid: &caller.session_id,
+                    iat: now,
+                    exp: now + self.ttl,
+                };
+                jsonwebtoken::encode(&Header::default(), &claims, key)
+                    .map_err(|e| Status::internal(format!("signing caller assertion: {}", e)))?
+            }
+        };
+
+        let value = MetadataValue::try_from(format!("Bearer {}", token))
+            .map_err(|_| Status::internal("invalid authorization metadata"))?;
+        req.metadata_mut().insert("authorization", value);
+        Ok(req)
+    }
+}
+
+/// How the dashboard reaches the secret service.
+///
+/// | Variable                          | Default |
+/// |-----------------------------------|---------|
+/// | `SECRET_GRPC_ADDR`                | unset: secrets disabled |
+/// | `SECRET_GRPC_TIMEOUT_MS`          | `5000`, retries included |
+/// | `SECRET_GRPC_CONNECT_TIMEOUT_MS`  | `3000`  |
+/// | `SECRET_GRPC_RETRIES`             | `2`, reads only |
+/// | `SECRET_GRPC_CA_CERT`             | unset: plaintext |
+/// | `SECRET_GRPC_CLIENT_CERT` / `_KEY`| unset   |
+/// | `SECRET_GRPC_TLS_DOMAIN`          | unset   |
+/// | `SECRET_GRPC_AUTH`                | `assertion`; `none` must be asked for |
+/// | `SECRET_GRPC_SIGNING_KEY`         | unset   |
+/// | `SECRET_GRPC_ASSERTION_TTL_SECS`  | `60`    |
+pub struct SecretClientConfig {
+    pub addr: Option<String>,
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub retries: u32,
+    pub tls: Option<SecretTlsConfig>,
+    pub auth: SecretAuthMode,
+    pub signing_key: Option<String>,
+    pub assertion_ttl: i64,
+}
+
+impl SecretClientConfig {
+    pub fn from_env() -> Self {
+        let tls = std::env::var("SECRET_GRPC_CA_CERT")
+            .ok()
+            .map(|ca_cert| SecretTlsConfig {
+                ca_cert,
+                client_identity: std::env::var("SECRET_GRPC_CLIENT_CERT")
+                    .ok()
+                    .zip(std::env::var("SECRET_GRPC_CLIENT_KEY").ok()),
+                domain: std::env::var("SECRET_GRPC_TLS_DOMAIN").ok(),
+            });
+
+        let signing_key = std::env::var("SECRET_GRPC_SIGNING_KEY").ok();
+        // Unauthenticated calls are never a fallback: without a signing key
+        // the default fails in `connect_lazy` until one is set or
+        // `SECRET_GRPC_AUTH=none` says the network is trusted.
+        let auth = match std::env::var("SECRET_GRPC_AUTH").as_deref() {
+            Ok("none") => SecretAuthMode::None,
+            Ok("bearer") => SecretAuthMode::Bearer,
+            Ok("assertion") | Err(_) => SecretAuthMode::Assertion,
+            Ok(other) => {
+                log!(
+                    LogLevel::Warn,
+                    "unknown SECRET_GRPC_AUTH {:?}, using assertion",
+                    other
+                );
+                SecretAuthMode::Assertion
+            }
+        };
+
+        Self {
+            addr: std::env::var("SECRET_GRPC_ADDR").ok(),
+            timeout: Duration::from_millis(env_or("SECRET_GRPC_TIMEOUT_MS", 5000)),
+            connect_timeout: Duration::from_millis(env_or("SECRET_GRPC_CONNECT_TIMEOUT_MS", 3000)),
+            retries: env_or("SECRET_GRPC_RETRIES", 2),
+            tls,
+            auth,
+            signing_key,
+            assertion_ttl: env_or("SECRET_GRPC_ASSERTION_TTL_SECS", 60),
+        }
+    }
+}
+
+type AuthedClient = SecretServiceClient<InterceptedService<Channel, AuthInterceptor>>;
 
 #[derive(Clone)]
 pub struct SecretClient {
-    client: SecretServiceClient<Channel>,
+    client: AuthedClient,
+    timeout: Duration,
+    retries: u32,
+    auth: SecretAuthMode,
+    caller: Option<Caller>,
 }
 
 impl SecretClient {
-    pub async fn connect(addr: String) -> Result<Self, tonic::transport::Error> {
-        log!(LogLevel::Info, "connecting SecretService gRPC at {}", addr);
-        let client = SecretServiceClient::connect(addr.clone()).await?;
-        log!(LogLevel::Info, "connected gRPC at {}", addr);
-        Ok(Self { client })
+    /// Builds a client without dialing. The channel connects on first use and
+    /// reconnects on its own, so a secret service that is down at boot only
+    /// affects secret requests.
+    pub fn connect_lazy(
+        addr: &str,
+        config: &SecretClientConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        log!(LogLevel::Info, "configuring SecretService gRPC at {}", addr);
+        let mut endpoint = Endpoint::from_shared(addr.to_owned())?
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .tcp_keepalive(Some(Duration::from_secs(30)));
+
+        if let Some(tls) = &config.tls {
+            let mut tls_config = ClientTlsConfig::new()
+                .ca_certificate(Certificate::from_pem(std::fs::read(&tls.ca_cert)?));
+            if let Some((cert, key)) = &tls.client_identity {
+                tls_config = tls_config.identity(Identity::from_pem(
+                    std::fs::read(cert)?,
+                    std::fs::read(key)?,
+                ));
+            }
+            if let Some(domain) = &tls.domain {
+                tls_config = tls_config.domain_name(domain.clone());
+            }
+            endpoint = endpoint.tls_config(tls_config)?;
+            log!(LogLevel::Info, "SecretService gRPC uses TLS");
+        }
+
+        if config.auth == SecretAuthMode::Assertion && config.signing_key.is_none() {
+            return Err(
+                "SECRET_GRPC_AUTH=assertion (the default) needs SECRET_GRPC_SIGNING_KEY; \
+                 set SECRET_GRPC_AUTH=none to call without credentials"
+                    .into(),
+            );
+        }
+        if config.auth == SecretAuthMode::None {
+            log!(
+                LogLevel::Warn,
+                "SECRET_GRPC_AUTH=none: secret calls carry no credentials"
+            );
+        }
+        let interceptor = AuthInterceptor {
+            mode: config.auth,
+            key: config
+                .signing_key
+                .as_ref()
+                .map(|k| EncodingKey::from_secret(k.as_bytes())),
+            ttl: config.assertion_ttl,
+        };
+
+        Ok(Self {
+            client: SecretServiceClient::with_interceptor(endpoint.connect_lazy(), interceptor),
+            timeout: config.timeout,
+            retries: config.retries,
+            auth: config.auth,
+            caller: None,
+        })
+    }
+
+    pub fn auth_mode(&self) -> SecretAuthMode {
+        self.auth
+    }
+
+    /// A copy of this client whose calls are made on behalf of `caller`.
+    pub fn for_caller(&self, caller: Caller) -> Self {
+        Self {
+            caller: Some(caller),
+            ..self.clone()
+        }
+    }
+
+    /// Sends `req` with a deadline, retrying up to `retries` times with
+    /// backoff while the service is `Unavailable` and the deadline allows. That status can also come
+    /// back after the service acted on the call (a reset connection, a proxy
+    /// giving up), so writes pass `0` rather than risk applying twice.
+    async fn call<Req, Resp, F, Fut>(
+        &self,
+        name: &str,
+        req: Req,
+        retries: u32,
+        f: F,
+    ) -> Result<Resp, Status>
+    where
+        Req: Clone,
+        F: Fn(AuthedClient, Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Response<Resp>, Status>>,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let mut attempt = 0;
+        loop {
+            log!(LogLevel::Debug, "gRPC {} attempt {}", name, attempt + 1);
+            let mut request = Request::new(req.clone());
+            request.set_timeout(deadline.saturating_duration_since(Instant::now()));
+            if let Some(caller) = &self.caller {
+                request.extensions_mut().insert(caller.clone());
+            }
+            match f(self.client.clone(), request).await {
+                Ok(resp) => return Ok(resp.into_inner()),
+                Err(status) if status.code() == Code::Unavailable && attempt < retries => {
+                    attempt += 1;
+                    // 200ms doubling up to 6.4s, however many retries are set.
+                    let delay = Duration::from_millis(100u64.saturating_mul(1 << attempt.min(6)));
+                    if Instant::now() + delay >= deadline {
+                        return Err(status);
+                    }
+                    log!(
+                        LogLevel::Warn,
+                        "gRPC {} unavailable, retry {}/{}",
+                        name,
+                        attempt,
+                        retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(status) => return Err(status),
+            }
+        }
     }
 
     pub async fn create_secret(
-        &mut self,
+        &self,
         req: secret_service::CreateSecretRequest,
-    ) -> Result<secret_service::SimpleSecretResponse, tonic::Status> {
-        log!(LogLevel::Debug, "gRPC create_secret");
-        Ok(self.client.create_secret(req).await?.into_inner())
+    ) -> Result<secret_service::SimpleSecretResponse, Status> {
+        self.call("create_secret", req, 0, |mut c, r| async move {
+            c.create_secret(r).await
+        })
+        .await
+    }
+
+    pub async fn get_secret(
+        &self,
+        req: secret_service::GetSecretRequest,
+    ) -> Result<secret_service::GetSecretResponse, Status> {
+        self.call("get_secret", req, self.retries, |mut c, r| async move {
+            c.get_secret(r).await
+        })
+        .await
     }
 
     pub async fn get_all_secrets(
-        &mut self,
+        &self,
         req: secret_service::GetAllSecretsRequest,
-    ) -> Result<secret_service::GetAllSecretsResponse, tonic::Status> {
-        log!(LogLevel::Debug, "gRPC get_all_secrets");
-        Ok(self.client.get_all_secrets(req).await?.into_inner())
+    ) -> Result<secret_service::GetAllSecretsResponse, Status> {
+        self.call(
+            "get_all_secrets",
+            req,
+            self.retries,
+            |mut c, r| async move { c.get_all_secrets(r).await },
+        )
+        .await
     }
 
     pub async fn update_secret(
-        &mut self,
+        &self,
         req: secret_service::UpdateSecretRequest,
-    ) -> Result<secret_service::SimpleSecretResponse, tonic::Status> {
-        log!(LogLevel::Debug, "gRPC update_secret");
-        Ok(self.client.update_secret(req).await?.into_inner())
+    ) -> Result<secret_service::SimpleSecretResponse, Status> {
+        self.call("update_secret", req, 0, |mut c, r| async move {
+            c.update_secret(r).await
+        })
+        .await
     }
 
     pub async fn delete_secret(
-        &mut self,
+        &self,
         req: secret_service::DeleteSecretRequest,
-    ) -> Result<secret_service::SimpleSecretResponse, tonic::Status> {
-        log!(LogLevel::Debug, "gRPC delete_secret");
-        Ok(self.client.delete_secret(req).await?.into_inner())
+    ) -> Result<secret_service::SimpleSecretResponse, Status> {
+        self.call("delete_secret", req, 0, |mut c, r| async move {
+            c.delete_secret(r).await
+        })
+        .await
+    }
+
+    pub async fn list_secret_versions(
+        &self,
+        req: secret_service::ListSecretVersionsRequest,
+    ) -> Result<secret_service::ListSecretVersionsResponse, Status> {
+        self.call(
+            "list_secret_versions",
+            req,
+            self.retries,
+            |mut c, r| async move { c.list_secret_versions(r).await },
+        )
+        .await
     }
 }
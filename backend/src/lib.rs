@@ -0,0 +1,9 @@
+pub mod api;
+pub mod auth;
+pub mod config;
+pub mod database;
+pub mod grpc;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod state;
+pub mod updater;
@@ -1,22 +1,17 @@
-mod api;
-mod auth;
-mod database;
-mod grpc;
-
-use api::routes::create_api_routes;
+use artisan_dashboard::api::api_tokens::token_sessions;
+use artisan_dashboard::api::routes::create_api_routes;
 // use api::http::create_api_routes;
+use artisan_dashboard::config::AppConfig;
+use artisan_dashboard::database::connection::connect_db_pool;
+use artisan_dashboard::database::sessions::SessionStore;
+use artisan_dashboard::state::AppState;
+use artisan_dashboard::updater::spawn_session_refresh;
 use artisan_middleware::dusa_collection_utils::{
     core::logger::{LogLevel, set_log_level},
     log,
 };
-use database::connection::{get_db_pool, init_db_pool};
-mod state;
-mod updater;
-use api::cookie::load_active_sessions;
-use state::{get_state, init_state};
 use std::{error::Error, net::SocketAddr, time::Duration};
 use tokio::{self, signal, time::timeout};
-use updater::spawn_session_refresh;
 use warp::Filter;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 8)]
@@ -30,23 +25,37 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // —————————————————————
     // Initialize Database
     // —————————————————————
-    if let Err(e) = init_db_pool().await {
-        log!(LogLevel::Error, "FATAL INIT ERROR: {}", e);
-        std::process::exit(1);
-    }
+    let pool = match connect_db_pool().await {
+        Ok(pool) => pool,
+        Err(e) => {
+            log!(LogLevel::Error, "FATAL INIT ERROR: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    if let Err(e) = init_state().await {
-        log!(LogLevel::Error, "FATAL STATE INIT ERROR: {}", e);
-        std::process::exit(1);
-    }
+    let state = match AppConfig::from_env()
+        .and_then(|config| AppState::new(config, SessionStore::MySql(pool)))
+    {
+        Ok(state) => state,
+        Err(e) => {
+            log!(LogLevel::Error, "FATAL STATE INIT ERROR: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    match load_active_sessions(get_db_pool()).await {
+    match state.sessions.load_active().await {
         Ok(sessions) => {
             let count = sessions.len();
-            let cache = &get_state().session_cache;
+            // Access tokens keep their sessions alive until they expire.
+            let token_sessions = token_sessions(&state).await;
             for s in sessions {
-                cache.insert(s.session_id.clone(), s.clone()).await;
-                spawn_session_refresh(s);
+                state
+                    .session_cache
+                    .insert(s.session_id.clone(), s.clone())
+                    .await;
+                if !token_sessions.contains(&s.session_id) {
+                    spawn_session_refresh(state.clone(), s);
+                }
             }
             log!(LogLevel::Info, "prefilled {} session cache entries", count);
         }
@@ -63,7 +72,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let static_fs = warp::fs::dir(out_dir);
 
-    let api_routes = create_api_routes().await;
+    let api_routes = create_api_routes(state.clone()).await;
 
     let try_html_fallback = warp::path::full().and_then(move |full_path: warp::path::FullPath| {
         let out_dir = out_dir.to_string();
@@ -0,0 +1,15 @@
+//! In-process stand-ins for the services the dashboard talks to, for local
+//! development (`cargo run --features mock --bin mock_upstream`) and
+//! integration tests.
+//!
+//! Point the dashboard at them with `UPSTREAM_BASE_URL` and
+//! `SECRET_GRPC_ADDR`. The mock secret service ignores credentials, so also
+//! set `SECRET_GRPC_AUTH=none` or any `SECRET_GRPC_SIGNING_KEY`.
+
+pub mod secrets;
+pub mod upstream;
+
+pub use secrets::{MockSecretServer, MockSecretService};
+pub use upstream::{
+    MOCK_AUDIENCE, MOCK_ISSUER, MockFixtures, MockRunner, MockUpstream, MockUser, ScriptedResponse,
+};
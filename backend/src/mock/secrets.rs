@@ -0,0 +1,352 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use chrono::Utc;
+use tokio::{net::TcpListener, sync::oneshot};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Code, Request, Response, Status, transport::Server};
+
+use crate::grpc::secret_service::{
+    CreateSecretRequest, DeleteSecretRequest, GetAllSecretsRequest, GetAllSecretsResponse,
+    GetSecretRequest, GetSecretResponse, KeyValuePair, ListSecretVersionsRequest,
+    ListSecretVersionsResponse, SecretVersion, SimpleSecretResponse, UpdateSecretRequest,
+    secret_service_server::{SecretService, SecretServiceServer},
+};
+
+struct StoredVersion {
+    version: i64,
+    value: Vec<u8>,
+    created_at: i64,
+    actor: String,
+}
+
+/// `(runner_id, environment_id)` → key → versions, oldest first.
+type Store = HashMap<(String, String), BTreeMap<String, Vec<StoredVersion>>>;
+
+#[derive(Default)]
+struct Inner {
+    store: Mutex<Store>,
+    failures: Mutex<VecDeque<Code>>,
+    authorizations: Mutex<Vec<Option<String>>>,
+}
+
+/// An in-memory `SecretService`. Clones share the same store, so a test can
+/// keep one handle for assertions after handing another to the server.
+#[derive(Clone, Default)]
+pub struct MockSecretService {
+    inner: Arc<Inner>,
+}
+
+/// A running [`MockSecretService`]. Shuts down when dropped.
+pub struct MockSecretServer {
+    addr: SocketAddr,
+    service: MockSecretService,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockSecretServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// What `SECRET_GRPC_ADDR` should be set to.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn service(&self) -> &MockSecretService {
+        &self.service
+    }
+}
+
+impl Drop for MockSecretServer {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl MockSecretService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves on an ephemeral localhost port.
+    pub async fn start(self) -> std::io::Result<MockSecretServer> {
+        self.bind(([127, 0, 0, 1], 0).into()).await
+    }
+
+    pub async fn bind(self, addr: SocketAddr) -> std::io::Result<MockSecretServer> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = oneshot::channel();
+
+        let server = Server::builder()
+            .add_service(SecretServiceServer::new(self.clone()))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                rx.await.ok();
+            });
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                log!(LogLevel::Error, "mock secret service stopped: {}", e);
+            }
+        });
+        log!(LogLevel::Info, "mock secret service listening on {}", addr);
+
+        Ok(MockSecretServer {
+            addr,
+            service: self,
+            shutdown: Some(tx),
+        })
+    }
+
+    /// Makes the next call fail with `code`. Calls fail in the order queued.
+    pub fn fail_next(&self, code: Code) {
+        self.inner.failures.lock().unwrap().push_back(code);
+    }
+
+    /// Stores `value` as a new version of `key`, attributed to `seed`.
+    pub fn seed(&self, runner_id: &str, environment_id: &str, key: &str, value: impl AsRef<[u8]>) {
+        self.push_version(runner_id, environment_id, key, value.as_ref(), "seed");
+    }
+
+    /// The latest value of `key`, if it exists.
+    pub fn value(&self, runner_id: &str, environment_id: &str, key: &str) -> Option<Vec<u8>> {
+        self.inner
+            .store
+            .lock()
+            .unwrap()
+            .get(&env_key(runner_id, environment_id))
+            .and_then(|env| env.get(key))
+            .and_then(|versions| versions.last())
+            .map(|v| v.value.clone())
+    }
+
+    /// The `authorization` metadata of every call so far, in order.
+    pub fn authorizations(&self) -> Vec<Option<String>> {
+        self.inner.authorizations.lock().unwrap().clone()
+    }
+
+    fn push_version(
+        &self,
+        runner_id: &str,
+        environment_id: &str,
+        key: &str,
+        value: &[u8],
+        actor: &str,
+    ) {
+        let mut store = self.inner.store.lock().unwrap();
+        let versions = store
+            .entry(env_key(runner_id, environment_id))
+            .or_default()
+            .entry(key.to_owned())
+            .or_default();
+        versions.push(StoredVersion {
+            version: versions.last().map_or(1, |v| v.version + 1),
+            value: value.to_vec(),
+            created_at: Utc::now().timestamp(),
+            actor: actor.to_owned(),
+        });
+    }
+
+    /// Records the call's credentials and returns the queued failure, if
+    /// any.
+    fn begin<T>(&self, name: &str, request: &Request<T>) -> Option<Status> {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned);
+        log!(LogLevel::Debug, "mock secret service {}", name);
+        self.inner
+            .authorizations
+            .lock()
+            .unwrap()
+            .push(authorization);
+
+        self.inner
+            .failures
+            .lock()
+            .unwrap()
+            .pop_front()
+            .map(|code| Status::new(code, format!("scripted failure of {}", name)))
+    }
+}
+
+fn env_key(runner_id: &str, environment_id: &str) -> (String, String) {
+    (runner_id.to_owned(), environment_id.to_owned())
+}
+
+/// The newest version at or below `version`, or simply the newest when
+/// `version` is 0.
+fn at_version(versions: &[StoredVersion], version: i64) -> Option<&StoredVersion> {
+    versions
+        .iter()
+        .rev()
+        .find(|v| version == 0 || v.version <= version)
+}
+
+#[tonic::async_trait]
+impl SecretService for MockSecretService {
+    async fn create_secret(
+        &self,
+        request: Request<CreateSecretRequest>,
+    ) -> Result<Response<SimpleSecretResponse>, Status> {
+        if let Some(status) = self.begin("create_secret", &request) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        if self
+            .value(&req.runner_id, &req.environment_id, &req.secret_key)
+            .is_some()
+        {
+            return Err(Status::already_exists("secret already exists"));
+        }
+        self.push_version(
+            &req.runner_id,
+            &req.environment_id,
+            &req.secret_key,
+            &req.value,
+            &req.actor,
+        );
+        Ok(Response::new(SimpleSecretResponse { success: true }))
+    }
+
+    async fn get_secret(
+        &self,
+        request: Request<GetSecretRequest>,
+    ) -> Result<Response<GetSecretResponse>, Status> {
+        if let Some(status) = self.begin("get_secret", &request) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let store = self.inner.store.lock().unwrap();
+        let found = store
+            .get(&env_key(&req.runner_id, &req.environment_id))
+            .and_then(|env| env.get(&req.secret_key))
+            .and_then(|versions| match req.version {
+                0 => versions.last(),
+                n => versions.iter().find(|v| v.version == n),
+            });
+
+        match found {
+            Some(v) => Ok(Response::new(GetSecretResponse {
+                secret_key: req.secret_key,
+                value: v.value.clone(),
+                version: v.version,
+                updated_at: v.created_at,
+            })),
+            None => Err(Status::not_found("secret not found")),
+        }
+    }
+
+    async fn get_all_secrets(
+        &self,
+        request: Request<GetAllSecretsRequest>,
+    ) -> Result<Response<GetAllSecretsResponse>, Status> {
+        if let Some(status) = self.begin("get_all_secrets", &request) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let store = self.inner.store.lock().unwrap();
+        let vals = store
+            .get(&env_key(&req.runner_id, &req.environment_id))
+            .map(|env| {
+                env.iter()
+                    .filter_map(|(key, versions)| {
+                        at_version(versions, req.version).map(|v| KeyValuePair {
+                            key: key.clone(),
+                            value: match req.keys_only {
+                                true => Vec::new(),
+                                false => v.value.clone(),
+                            },
+                            version: v.version,
+                            updated_at: v.created_at,
+                            size: v.value.len() as i64,
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Response::new(GetAllSecretsResponse { vals }))
+    }
+
+    async fn update_secret(
+        &self,
+        request: Request<UpdateSecretRequest>,
+    ) -> Result<Response<SimpleSecretResponse>, Status> {
+        if let Some(status) = self.begin("update_secret", &request) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        if self
+            .value(&req.runner_id, &req.environment_id, &req.secret_key)
+            .is_none()
+        {
+            return Err(Status::not_found("secret not found"));
+        }
+        self.push_version(
+            &req.runner_id,
+            &req.environment_id,
+            &req.secret_key,
+            &req.new_value,
+            &req.actor,
+        );
+        Ok(Response::new(SimpleSecretResponse { success: true }))
+    }
+
+    async fn delete_secret(
+        &self,
+        request: Request<DeleteSecretRequest>,
+    ) -> Result<Response<SimpleSecretResponse>, Status> {
+        if let Some(status) = self.begin("delete_secret", &request) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let removed = self
+            .inner
+            .store
+            .lock()
+            .unwrap()
+            .get_mut(&env_key(&req.runner_id, &req.environment_id))
+            .and_then(|env| env.remove(&req.secret_key))
+            .is_some();
+        if !removed {
+            return Err(Status::not_found("secret not found"));
+        }
+        Ok(Response::new(SimpleSecretResponse { success: true }))
+    }
+
+    async fn list_secret_versions(
+        &self,
+        request: Request<ListSecretVersionsRequest>,
+    ) -> Result<Response<ListSecretVersionsResponse>, Status> {
+        if let Some(status) = self.begin("list_secret_versions", &request) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let store = self.inner.store.lock().unwrap();
+        let versions = store
+            .get(&env_key(&req.runner_id, &req.environment_id))
+            .and_then(|env| env.get(&req.secret_key))
+            .ok_or_else(|| Status::not_found("secret not found"))?;
+
+        Ok(Response::new(ListSecretVersionsResponse {
+            secret_key: req.secret_key,
+            versions: versions
+                .iter()
+                .rev()
+                .map(|v| SecretVersion {
+                    version: v.version,
+                    created_at: v.created_at,
+                    actor: v.actor.clone(),
+                })
+                .collect(),
+        }))
+    }
+}
@@ -0,0 +1,761 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use chrono::Utc;
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+use warp::{Filter, http::StatusCode, reply::Response};
+
+/// Key the mock signs its tokens with, published as an `oct` JWK at
+/// `.well-known/jwks.json`.
+const SIGNING_KEY: &[u8] = b"artisan-mock-upstream";
+const KEY_ID: &str = "mock-hs256";
+
+/// `iss` of every token the mock issues.
+pub const MOCK_ISSUER: &str = "artisan-mock-upstream";
+/// `aud` of every token the mock issues.
+pub const MOCK_AUDIENCE: &str = "artisan-dashboard";
+
+/// A management API account.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MockUser {
+    pub user_id: String,
+    pub email: String,
+    pub password: String,
+    #[serde(default = "default_role")]
+    pub role: String,
+    #[serde(default)]
+    pub runners: Vec<MockRunner>,
+    #[serde(default)]
+    pub vms: Vec<Value>,
+    #[serde(default)]
+    pub usage: Value,
+    #[serde(default)]
+    pub logs: Vec<Value>,
+    /// The one code `auth/login/mfa` accepts. Accounts without one log in
+    /// without a challenge.
+    #[serde(default)]
+    pub totp: Option<String>,
+}
+
+/// A runner as listed by `runners`, named by its bare id (the mock adds the
+/// `ais_` prefix itself).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MockRunner {
+    pub id: String,
+    #[serde(default = "default_status")]
+    pub status: String,
+    #[serde(default)]
+    pub instances: Vec<String>,
+}
+
+/// Everything the mock answers with. Loadable from JSON so the standalone
+/// binary can be pointed at a fixtures file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MockFixtures {
+    pub users: Vec<MockUser>,
+    /// Lifetime of access tokens issued by login. Zero or negative hands out
+    /// tokens that are already expired, to exercise the refresh path;
+    /// refreshed tokens then get the default lifetime.
+    #[serde(default = "default_auth_ttl")]
+    pub auth_ttl_secs: i64,
+    #[serde(default = "default_refresh_ttl")]
+    pub refresh_ttl_secs: i64,
+    /// Issue a new refresh token on every refresh and answer
+    /// `RefreshTokenReused` when a rotated-out one comes back.
+    #[serde(default)]
+    pub rotate_refresh_tokens: bool,
+}
+
+fn default_role() -> String {
+    "user".to_owned()
+}
+
+fn default_status() -> String {
+    "Running".to_owned()
+}
+
+fn default_auth_ttl() -> i64 {
+    900
+}
+
+fn default_refresh_ttl() -> i64 {
+    7 * 24 * 3600
+}
+
+impl Default for MockFixtures {
+    /// Two users, so tests can check one cannot reach the other's runners.
+    fn default() -> Self {
+        Self {
+            users: vec![
+                MockUser {
+                    user_id: "alice".to_owned(),
+                    email: "alice@example.com".to_owned(),
+                    password: "alice-password".to_owned(),
+                    role: default_role(),
+                    runners: vec![MockRunner {
+                        id: "web".to_owned(),
+                        status: default_status(),
+                        instances: vec!["web-1".to_owned(), "web-2".to_owned()],
+                    }],
+                    vms: vec![json!({ "vmid": 101, "name": "alice-vm", "status": "running" })],
+                    usage: json!({ "cpu_hours": 12.5, "bandwidth_gb": 3.2 }),
+                    logs: vec![json!({ "timestamp": "2025-01-01T00:00:00Z", "message": "booted" })],
+                    totp: None,
+                },
+                MockUser {
+                    user_id: "bob".to_owned(),
+                    email: "bob@example.com".to_owned(),
+                    password: "bob-password".to_owned(),
+                    role: default_role(),
+                    runners: vec![MockRunner {
+                        id: "api".to_owned(),
+                        status: default_status(),
+                        instances: vec!["api-1".to_owned()],
+                    }],
+                    vms: Vec::new(),
+                    usage: json!({}),
+                    logs: Vec::new(),
+                    totp: None,
+                },
+            ],
+            auth_ttl_secs: default_auth_ttl(),
+            refresh_ttl_secs: default_refresh_ttl(),
+            rotate_refresh_tokens: false,
+        }
+    }
+}
+
+/// A canned reply that takes precedence over the built-in behaviour.
+#[derive(Clone)]
+pub struct ScriptedResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    iss: String,
+    aud: String,
+    sub: String,
+    typ: String,
+    iat: i64,
+    exp: i64,
+    jti: String,
+}
+
+#[derive(Default)]
+struct Recorded {
+    hits: HashMap<String, usize>,
+    commands: Vec<(String, String)>,
+    /// `jti`s of refresh tokens that have been rotated out.
+    retired: HashSet<String>,
+    /// `jti`s of tokens revoked through `auth/revoke`.
+    revoked: HashSet<String>,
+    /// Outstanding password reset tokens and the user each one is for.
+    resets: HashMap<String, String>,
+    /// Open MFA challenges and the user each one is for.
+    challenges: HashMap<String, String>,
+}
+
+struct Inner {
+    fixtures: Mutex<MockFixtures>,
+    scripted: Mutex<HashMap<String, VecDeque<ScriptedResponse>>>,
+    recorded: Mutex<Recorded>,
+}
+
+/// A running mock of the management API. Shuts down when dropped.
+pub struct MockUpstream {
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockUpstream {
+    /// Serves `fixtures` on an ephemeral localhost port. Must be called from
+    /// within a tokio runtime.
+    pub fn start(fixtures: MockFixtures) -> Result<Self, warp::Error> {
+        Self::bind(([127, 0, 0, 1], 0).into(), fixtures)
+    }
+
+    pub fn bind(addr: SocketAddr, fixtures: MockFixtures) -> Result<Self, warp::Error> {
+        let inner = Arc::new(Inner {
+            fixtures: Mutex::new(fixtures),
+            scripted: Mutex::new(HashMap::new()),
+            recorded: Mutex::new(Recorded::default()),
+        });
+
+        let state = inner.clone();
+        let routes = warp::path("v1")
+            .and(warp::path::tail())
+            .and(warp::method())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::bytes())
+            .map(
+                move |tail: warp::path::Tail,
+                      method: warp::http::Method,
+                      query: String,
+                      auth: Option<String>,
+                      body: Bytes| {
+                    let (status, body) =
+                        state.dispatch(method.as_str(), tail.as_str(), &query, auth, &body);
+                    let mut resp = Response::new(body.to_string().into());
+                    *resp.status_mut() = status;
+                    resp.headers_mut().insert(
+                        "content-type",
+                        warp::http::HeaderValue::from_static("application/json"),
+                    );
+                    resp
+                },
+            );
+
+        let (tx, rx) = oneshot::channel();
+        let (addr, server) = warp::serve(routes).try_bind_with_graceful_shutdown(addr, async {
+            rx.await.ok();
+        })?;
+        tokio::spawn(server);
+        log!(LogLevel::Info, "mock upstream listening on {}", addr);
+
+        Ok(Self {
+            addr,
+            inner,
+            shutdown: Some(tx),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// What `UPSTREAM_BASE_URL` should be set to.
+    pub fn base_url(&self) -> String {
+        format!("http://{}/v1/", self.addr)
+    }
+
+    /// Where the mock publishes its signing key, for `UPSTREAM_JWKS_URL`.
+    pub fn jwks_url(&self) -> String {
+        format!("{}.well-known/jwks.json", self.base_url())
+    }
+
+    /// The JWKS document served at `jwks_url`.
+    pub fn jwks(&self) -> Value {
+        jwks_document()
+    }
+
+    /// Queues a reply for the next `method path` request, e.g.
+    /// `script("GET", "runners", ...)`. Queued replies are used up in order
+    /// before the built-in behaviour resumes.
+    pub fn script(&self, method: &str, path: &str, response: ScriptedResponse) {
+        self.inner
+            .scripted
+            .lock()
+            .unwrap()
+            .entry(route_key(method, path))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// How many times `method path` has been requested. `path` may end in
+    /// `?query` to count only requests with exactly that query string.
+    pub fn hits(&self, method: &str, path: &str) -> usize {
+        self.inner
+            .recorded
+            .lock()
+            .unwrap()
+            .hits
+            .get(&route_key(method, path))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Control commands accepted so far, as `(instance, command)`.
+    pub fn commands(&self) -> Vec<(String, String)> {
+        self.inner.recorded.lock().unwrap().commands.clone()
+    }
+
+    /// Changes the fixtures of the running server.
+    pub fn update_fixtures(&self, f: impl FnOnce(&mut MockFixtures)) {
+        f(&mut self.inner.fixtures.lock().unwrap());
+    }
+
+    /// The outstanding password reset token for `user_id`, as the reset
+    /// email would deliver it.
+    pub fn reset_token(&self, user_id: &str) -> Option<String> {
+        self.inner
+            .recorded
+            .lock()
+            .unwrap()
+            .resets
+            .iter()
+            .find(|(_, user)| *user == user_id)
+            .map(|(token, _)| token.clone())
+    }
+
+    /// Mints a token the way `auth/login` would. `typ` is `auth` or
+    /// `refresh`.
+    pub fn issue_token(&self, user_id: &str, typ: &str, ttl_secs: i64) -> String {
+        issue_token(user_id, typ, ttl_secs)
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+fn route_key(method: &str, path: &str) -> String {
+    format!("{} {}", method.to_uppercase(), path.trim_matches('/'))
+}
+
+/// What a successful login answers with.
+fn session_tokens(fixtures: &MockFixtures, user_id: &str) -> Value {
+    json!({
+        "auth": issue_token(user_id, "auth", fixtures.auth_ttl_secs),
+        "refresh": issue_token(user_id, "refresh", fixtures.refresh_ttl_secs),
+    })
+}
+
+fn issue_token(user_id: &str, typ: &str, ttl_secs: i64) -> String {
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        iss: MOCK_ISSUER.to_owned(),
+        aud: MOCK_AUDIENCE.to_owned(),
+        sub: user_id.to_owned(),
+        typ: typ.to_owned(),
+        iat: now,
+        exp: now + ttl_secs,
+        jti: Uuid::new_v4().to_string(),
+    };
+    let header = Header {
+        kid: Some(KEY_ID.to_owned()),
+        ..Header::default()
+    };
+    jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(SIGNING_KEY))
+        .expect("HS256 encoding cannot fail")
+}
+
+/// Checks signature and type. Expiry is only enforced when `check_exp`.
+fn verify_token(token: &str, typ: &str, check_exp: bool) -> Option<Claims> {
+    let mut validation = Validation::default();
+    validation.validate_exp = check_exp;
+    validation.leeway = 0;
+    validation.set_audience(&[MOCK_AUDIENCE]);
+    let data =
+        jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(SIGNING_KEY), &validation)
+            .ok()?;
+    (data.claims.typ == typ).then_some(data.claims)
+}
+
+fn jwks_document() -> Value {
+    json!({
+        "keys": [{
+            "kty": "oct",
+            "kid": KEY_ID,
+            "alg": "HS256",
+            "use": "sig",
+            "k": URL_SAFE_NO_PAD.encode(SIGNING_KEY),
+        }]
+    })
+}
+
+fn ok(data: Value) -> (StatusCode, Value) {
+    (
+        StatusCode::OK,
+        json!({ "status": "success", "data": data, "errors": [] }),
+    )
+}
+
+fn error(status: StatusCode, code: &str, message: &str) -> (StatusCode, Value) {
+    (
+        status,
+        json!({
+            "status": "error",
+            "data": null,
+            "errors": [{ "code": code, "message": message, "details": null }],
+        }),
+    )
+}
+
+/// An `error` naming the request field at fault in `details`.
+fn field_error(status: StatusCode, code: &str, field: &str, message: &str) -> (StatusCode, Value) {
+    let (status, mut body) = error(status, code, message);
+    body["errors"][0]["details"] = json!({ "field": field });
+    (status, body)
+}
+
+fn runner_summary(runner: &MockRunner) -> Value {
+    let version = json!({ "number": "1.0.0", "code": "Production" });
+    json!({
+        "name": format!("ais_{}", runner.id),
+        "status": runner.status,
+        "version": { "application": version, "library": version },
+        "nodes": [1],
+        "uptime": 3600,
+        "metrics": null,
+    })
+}
+
+impl Inner {
+    fn dispatch(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        auth: Option<String>,
+        body: &[u8],
+    ) -> (StatusCode, Value) {
+        let key = route_key(method, path);
+        log!(LogLevel::Debug, "mock upstream {} ?{}", key, query);
+        {
+            let hits = &mut self.recorded.lock().unwrap().hits;
+            *hits.entry(key.clone()).or_default() += 1;
+            if !query.is_empty() {
+                *hits.entry(format!("{}?{}", key, query)).or_default() += 1;
+            }
+        }
+
+        if let Some(scripted) = self
+            .scripted
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(|queue| queue.pop_front())
+        {
+            let status =
+                StatusCode::from_u16(scripted.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            return (status, scripted.body);
+        }
+
+        let body: Value = serde_json::from_slice(body).unwrap_or(Value::Null);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (method, segments.as_slice()) {
+            ("GET", [".well-known", "jwks.json"]) => return (StatusCode::OK, jwks_document()),
+            ("POST", ["auth", "login"]) => return self.login(&body),
+            ("POST", ["auth", "login", "mfa"]) => return self.login_mfa(&body),
+            ("POST", ["auth", "refresh"]) => return self.refresh(&body),
+            ("POST", ["auth", "password-reset", "request"]) => return self.reset_request(&body),
+            ("POST", ["auth", "password-reset", "confirm"]) => return self.reset_confirm(&body),
+            _ => {}
+        }
+
+        let claims = match auth
+            .as_deref()
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .and_then(|token| verify_token(token, "auth", true))
+            .filter(|claims| !self.is_revoked(&claims.jti))
+        {
+            Some(claims) => claims,
+            None => {
+                return error(
+                    StatusCode::UNAUTHORIZED,
+                    "NotAuthorized",
+                    "missing or expired token",
+                );
+            }
+        };
+
+        match (method, segments.as_slice()) {
+            ("PUT", ["account", "email"]) => return self.update_email(&claims.sub, &body),
+            ("PUT", ["account", "password"]) => return self.update_password(&claims.sub, &body),
+            _ => {}
+        }
+
+        let fixtures = self.fixtures.lock().unwrap();
+        let Some(user) = fixtures.users.iter().find(|u| u.user_id == claims.sub) else {
+            return error(StatusCode::UNAUTHORIZED, "NotAuthorized", "unknown user");
+        };
+
+        match (method, segments.as_slice()) {
+            ("POST", ["auth", "revoke"]) => self.revoke(&claims, &body),
+            ("GET", ["account", "me"]) => (
+                StatusCode::OK,
+                json!({ "user_id": user.user_id, "email": user.email }),
+            ),
+            ("POST", ["whoami"]) => (
+                StatusCode::OK,
+                json!({
+                    "you": {
+                        "user_id": user.user_id,
+                        "role": user.role,
+                        "expires": (claims.exp - Utc::now().timestamp()).max(0),
+                    }
+                }),
+            ),
+            ("GET", ["runners"]) => ok(user.runners.iter().map(runner_summary).collect()),
+            ("GET", ["runner", id]) => {
+                let id = id.strip_prefix("ais_").unwrap_or(id);
+                match user.runners.iter().find(|r| r.id == id) {
+                    Some(runner) => ok(runner
+                        .instances
+                        .iter()
+                        .map(|instance| json!({ "id": instance, "status": runner.status }))
+                        .collect()),
+                    None => error(StatusCode::NOT_FOUND, "RunnerNotFound", "no such runner"),
+                }
+            }
+            ("GET", ["vms"]) => ok(Value::Array(user.vms.clone())),
+            ("GET", ["usage"]) => ok(user.usage.clone()),
+            ("GET", ["logs"]) => ok(Value::Array(user.logs.clone())),
+            ("GET", ["control", identity, command]) => {
+                if !matches!(*command, "start" | "stop" | "restart") {
+                    return error(StatusCode::BAD_REQUEST, "Whoops", "unknown command");
+                }
+                let owned = user
+                    .runners
+                    .iter()
+                    .any(|r| r.instances.iter().any(|i| i == identity));
+                if !owned {
+                    return error(StatusCode::NOT_FOUND, "RunnerNotFound", "no such instance");
+                }
+                self.recorded
+                    .lock()
+                    .unwrap()
+                    .commands
+                    .push((identity.to_string(), command.to_string()));
+                let (_, body) = ok(json!({ "accepted": command }));
+                (StatusCode::ACCEPTED, body)
+            }
+            ("POST", ["billing", "calculate"]) => {
+                let field = |name: &str| body.get(name).and_then(Value::as_f64).unwrap_or(0.0);
+                let hours = body.get("hours").and_then(Value::as_f64).unwrap_or(730.0);
+                let total = (field("cpu_cores") * 0.01
+                    + field("memory_gb") * 0.005
+                    + field("disk_gb") * 0.0001)
+                    * hours;
+                ok(json!({ "hours": hours, "total": (total * 100.0).round() / 100.0 }))
+            }
+            _ => error(StatusCode::NOT_FOUND, "Whoops", "no such endpoint"),
+        }
+    }
+
+    fn login(&self, body: &Value) -> (StatusCode, Value) {
+        let email = body
+            .get("email")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let password = body
+            .get("password")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        let fixtures = self.fixtures.lock().unwrap();
+        match fixtures
+            .users
+            .iter()
+            .find(|u| u.email == email && u.password == password)
+        {
+            Some(user) if user.totp.is_some() => {
+                let challenge = Uuid::new_v4().to_string();
+                self.recorded
+                    .lock()
+                    .unwrap()
+                    .challenges
+                    .insert(challenge.clone(), user.user_id.clone());
+                (
+                    StatusCode::OK,
+                    json!({ "mfa_required": true, "challenge": challenge, "methods": ["totp"] }),
+                )
+            }
+            Some(user) => (StatusCode::OK, session_tokens(&fixtures, &user.user_id)),
+            None => error(
+                StatusCode::UNAUTHORIZED,
+                "InvalidCredentials",
+                "invalid email or password",
+            ),
+        }
+    }
+
+    /// Second step of a login that was answered with a challenge. The
+    /// challenge survives wrong codes and is used up by the right one.
+    fn login_mfa(&self, body: &Value) -> (StatusCode, Value) {
+        let challenge = body
+            .get("challenge")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let code = body.get("code").and_then(Value::as_str).unwrap_or_default();
+
+        let fixtures = self.fixtures.lock().unwrap();
+        let mut recorded = self.recorded.lock().unwrap();
+        let Some(user) = recorded
+            .challenges
+            .get(challenge)
+            .and_then(|user_id| fixtures.users.iter().find(|u| &u.user_id == user_id))
+        else {
+            return error(
+                StatusCode::UNAUTHORIZED,
+                "InvalidChallenge",
+                "unknown or used challenge",
+            );
+        };
+        if user.totp.as_deref() != Some(code) {
+            return error(StatusCode::UNAUTHORIZED, "InvalidCode", "wrong code");
+        }
+        recorded.challenges.remove(challenge);
+        (StatusCode::OK, session_tokens(&fixtures, &user.user_id))
+    }
+
+    fn refresh(&self, body: &Value) -> (StatusCode, Value) {
+        let expired = body
+            .get("expired_token")
+            .and_then(Value::as_str)
+            .and_then(|t| verify_token(t, "auth", false));
+        let refresh = body
+            .get("refresh_token")
+            .and_then(Value::as_str)
+            .and_then(|t| verify_token(t, "refresh", true))
+            .filter(|claims| !self.is_revoked(&claims.jti));
+
+        match (expired, refresh) {
+            (Some(expired), Some(refresh)) if expired.sub == refresh.sub => {
+                let fixtures = self.fixtures.lock().unwrap();
+                let ttl = match fixtures.auth_ttl_secs {
+                    ttl if ttl > 0 => ttl,
+                    _ => default_auth_ttl(),
+                };
+                let auth = issue_token(&refresh.sub, "auth", ttl);
+                if !fixtures.rotate_refresh_tokens {
+                    return (StatusCode::OK, json!({ "auth": auth }));
+                }
+
+                if !self.recorded.lock().unwrap().retired.insert(refresh.jti) {
+                    return error(
+                        StatusCode::UNAUTHORIZED,
+                        "RefreshTokenReused",
+                        "refresh token already used",
+                    );
+                }
+                (
+                    StatusCode::OK,
+                    json!({
+                        "auth": auth,
+                        "refresh": issue_token(&refresh.sub, "refresh", fixtures.refresh_ttl_secs),
+                    }),
+                )
+            }
+            _ => error(
+                StatusCode::UNAUTHORIZED,
+                "InvalidCredentials",
+                "refresh rejected",
+            ),
+        }
+    }
+
+    /// Revokes the presenting access token and, if it belongs to the same
+    /// user, the refresh token in the body.
+    fn revoke(&self, claims: &Claims, body: &Value) -> (StatusCode, Value) {
+        let refresh = body
+            .get("refresh_token")
+            .and_then(Value::as_str)
+            .and_then(|t| verify_token(t, "refresh", false));
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.revoked.insert(claims.jti.clone());
+        match refresh {
+            Some(refresh) if refresh.sub == claims.sub => {
+                recorded.revoked.insert(refresh.jti);
+                (StatusCode::OK, json!({ "revoked": true }))
+            }
+            _ => error(
+                StatusCode::BAD_REQUEST,
+                "InvalidRefreshToken",
+                "refresh token missing or not yours",
+            ),
+        }
+    }
+
+    fn update_email(&self, user_id: &str, body: &Value) -> (StatusCode, Value) {
+        let email = body
+            .get("email")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let mut fixtures = self.fixtures.lock().unwrap();
+        if fixtures
+            .users
+            .iter()
+            .any(|u| u.email == email && u.user_id != user_id)
+        {
+            return field_error(StatusCode::CONFLICT, "EmailTaken", "email", "email in use");
+        }
+        match fixtures.users.iter_mut().find(|u| u.user_id == user_id) {
+            Some(user) => {
+                user.email = email.to_owned();
+                ok(json!({ "email": email }))
+            }
+            None => error(StatusCode::UNAUTHORIZED, "NotAuthorized", "unknown user"),
+        }
+    }
+
+    fn update_password(&self, user_id: &str, body: &Value) -> (StatusCode, Value) {
+        let field = |name: &str| body.get(name).and_then(Value::as_str).unwrap_or_default();
+        let mut fixtures = self.fixtures.lock().unwrap();
+        let Some(user) = fixtures.users.iter_mut().find(|u| u.user_id == user_id) else {
+            return error(StatusCode::UNAUTHORIZED, "NotAuthorized", "unknown user");
+        };
+        if user.password != field("current_password") {
+            return field_error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "WrongPassword",
+                "current_password",
+                "current password is wrong",
+            );
+        }
+        user.password = field("password").to_owned();
+        ok(json!({ "updated": true }))
+    }
+
+    /// Issues a reset token, which a real upstream would email. Tests read
+    /// it back with `MockUpstream::reset_token`.
+    fn reset_request(&self, body: &Value) -> (StatusCode, Value) {
+        let email = body
+            .get("email")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let fixtures = self.fixtures.lock().unwrap();
+        let Some(user) = fixtures.users.iter().find(|u| u.email == email) else {
+            return error(StatusCode::NOT_FOUND, "UserNotFound", "no such account");
+        };
+        self.recorded
+            .lock()
+            .unwrap()
+            .resets
+            .insert(Uuid::new_v4().to_string(), user.user_id.clone());
+        ok(json!({ "sent": true }))
+    }
+
+    fn reset_confirm(&self, body: &Value) -> (StatusCode, Value) {
+        let field = |name: &str| body.get(name).and_then(Value::as_str).unwrap_or_default();
+        let Some(user_id) = self.recorded.lock().unwrap().resets.remove(field("token")) else {
+            return field_error(
+                StatusCode::BAD_REQUEST,
+                "InvalidResetToken",
+                "token",
+                "unknown or used reset token",
+            );
+        };
+        let mut fixtures = self.fixtures.lock().unwrap();
+        match fixtures.users.iter_mut().find(|u| u.user_id == user_id) {
+            Some(user) => {
+                user.password = field("password").to_owned();
+                ok(json!({ "user_id": user_id }))
+            }
+            None => error(StatusCode::NOT_FOUND, "UserNotFound", "no such account"),
+        }
+    }
+
+    fn is_revoked(&self, jti: &str) -> bool {
+        self.recorded.lock().unwrap().revoked.contains(jti)
+    }
+}
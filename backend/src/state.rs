@@ -1,41 +1,137 @@
-use once_cell::sync::OnceCell;
+use std::{convert::Infallible, sync::Arc};
+
 use reqwest::Client;
+use warp::Filter;
 
 use crate::{
-    api::cache::{Cache, SessionCache},
+    api::{
+        api_tokens::TokenUses,
+        cache::{Cache, OwnershipCache, SessionCache},
+        csrf::CsrfKey,
+        rate_limit::RateLimiter,
+        session_activity::SessionActivity,
+        session_cookie::SessionRotation,
+    },
+    auth::{
+        login_limit::LoginLimiter, mfa::PendingLogins, revoke::RevokedTokens, token::RefreshLocks,
+        verify::TokenVerifier,
+    },
+    config::AppConfig,
+    database::{api_tokens::ApiTokenStore, login_attempts::AttemptStore, sessions::SessionStore},
     grpc, // for SecretClient
+    updater::spawn_activity_flush,
 };
 use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
 
 pub struct AppState {
+    pub config: AppConfig,
     pub proxy_cache: Cache,
     pub session_cache: SessionCache,
+    pub refresh_locks: RefreshLocks,
+    pub session_rotation: SessionRotation,
+    pub session_activity: SessionActivity,
+    pub revoked_tokens: RevokedTokens,
+    pub csrf: CsrfKey,
+    pub login_limiter: LoginLimiter,
+    pub pending_logins: PendingLogins,
+    pub rate_limiter: RateLimiter,
+    pub ownership_cache: OwnershipCache,
+    pub sessions: SessionStore,
+    pub api_tokens: ApiTokenStore,
+    pub token_uses: TokenUses,
     pub http_client: Client,
-    pub secret_client: grpc::SecretClient,
+    /// `None` when the secret service isn't configured; secret routes then
+    /// answer 503 while everything else keeps working.
+    pub secret_client: Option<grpc::SecretClient>,
+    /// `None` when upstream tokens are taken on trust.
+    pub token_verifier: Option<TokenVerifier>,
 }
 
-static APP_STATE: OnceCell<AppState> = OnceCell::new();
+/// What handlers and background tasks hold on to.
+pub type SharedState = Arc<AppState>;
+
+impl AppState {
+    /// Must run inside the tokio runtime that will serve requests, since the
+    /// secret client's channel is bound to it.
+    pub fn new(
+        config: AppConfig,
+        sessions: SessionStore,
+    ) -> Result<SharedState, Box<dyn std::error::Error>> {
+        let secret_client = match &config.secret_grpc.addr {
+            Some(addr) => match grpc::SecretClient::connect_lazy(addr, &config.secret_grpc) {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    log!(
+                        LogLevel::Error,
+                        "secret gRPC misconfigured, secrets disabled: {}",
+                        e
+                    );
+                    None
+                }
+            },
+            None => {
+                log!(LogLevel::Warn, "SECRET_GRPC_ADDR not set, secrets disabled");
+                None
+            }
+        };
 
-pub async fn init_state() -> Result<(), Box<dyn std::error::Error>> {
-    let secret_addr = std::env::var("SECRET_GRPC_ADDR").expect("SECRET_GRPC_ADDR must be set");
-    log!(LogLevel::Info, "connecting to secret gRPC {}", &secret_addr);
-    let secret_client = grpc::SecretClient::connect(secret_addr).await?;
+        let csrf = CsrfKey::new(config.csrf_secret.as_deref())?;
+        let attempts = match (&sessions, config.login_limit.persist) {
+            (SessionStore::MySql(pool), true) => AttemptStore::MySql(pool.clone()),
+            (_, true) => {
+                log!(
+                    LogLevel::Warn,
+                    "LOGIN_LIMIT_PERSIST needs the MySQL session store, counting in memory"
+                );
+                AttemptStore::memory()
+            }
+            (_, false) => AttemptStore::memory(),
+        };
+        let api_tokens = match &sessions {
+            SessionStore::MySql(pool) => ApiTokenStore::MySql(pool.clone()),
+            _ => ApiTokenStore::memory(),
+        };
+        let login_limiter = LoginLimiter::new(config.login_limit.clone(), attempts);
+        let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+        let http_client = Client::new();
+        let token_verifier = TokenVerifier::new(&config.token_verify, http_client.clone())?;
 
-    let state = AppState {
-        proxy_cache: Cache::new(),
-        session_cache: SessionCache::new(),
-        http_client: Client::new(),
-        secret_client,
-    };
+        let state = AppState {
+            config,
+            proxy_cache: Cache::new(),
+            session_cache: SessionCache::new(),
+            refresh_locks: RefreshLocks::default(),
+            session_rotation: SessionRotation::new(),
+            session_activity: SessionActivity::new(),
+            revoked_tokens: RevokedTokens::new(),
+            csrf,
+            login_limiter,
+            pending_logins: PendingLogins::new(),
+            rate_limiter,
+            ownership_cache: OwnershipCache::new(),
+            sessions,
+            api_tokens,
+            token_uses: TokenUses::new(),
+            http_client,
+            secret_client,
+            token_verifier,
+        };
 
-    APP_STATE.set(state).map_err(|_| {
-        std::io::Error::new(std::io::ErrorKind::Other, "AppState already initialized")
-    })?;
+        log!(LogLevel::Info, "app state initialized");
+        let state = Arc::new(state);
+        spawn_activity_flush(&state);
+        Ok(state)
+    }
 
-    log!(LogLevel::Info, "app state initialized");
-    Ok(())
+    /// `path` resolved against the management API root.
+    pub fn upstream_url(&self, path: &str) -> String {
+        format!("{}{}", self.config.upstream_base_url, path)
+    }
 }
 
-pub fn get_state() -> &'static AppState {
-    APP_STATE.get().expect("AppState not initialized")
+/// Hands every request its own reference to `state`.
+pub fn with_state(
+    state: SharedState,
+) -> impl Filter<Extract = (SharedState,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
 }
@@ -1,70 +1,92 @@
-use crate::api::{cache::CachedResponse, cookie::SessionData, helper::get_base_url};
+use crate::api::{
+    cookie::SessionData,
+    session_activity::{ActivityError, expire_session},
+};
 use crate::auth::token::get_token;
-use crate::state::get_state;
+use crate::state::{AppState, SharedState};
 use artisan_middleware::dusa_collection_utils::{core::logger::LogLevel, log};
-use chrono::Utc;
-use std::time::{Duration, Instant};
+use std::{sync::Arc, time::Duration};
 use tokio::time::sleep;
 
-async fn refresh_endpoint(path: &str, token: &str) {
-    let url = format!("{}{}", get_base_url(), path);
-    let client = get_state().http_client.clone();
-    match client.get(&url).bearer_auth(token).send().await {
-        Ok(resp) => {
-            let status = resp.status();
-            let headers = resp.headers().clone();
-            match resp.bytes().await {
-                Ok(body) => {
-                    let content_type = headers
-                        .get("content-type")
-                        .and_then(|h| h.to_str().ok())
-                        .unwrap_or("application/json")
-                        .to_string();
-                    get_state()
-                        .proxy_cache
-                        .insert(
-                            path.to_string(),
-                            CachedResponse {
-                                status: status.as_u16(),
-                                content_type,
-                                body: body.to_vec(),
-                                inserted: Instant::now(),
-                            },
-                        )
-                        .await;
-                }
-                Err(e) => log!(LogLevel::Warn, "failed to read {} body: {}", path, e),
+/// Keeps the session's tokens fresh until it ends or the state is dropped.
+/// The session is re-read every round, since refreshes may rotate its
+/// tokens and extend its lifetime, and logout or a refused refresh removes
+/// it.
+pub fn spawn_session_refresh(state: SharedState, session: SessionData) {
+    let state = Arc::downgrade(&state);
+    tokio::spawn(async move {
+        let session_id = session.session_id;
+        loop {
+            let Some(state) = state.upgrade() else {
+                break;
+            };
+            if !refresh_session(&state, &session_id).await {
+                break;
             }
+            drop(state);
+            sleep(Duration::from_secs(30)).await;
+        }
+    });
+}
+
+/// One round of `spawn_session_refresh`; `false` once the session is over.
+async fn refresh_session(state: &AppState, session_id: &str) -> bool {
+    let session = match state.sessions.lookup(session_id).await {
+        Ok(session) => session,
+        Err(_) => {
+            log!(
+                LogLevel::Info,
+                "session {} ended, stopping refresh",
+                session_id
+            );
+            state.session_cache.remove(session_id).await;
+            return false;
+        }
+    };
+
+    // Refreshing doesn't count as use; an abandoned session still runs into
+    // its idle timeout.
+    match state.session_activity.check(state, session_id, false).await {
+        Ok(_) => {}
+        Err(ActivityError::Expired(reason)) => {
+            expire_session(state, &session, reason).await;
+            return false;
+        }
+        // Tokens of a session that can't be checked aren't kept fresh; the
+        // next round tries again.
+        Err(ActivityError::Unreadable(e)) => {
+            log!(
+                LogLevel::Warn,
+                "skipping refresh, activity unreadable: {}",
+                e
+            );
+            return true;
         }
-        Err(e) => log!(LogLevel::Warn, "refresh {} failed: {}", path, e),
     }
+
+    if get_token(state, session.clone()).await.is_err() {
+        log!(
+            LogLevel::Warn,
+            "failed to get token for {}",
+            session.session_id
+        );
+    }
+    true
 }
 
-pub fn spawn_session_refresh(session: SessionData) {
+/// Writes recorded session activity and API token uses to the store every
+/// `activity_flush`, until the state is dropped.
+pub fn spawn_activity_flush(state: &SharedState) {
+    let every = state.config.activity_flush;
+    let state = Arc::downgrade(state);
     tokio::spawn(async move {
         loop {
-            if session.expires_at <= Utc::now() {
-                log!(
-                    LogLevel::Info,
-                    "session {} expired, stopping refresh",
-                    session.session_id
-                );
-                get_state().session_cache.remove(&session.session_id).await;
+            sleep(every).await;
+            let Some(state) = state.upgrade() else {
                 break;
-            }
-
-            if let Ok(token) = get_token(session.clone()).await {
-                refresh_endpoint("vms", &token).await;
-                refresh_endpoint("apps", &token).await;
-            } else {
-                log!(
-                    LogLevel::Warn,
-                    "failed to get token for {}",
-                    session.session_id
-                );
-            }
-
-            sleep(Duration::from_secs(30)).await;
+            };
+            state.session_activity.flush(&state).await;
+            state.token_uses.flush(&state).await;
         }
     });
 }
@@ -0,0 +1,178 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{Browser, TestEnv};
+use reqwest::{Method, Response, StatusCode};
+use serde_json::{Value, json};
+
+async fn put(browser: &Browser, path: &str, body: Value) -> Response {
+    browser
+        .request(Method::PUT, path)
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn first_field_error(resp: Response) -> (String, String) {
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = resp.json().await.unwrap();
+    let field = &body["fields"][0];
+    (
+        field["field"].as_str().unwrap().to_owned(),
+        field["code"].as_str().unwrap().to_owned(),
+    )
+}
+
+#[tokio::test]
+async fn password_change_ends_the_other_sessions() {
+    let env = TestEnv::start().await;
+    let laptop = env.logged_in("alice").await;
+    let phone = env.logged_in("alice").await;
+
+    let resp = put(
+        &laptop,
+        "account/email",
+        json!({ "email": "bob@example.com" }),
+    )
+    .await;
+    assert_eq!(
+        first_field_error(resp).await,
+        ("email".to_owned(), "conflict".to_owned())
+    );
+
+    let resp = put(
+        &laptop,
+        "account/password",
+        json!({ "current_password": "alice-password", "password": "short" }),
+    )
+    .await;
+    assert_eq!(
+        first_field_error(resp).await,
+        ("password".to_owned(), "too_short".to_owned())
+    );
+    let resp = put(
+        &laptop,
+        "account/password",
+        json!({ "current_password": "guess", "password": "a-new-long-password" }),
+    )
+    .await;
+    assert_eq!(
+        first_field_error(resp).await,
+        ("current_password".to_owned(), "rejected".to_owned())
+    );
+
+    let resp = put(
+        &laptop,
+        "account/password",
+        json!({ "current_password": "alice-password", "password": "a-new-long-password" }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["sessions_ended"], 1);
+    assert_eq!(laptop.get("auth/whoami").await.status(), StatusCode::OK);
+    assert_eq!(
+        phone.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    let mut browser = env.browser();
+    let resp = browser
+        .login("alice@example.com", "a-new-long-password")
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn password_resets_do_not_reveal_accounts_and_end_sessions() {
+    let env = TestEnv::start().await;
+    let bob = env.logged_in("bob").await;
+    let client = reqwest::Client::new();
+    let post = |path: &str, body: Value| {
+        client
+            .post(format!("{}{}", env.base, path))
+            .json(&body)
+            .send()
+    };
+
+    for email in ["nobody@example.com", "bob@example.com"] {
+        let resp = post("auth/password-reset/request", json!({ "email": email }))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    }
+    let token = env.upstream.reset_token("bob").expect("reset token");
+
+    let confirm = json!({ "token": token, "password": "bobs-new-password" });
+    let resp = post("auth/password-reset/confirm", confirm.clone())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        bob.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let resp = env
+        .browser()
+        .login("bob@example.com", "bobs-new-password")
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Tokens work once.
+    let resp = post("auth/password-reset/confirm", confirm).await.unwrap();
+    assert_eq!(
+        first_field_error(resp).await,
+        ("token".to_owned(), "rejected".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn password_resets_are_limited_apart_from_logins() {
+    let env = TestEnv::start_with(|config, _| {
+        let limits = &mut config.login_limit;
+        limits.email.free = 1;
+        limits.ip.free = 3;
+        limits.backoff = Duration::from_secs(60);
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let post = |path: &str, body: Value| {
+        client
+            .post(format!("{}{}", env.base, path))
+            .json(&body)
+            .send()
+    };
+
+    let request = json!({ "email": "carol@example.com" });
+    for expected in [
+        StatusCode::ACCEPTED,
+        StatusCode::ACCEPTED,
+        StatusCode::TOO_MANY_REQUESTS,
+    ] {
+        let resp = post("auth/password-reset/request", request.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), expected);
+    }
+
+    // Guessed tokens count against the address, on top of the two requests.
+    let guess = json!({ "token": "not-a-token", "password": "a-long-new-password" });
+    for expected in [
+        StatusCode::UNPROCESSABLE_ENTITY,
+        StatusCode::UNPROCESSABLE_ENTITY,
+        StatusCode::TOO_MANY_REQUESTS,
+    ] {
+        let resp = post("auth/password-reset/confirm", guess.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), expected);
+    }
+
+    let resp = env
+        .browser()
+        .login("carol@example.com", "carol-password")
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
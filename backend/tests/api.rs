@@ -0,0 +1,533 @@
+mod common;
+
+use artisan_dashboard::api::cookie::SessionData;
+use chrono::{Duration, Utc};
+use common::{TestEnv, session_cookie};
+use reqwest::{Method, StatusCode, header::SET_COOKIE};
+use serde_json::{Value, json};
+use tonic::Code;
+
+#[tokio::test]
+async fn login_sets_session_cookie_and_whoami_resolves_user() {
+    let env = TestEnv::start().await;
+    let mut browser = env.browser();
+    let rejected = browser.login("alice@example.com", "nope").await;
+    assert!(!rejected.status().is_success());
+    assert!(browser.session_id.is_none());
+
+    let resp = browser.login("alice@example.com", "alice-password").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let cookie = resp.headers()[SET_COOKIE].to_str().unwrap().to_owned();
+    assert!(cookie.contains("HttpOnly"), "{}", cookie);
+    assert!(browser.session_id.is_some());
+
+    let whoami: Value = browser.get("auth/whoami").await.json().await.unwrap();
+    assert_eq!(whoami["user_id"], "alice");
+    assert!(whoami["expires"].as_u64().unwrap() > 0);
+
+    let me: Value = browser.get("auth/me").await.json().await.unwrap();
+    assert_eq!(me["email"], "alice@example.com");
+}
+
+#[tokio::test]
+async fn dropped_environments_free_their_state() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("alice").await;
+    let state = std::sync::Arc::downgrade(&env.state);
+    drop(browser);
+    drop(env);
+
+    // The session's refresh loop mustn't keep the state alive.
+    for _ in 0..50 {
+        if state.upgrade().is_none() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("state still referenced after the environment was dropped");
+}
+
+#[tokio::test]
+async fn unknown_session_is_unauthorized() {
+    let env = TestEnv::start().await;
+    let browser = env.browser_with_session("not-a-session");
+    let resp = browser.get("auth/whoami").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body["error"].is_string());
+}
+
+#[tokio::test]
+async fn proxy_forwards_with_the_session_token() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("alice").await;
+    let resp = browser.get("proxy/vms").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["data"][0]["name"], "alice-vm");
+
+    let runners: Value = browser.get("runners").await.json().await.unwrap();
+    assert_eq!(runners["data"][0]["name"], "ais_web");
+}
+
+#[tokio::test]
+async fn control_commands_require_instance_ownership() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("alice").await;
+
+    let accepted = browser.get("proxy/control/web-1/restart").await;
+    assert_eq!(accepted.status(), StatusCode::ACCEPTED);
+    assert!(
+        env.upstream
+            .commands()
+            .contains(&("web-1".to_owned(), "restart".to_owned()))
+    );
+
+    let denied = browser.get("proxy/control/api-1/stop").await;
+    assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+    assert!(
+        !env.upstream
+            .commands()
+            .iter()
+            .any(|(instance, _)| instance == "api-1")
+    );
+}
+
+#[tokio::test]
+async fn dot_segments_cannot_reach_other_instances() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("alice").await;
+
+    for path in [
+        "proxy/vms/../control/api-1/stop",
+        "proxy/vms/%2e%2E/control/api-1/stop",
+        "proxy/vms/.%2e/control/api-1/stop",
+        "proxy/vms\\..\\control/api-1/stop",
+    ] {
+        assert_eq!(browser.raw_get(path).await, 400, "{}", path);
+    }
+    // An encoded segment is checked as the upstream decodes it.
+    assert_eq!(browser.raw_get("proxy/%63ontrol/api-1/stop").await, 403);
+    assert!(env.upstream.commands().is_empty());
+    assert_eq!(browser.raw_get("proxy/vms").await, 200);
+}
+
+#[tokio::test]
+async fn logout_ends_only_that_session() {
+    let env = TestEnv::start().await;
+    let first = env.logged_in("bob").await;
+    let second = env.logged_in("bob").await;
+
+    let resp = first.post("auth/logout").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(session_cookie(&resp).as_deref(), Some(""));
+
+    assert_eq!(
+        first.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(second.get("auth/whoami").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logout_all_ends_every_session_of_the_user() {
+    let env = TestEnv::start().await;
+    let first = env.logged_in("carol").await;
+    let second = env.logged_in("carol").await;
+
+    assert_eq!(first.post("auth/logout_all").await.status(), StatusCode::OK);
+
+    assert_eq!(
+        first.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        second.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn expired_access_token_is_refreshed() {
+    let env = TestEnv::start().await;
+    let upstream = &env.upstream;
+    let expired = upstream.issue_token("dave", "auth", -60);
+    let session = SessionData {
+        session_id: "e2e-dave-expired".to_owned(),
+        user_id: "dave".to_owned(),
+        auth_jwt: expired.clone(),
+        refresh_jwt: upstream.issue_token("dave", "refresh", 3600),
+        expires_at: Utc::now() + Duration::hours(1),
+    };
+    env.state.sessions.insert(&session).await.unwrap();
+
+    let refreshes_before = upstream.hits("POST", "auth/refresh");
+    let browser = env.browser_with_session(&session.session_id);
+    let me = browser.get("auth/me").await;
+    assert_eq!(me.status(), StatusCode::OK);
+    let me: Value = me.json().await.unwrap();
+    assert_eq!(me["user_id"], "dave");
+
+    assert!(upstream.hits("POST", "auth/refresh") > refreshes_before);
+    let stored = env
+        .state
+        .sessions
+        .lookup(&session.session_id)
+        .await
+        .unwrap();
+    assert_ne!(stored.auth_jwt, expired);
+}
+
+#[tokio::test]
+async fn tokens_near_expiry_are_refreshed_ahead_of_time() {
+    let env = TestEnv::start().await;
+    let upstream = &env.upstream;
+    let expiring = upstream.issue_token("frank", "auth", 20);
+    let session = SessionData {
+        session_id: "e2e-frank-expiring".to_owned(),
+        user_id: "frank".to_owned(),
+        auth_jwt: expiring.clone(),
+        refresh_jwt: upstream.issue_token("frank", "refresh", 3600),
+        expires_at: Utc::now() + Duration::hours(1),
+    };
+    env.state.sessions.insert(&session).await.unwrap();
+
+    let browser = env.browser_with_session(&session.session_id);
+    assert_eq!(browser.get("auth/me").await.status(), StatusCode::OK);
+    assert_eq!(upstream.hits("POST", "auth/refresh"), 1);
+
+    // The cached session carries the new token too.
+    let cached = env
+        .state
+        .session_cache
+        .get(&session.session_id, std::time::Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_ne!(cached.auth_jwt, expiring);
+
+    assert_eq!(browser.get("auth/me").await.status(), StatusCode::OK);
+    assert_eq!(upstream.hits("POST", "auth/refresh"), 1);
+}
+
+#[tokio::test]
+async fn concurrent_requests_share_one_refresh() {
+    let env = TestEnv::start().await;
+    let upstream = &env.upstream;
+    let session = SessionData {
+        session_id: "e2e-carol-concurrent".to_owned(),
+        user_id: "carol".to_owned(),
+        auth_jwt: upstream.issue_token("carol", "auth", -60),
+        refresh_jwt: upstream.issue_token("carol", "refresh", 3600),
+        expires_at: Utc::now() + Duration::hours(1),
+    };
+    env.state.sessions.insert(&session).await.unwrap();
+
+    let browser = env.browser_with_session(&session.session_id);
+    let (a, b, c, d) = tokio::join!(
+        browser.get("auth/me"),
+        browser.get("auth/me"),
+        browser.get("auth/me"),
+        browser.get("auth/me"),
+    );
+    for resp in [a, b, c, d] {
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    assert_eq!(upstream.hits("POST", "auth/refresh"), 1);
+}
+
+#[tokio::test]
+async fn rejected_refresh_ends_the_session() {
+    let env = TestEnv::start().await;
+    let upstream = &env.upstream;
+    let session = SessionData {
+        session_id: "e2e-grace-revoked".to_owned(),
+        user_id: "grace".to_owned(),
+        auth_jwt: upstream.issue_token("grace", "auth", -60),
+        refresh_jwt: upstream.issue_token("grace", "refresh", -60),
+        expires_at: Utc::now() + Duration::hours(1),
+    };
+    env.state.sessions.insert(&session).await.unwrap();
+
+    let browser = env.browser_with_session(&session.session_id);
+    let resp = browser.get("auth/me").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(
+        env.state
+            .sessions
+            .lookup(&session.session_id)
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        browser.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+fn expired_session(env: &TestEnv, id: &str, user_id: &str, refresh_jwt: String) -> SessionData {
+    SessionData {
+        session_id: id.to_owned(),
+        user_id: user_id.to_owned(),
+        auth_jwt: env.upstream.issue_token(user_id, "auth", -60),
+        refresh_jwt,
+        expires_at: Utc::now() + Duration::hours(1),
+    }
+}
+
+#[tokio::test]
+async fn rotated_refresh_tokens_are_persisted() {
+    let env = TestEnv::start().await;
+    env.upstream
+        .update_fixtures(|f| f.rotate_refresh_tokens = true);
+    let original = env.upstream.issue_token("dave", "refresh", 600);
+    let session = expired_session(&env, "e2e-dave-rotate", "dave", original.clone());
+    env.state.sessions.insert(&session).await.unwrap();
+
+    let browser = env.browser_with_session(&session.session_id);
+    assert_eq!(browser.get("auth/me").await.status(), StatusCode::OK);
+
+    let stored = env
+        .state
+        .sessions
+        .lookup(&session.session_id)
+        .await
+        .unwrap();
+    assert_ne!(stored.refresh_jwt, original);
+    assert!(stored.expires_at > session.expires_at);
+}
+
+#[tokio::test]
+async fn refresh_token_reuse_ends_every_session_of_the_user() {
+    let env = TestEnv::start().await;
+    env.upstream
+        .update_fixtures(|f| f.rotate_refresh_tokens = true);
+    let original = env.upstream.issue_token("carol", "refresh", 600);
+    let first = expired_session(&env, "e2e-carol-first", "carol", original.clone());
+    env.state.sessions.insert(&first).await.unwrap();
+    let first_browser = env.browser_with_session(&first.session_id);
+    assert_eq!(first_browser.get("auth/me").await.status(), StatusCode::OK);
+
+    // Another session turns up holding the refresh token just rotated out.
+    let replay = expired_session(&env, "e2e-carol-replay", "carol", original);
+    env.state.sessions.insert(&replay).await.unwrap();
+    let replay_browser = env.browser_with_session(&replay.session_id);
+    assert_eq!(
+        replay_browser.get("auth/me").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    for id in [&first.session_id, &replay.session_id] {
+        assert!(env.state.sessions.lookup(id).await.is_err());
+    }
+    assert_eq!(
+        first_browser.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn proxy_serves_repeat_gets_from_cache() {
+    let env = TestEnv::start().await;
+    let upstream = &env.upstream;
+    let browser = env.logged_in("frank").await;
+
+    let first = browser.get("proxy/usage?probe=e2e-cache").await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let first: Value = first.json().await.unwrap();
+
+    let second: Value = browser
+        .get("proxy/usage?probe=e2e-cache")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(first, second);
+    assert_eq!(upstream.hits("GET", "usage?probe=e2e-cache"), 1);
+
+    browser.get("proxy/usage?probe=e2e-cache-2").await;
+    assert_eq!(upstream.hits("GET", "usage?probe=e2e-cache-2"), 1);
+
+    // Another user gets their own reply, not frank's cached one.
+    let alice: Value = env
+        .logged_in("alice")
+        .await
+        .get("proxy/usage?probe=e2e-cache")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_ne!(alice, first);
+    assert_eq!(alice["data"]["cpu_hours"], 12.5);
+    assert_eq!(upstream.hits("GET", "usage?probe=e2e-cache"), 2);
+}
+
+#[tokio::test]
+async fn secret_crud_round_trip() {
+    let env = TestEnv::start_with(|config, _| {
+        config
+            .secret_policy
+            .env_key_limits
+            .insert("e2e".to_owned(), 1);
+    })
+    .await;
+    let secrets = env.secrets.service();
+    let browser = env.logged_in("erin").await;
+    let target = json!({ "runner_id": "vault", "environment_id": "e2e", "secret_key": "DB_URL" });
+    let with = |extra: Value| {
+        let mut body = target.clone();
+        body.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        body
+    };
+
+    let created = browser
+        .request(Method::POST, "secrets/create")
+        .json(&with(json!({ "value": "postgres://one" })))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(created.status(), StatusCode::OK);
+
+    // Listings never carry values, even when asked to.
+    let listed: Value = browser
+        .get("secrets/list?runner_id=vault&environment_id=e2e&reveal=true")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed["secrets"][0]["key"], "DB_URL");
+    assert_eq!(listed["secrets"][0]["size"], 14);
+    assert!(listed["secrets"][0].get("value").is_none());
+
+    // The environment holds its one key; a second is refused.
+    let over = browser
+        .request(Method::POST, "secrets/create")
+        .json(&json!({
+            "runner_id": "vault",
+            "environment_id": "e2e",
+            "secret_key": "OTHER",
+            "value": "x",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(over.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let updated = browser
+        .request(Method::PUT, "secrets/update")
+        .json(&with(json!({ "new_value": "postgres://two" })))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(updated.status(), StatusCode::OK);
+
+    let revealed: Value = browser
+        .get("secrets/get?runner_id=vault&environment_id=e2e&secret_key=DB_URL")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(revealed["value"], "postgres://two");
+    assert_eq!(revealed["version"], 2);
+
+    let versions: Value = browser
+        .get("secrets/versions?runner_id=vault&environment_id=e2e&secret_key=DB_URL")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(versions["versions"].as_array().unwrap().len(), 2);
+    // Version and rendering options mean nothing to the version list.
+    let stray = browser
+        .get("secrets/versions?runner_id=vault&environment_id=e2e&secret_key=DB_URL&version=1")
+        .await;
+    assert_eq!(stray.status(), StatusCode::BAD_REQUEST);
+    // A secret service without version history answers 501, not 500.
+    secrets.fail_next(Code::Unimplemented);
+    let unsupported = browser
+        .get("secrets/versions?runner_id=vault&environment_id=e2e&secret_key=DB_URL")
+        .await;
+    assert_eq!(unsupported.status(), StatusCode::NOT_IMPLEMENTED);
+
+    let invalid = browser
+        .request(Method::POST, "secrets/create")
+        .json(&json!({
+            "runner_id": "vault",
+            "environment_id": "e2e",
+            "secret_key": "lower case",
+            "value": "x",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(invalid.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Writes are not resent: the service may have acted before failing.
+    secrets.fail_next(Code::Unavailable);
+    let lost = browser
+        .request(Method::DELETE, "secrets/delete")
+        .json(&target)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(lost.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(secrets.value("vault", "e2e", "DB_URL").is_some());
+
+    let deleted = browser
+        .request(Method::DELETE, "secrets/delete")
+        .json(&target)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(deleted.status(), StatusCode::OK);
+    assert!(secrets.value("vault", "e2e", "DB_URL").is_none());
+
+    // Every call carried a dashboard-signed caller assertion.
+    assert!(
+        secrets
+            .authorizations()
+            .iter()
+            .all(|auth| auth.as_deref().is_some_and(|a| a.starts_with("Bearer ")))
+    );
+
+    // An outage that outlasts the retries surfaces as 503.
+    for _ in 0..3 {
+        secrets.fail_next(Code::Unavailable);
+    }
+    let unavailable = browser
+        .get("secrets/list?runner_id=vault&environment_id=e2e")
+        .await;
+    assert_eq!(unavailable.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn secret_retries_stop_at_the_call_deadline() {
+    let env = TestEnv::start_with(|config, _| {
+        config.secret_grpc.retries = 100;
+        config.secret_grpc.timeout = std::time::Duration::from_secs(1);
+    })
+    .await;
+    let secrets = env.secrets.service();
+    let browser = env.logged_in("erin").await;
+    for _ in 0..100 {
+        secrets.fail_next(Code::Unavailable);
+    }
+
+    let started = std::time::Instant::now();
+    let resp = browser
+        .get("secrets/list?runner_id=vault&environment_id=e2e")
+        .await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn secrets_of_foreign_runners_are_forbidden() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("erin").await;
+    let resp = browser
+        .get("secrets/list?runner_id=web&environment_id=prod")
+        .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use common::{Browser, TestEnv, raw_get};
+use reqwest::{Method, Response, StatusCode};
+use serde_json::{Value, json};
+
+async fn create(browser: &Browser, body: Value) -> Response {
+    browser
+        .request(Method::POST, "auth/tokens")
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn create_token(browser: &Browser, scopes: Value) -> (String, String) {
+    let resp = create(
+        browser,
+        json!({ "name": "ci", "scopes": scopes, "password": "alice-password" }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body: Value = resp.json().await.unwrap();
+    (
+        body["id"].as_str().unwrap().to_owned(),
+        body["token"].as_str().unwrap().to_owned(),
+    )
+}
+
+/// A cookie-less request, the way a script sends it.
+async fn bearer(env: &TestEnv, token: &str, path: &str) -> StatusCode {
+    reqwest::Client::new()
+        .get(format!("{}{}", env.base, path))
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn tokens_are_held_to_their_scopes_until_revoked() {
+    let env = TestEnv::start().await;
+    let alice = env.logged_in("alice").await;
+
+    let resp = create(
+        &alice,
+        json!({ "name": "ci", "scopes": ["proxy_read"], "password": "guess" }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let resp = create(
+        &alice,
+        json!({ "name": "ci", "scopes": ["admin"], "password": "alice-password" }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (id, token) = create_token(&alice, json!(["proxy_read"])).await;
+    assert!(token.starts_with("adt_"));
+    assert_eq!(bearer(&env, &token, "proxy/vms").await, StatusCode::OK);
+    // Control commands are writes, whatever their method.
+    assert_eq!(
+        bearer(&env, &token, "proxy/control/web-1/restart").await,
+        StatusCode::FORBIDDEN
+    );
+    // Scopes are judged on the path the upstream resolves.
+    let raw = |path: &'static str| {
+        let auth = format!("authorization: Bearer {}\r\n", token);
+        let base = env.base.clone();
+        async move { raw_get(&base, path, &auth).await }
+    };
+    assert_eq!(raw("proxy/vms/../control/web-1/restart").await, 400);
+    assert_eq!(raw("proxy/%63ontrol/web-1/restart").await, 403);
+    assert_eq!(
+        bearer(
+            &env,
+            &token,
+            "secrets/list?runner_id=web&environment_id=prod"
+        )
+        .await,
+        StatusCode::FORBIDDEN
+    );
+    // Tokens are managed with a session only.
+    assert_eq!(
+        bearer(&env, &token, "auth/tokens").await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let listed: Value = alice.get("auth/tokens").await.json().await.unwrap();
+    assert_eq!(listed["tokens"][0]["id"], id.as_str());
+    assert_eq!(listed["tokens"][0]["scopes"], json!(["proxy_read"]));
+    assert!(listed["tokens"][0]["last_used"].is_i64());
+    assert!(listed["tokens"][0].get("token").is_none());
+
+    let resp = alice
+        .request(Method::DELETE, &format!("auth/tokens/{}", id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        bearer(&env, &token, "proxy/vms").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        bearer(&env, "adt_not-a-token", "proxy/vms").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(alice.get("auth/whoami").await.status(), StatusCode::OK);
+
+    // A token ends with its session, whatever lifetime was asked for.
+    let resp = create(
+        &alice,
+        json!({
+            "name": "long",
+            "scopes": ["proxy_read"],
+            "password": "alice-password",
+            "expires_in_days": 365,
+        }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body: Value = resp.json().await.unwrap();
+    let lifetime = body["expires_at"].as_i64().unwrap() - body["created_at"].as_i64().unwrap();
+    assert!(lifetime <= 7 * 24 * 3600 + 60, "{}", lifetime);
+}
+
+#[tokio::test]
+async fn tokens_need_the_sign_in_code_and_end_with_the_account_sessions() {
+    let env = TestEnv::start().await;
+    let alice = env.logged_in("alice").await;
+    env.upstream.update_fixtures(|fixtures| {
+        let user = fixtures.users.iter_mut().find(|u| u.user_id == "alice");
+        user.unwrap().totp = Some("135790".to_owned());
+    });
+
+    let body = json!({
+        "name": "deploy",
+        "scopes": ["secrets", "proxy_write"],
+        "password": "alice-password",
+    });
+    let resp = create(&alice, body.clone()).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let errors: Value = resp.json().await.unwrap();
+    assert_eq!(errors["fields"][0]["field"], "code");
+
+    let mut with_code = body;
+    with_code["code"] = json!("135790");
+    let resp = create(&alice, with_code).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let created: Value = resp.json().await.unwrap();
+    let token = created["token"].as_str().unwrap();
+    assert_eq!(
+        bearer(
+            &env,
+            token,
+            "secrets/list?runner_id=web&environment_id=prod"
+        )
+        .await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        bearer(&env, token, "proxy/control/web-1/restart").await,
+        StatusCode::ACCEPTED
+    );
+
+    // Logging out everywhere takes the token's session along.
+    assert!(alice.post("auth/logout_all").await.status().is_success());
+    assert_eq!(
+        bearer(
+            &env,
+            token,
+            "secrets/list?runner_id=web&environment_id=prod"
+        )
+        .await,
+        StatusCode::UNAUTHORIZED
+    );
+}
@@ -0,0 +1,296 @@
+//! A dashboard served over real HTTP against the mock upstream, the mock
+//! secret service and an in-memory session store. Every test starts its own,
+//! so tests share nothing.
+
+#![allow(dead_code)]
+
+use artisan_dashboard::{
+    api::routes::create_api_routes,
+    auth::verify::KeySource,
+    config::AppConfig,
+    database::sessions::SessionStore,
+    grpc::SecretAuthMode,
+    mock::{
+        MOCK_AUDIENCE, MOCK_ISSUER, MockFixtures, MockRunner, MockSecretServer, MockSecretService,
+        MockUpstream, MockUser,
+    },
+    state::{AppState, SharedState},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use reqwest::{Method, RequestBuilder, Response, header::SET_COOKIE};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::oneshot,
+};
+
+pub const SIGNING_KEY: &str = "e2e-signing-key";
+
+pub struct TestEnv {
+    /// Root of the dashboard API, ending in `/api/`.
+    pub base: String,
+    pub state: SharedState,
+    pub upstream: MockUpstream,
+    pub secrets: MockSecretServer,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl TestEnv {
+    pub async fn start() -> Self {
+        Self::start_with(|_, _| {}).await
+    }
+
+    /// Like `start`, with a chance to adjust the configuration first. By
+    /// default upstream tokens are verified against the mock's JWKS.
+    pub async fn start_with(configure: impl FnOnce(&mut AppConfig, &MockUpstream)) -> Self {
+        let upstream = MockUpstream::start(fixtures()).expect("mock upstream");
+        let secrets = MockSecretService::new()
+            .start()
+            .await
+            .expect("mock secret service");
+
+        let mut config = AppConfig::from_env().expect("config");
+        config.upstream_base_url = upstream.base_url();
+        config.secret_grpc.addr = Some(secrets.url());
+        config.secret_grpc.auth = SecretAuthMode::Assertion;
+        config.secret_grpc.signing_key = Some(SIGNING_KEY.to_owned());
+        config.token_verify.keys = Some(KeySource::JwksUrl(upstream.jwks_url()));
+        config.token_verify.issuer = Some(vec![MOCK_ISSUER.to_owned()]);
+        config.token_verify.audience = Some(vec![MOCK_AUDIENCE.to_owned()]);
+        configure(&mut config, &upstream);
+        let state = AppState::new(config, SessionStore::memory()).expect("state");
+
+        let routes = create_api_routes(state.clone()).await;
+        let (tx, rx) = oneshot::channel();
+        let (addr, server) =
+            warp::serve(routes).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async {
+                rx.await.ok();
+            });
+        tokio::spawn(server);
+
+        Self {
+            base: format!("http://{}/api/", addr),
+            state,
+            upstream,
+            secrets,
+            shutdown: Some(tx),
+        }
+    }
+
+    pub fn browser(&self) -> Browser {
+        Browser {
+            client: reqwest::Client::new(),
+            base: self.base.clone(),
+            session_id: None,
+            csrf_token: None,
+        }
+    }
+
+    /// A browser presenting `session_id` without having logged in.
+    pub fn browser_with_session(&self, session_id: &str) -> Browser {
+        Browser {
+            session_id: Some(session_id.to_owned()),
+            ..self.browser()
+        }
+    }
+
+    /// Logs in as a fixture user (password `<id>-password`).
+    pub async fn logged_in(&self, user_id: &str) -> Browser {
+        let mut browser = self.browser();
+        let resp = browser
+            .login(
+                &format!("{}@example.com", user_id),
+                &format!("{}-password", user_id),
+            )
+            .await;
+        assert!(resp.status().is_success(), "login as {} failed", user_id);
+        browser
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+fn user(id: &str, runner: &str, instances: &[&str]) -> MockUser {
+    MockUser {
+        user_id: id.to_owned(),
+        email: format!("{}@example.com", id),
+        password: format!("{}-password", id),
+        role: "user".to_owned(),
+        runners: vec![MockRunner {
+            id: runner.to_owned(),
+            status: "Running".to_owned(),
+            instances: instances.iter().map(|i| i.to_string()).collect(),
+        }],
+        vms: vec![json!({ "vmid": 200, "name": format!("{}-vm", id), "status": "running" })],
+        usage: json!({ "cpu_hours": 1.0 }),
+        logs: Vec::new(),
+        totp: None,
+    }
+}
+
+/// The default `alice` and `bob`, plus a few more with runners of their
+/// own.
+fn fixtures() -> MockFixtures {
+    let mut fixtures = MockFixtures::default();
+    fixtures.users.extend([
+        user("carol", "carol-app", &["carol-1"]),
+        user("dave", "dave-app", &["dave-1"]),
+        user("erin", "vault", &["vault-1"]),
+        user("frank", "frank-app", &["frank-1"]),
+        user("grace", "grace-app", &["grace-1"]),
+    ]);
+    fixtures
+}
+
+/// `token` with its `sub` swapped for `sub`, keeping the original signature.
+pub fn tamper_sub(token: &str, sub: &str) -> String {
+    let parts: Vec<&str> = token.split('.').collect();
+    let mut claims: Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+    claims["sub"] = json!(sub);
+    let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+    format!("{}.{}.{}", parts[0], payload, parts[2])
+}
+
+/// A client holding one session cookie, like a browser tab. Once logged in
+/// it echoes the CSRF cookie in `X-CSRF-Token`, as the frontend does.
+pub struct Browser {
+    client: reqwest::Client,
+    base: String,
+    pub session_id: Option<String>,
+    pub csrf_token: Option<String>,
+}
+
+impl Browser {
+    pub async fn login(&mut self, email: &str, password: &str) -> Response {
+        let request = self.request(Method::POST, "auth/login");
+        self.send_login(request, email, password).await
+    }
+
+    /// Logs in presenting `user_agent`, for telling sessions apart.
+    pub async fn login_as_agent(mut self, email: &str, password: &str, user_agent: &str) -> Self {
+        let request = self
+            .request(Method::POST, "auth/login")
+            .header("user-agent", user_agent);
+        let resp = self.send_login(request, email, password).await;
+        assert!(resp.status().is_success(), "login as {} failed", email);
+        self
+    }
+
+    async fn send_login(
+        &mut self,
+        request: RequestBuilder,
+        email: &str,
+        password: &str,
+    ) -> Response {
+        let resp = request
+            .json(&json!({ "email": email, "password": password }))
+            .send()
+            .await
+            .expect("login request");
+        self.keep_cookies(&resp);
+        resp
+    }
+
+    /// Second login step, for accounts with MFA.
+    pub async fn verify_login(&mut self, pending_login: &str, code: &str) -> Response {
+        let resp = self
+            .request(Method::POST, "auth/login/verify")
+            .json(&json!({ "pending_login": pending_login, "code": code }))
+            .send()
+            .await
+            .expect("verify request");
+        self.keep_cookies(&resp);
+        resp
+    }
+
+    fn keep_cookies(&mut self, resp: &Response) {
+        if let Some(id) = session_cookie(resp) {
+            self.session_id = Some(id);
+        }
+        if let Some(token) = cookie_value(resp, "csrf_token") {
+            self.csrf_token = Some(token);
+        }
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let builder = self
+            .client
+            .request(method, format!("{}{}", self.base, path));
+        let builder = match &self.session_id {
+            Some(id) => builder.header("cookie", format!("session_id={}", id)),
+            None => builder,
+        };
+        match &self.csrf_token {
+            Some(token) => builder.header("x-csrf-token", token),
+            None => builder,
+        }
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        self.request(Method::GET, path)
+            .send()
+            .await
+            .expect("GET request")
+    }
+
+    /// `GET` of `path` exactly as written, for paths reqwest would resolve
+    /// before sending. Returns the status code.
+    pub async fn raw_get(&self, path: &str) -> u16 {
+        let cookie = match &self.session_id {
+            Some(id) => format!("cookie: session_id={}\r\n", id),
+            None => String::new(),
+        };
+        raw_get(&self.base, path, &cookie).await
+    }
+
+    pub async fn post(&self, path: &str) -> Response {
+        self.request(Method::POST, path)
+            .send()
+            .await
+            .expect("POST request")
+    }
+}
+
+/// `GET` of `base` + `path` exactly as written, with `headers` (each ending
+/// in CRLF). Returns the status code.
+pub async fn raw_get(base: &str, path: &str, headers: &str) -> u16 {
+    let url = reqwest::Url::parse(base).unwrap();
+    let addr = format!("{}:{}", url.host_str().unwrap(), url.port().unwrap());
+    let request = format!(
+        "GET {}{} HTTP/1.1\r\nhost: {}\r\n{}connection: close\r\n\r\n",
+        url.path(),
+        path,
+        addr,
+        headers
+    );
+    let mut stream = TcpStream::connect(&addr).await.expect("connect");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response);
+    response.split(' ').nth(1).unwrap().parse().unwrap()
+}
+
+/// The `session_id` value set by a response, if any.
+pub fn session_cookie(resp: &Response) -> Option<String> {
+    cookie_value(resp, "session_id")
+}
+
+/// The value of cookie `name` set by a response, if any.
+pub fn cookie_value(resp: &Response, name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    resp.headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find_map(|v| v.strip_prefix(prefix.as_str()))
+        .map(|rest| rest.split(';').next().unwrap_or_default().to_owned())
+}
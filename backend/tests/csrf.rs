@@ -0,0 +1,80 @@
+mod common;
+
+use common::{TestEnv, cookie_value};
+use reqwest::{Method, StatusCode};
+use serde_json::{Value, json};
+
+#[tokio::test]
+async fn mutating_requests_need_the_session_token() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("dave").await;
+    let session_id = browser.session_id.clone().unwrap();
+
+    let forged = env.browser_with_session(&session_id);
+    let resp = forged.post("auth/logout_all").await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("CSRF"));
+
+    let wrong = forged
+        .request(Method::POST, "auth/logout_all")
+        .header("x-csrf-token", env.state.csrf.token("another-session"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong.status(), StatusCode::FORBIDDEN);
+    // Only the login routes themselves go without a token.
+    let proxied = forged.post("proxy/vms/auth/login").await;
+    assert_eq!(proxied.status(), StatusCode::FORBIDDEN);
+    let body: Value = proxied.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("CSRF"));
+    assert_eq!(forged.get("auth/whoami").await.status(), StatusCode::OK);
+
+    let token: Value = browser.get("auth/csrf").await.json().await.unwrap();
+    assert_eq!(token["csrf_token"], browser.csrf_token.clone().unwrap());
+    assert_eq!(
+        browser.post("auth/logout_all").await.status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn writes_from_foreign_origins_are_refused() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("frank").await;
+
+    let foreign = browser
+        .request(Method::POST, "auth/logout")
+        .header("origin", "https://evil.example")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(foreign.status(), StatusCode::FORBIDDEN);
+
+    let foreign_referer = browser
+        .request(Method::POST, "auth/logout")
+        .header("referer", "https://evil.example/page?x=1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(foreign_referer.status(), StatusCode::FORBIDDEN);
+
+    let login = env
+        .browser()
+        .request(Method::POST, "auth/login")
+        .header("origin", "https://evil.example")
+        .json(&json!({ "email": "frank@example.com", "password": "frank-password" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(login.status(), StatusCode::FORBIDDEN);
+
+    let allowed = browser
+        .request(Method::POST, "auth/logout")
+        .header("origin", "http://localhost:3800")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(allowed.status(), StatusCode::OK);
+    assert_eq!(cookie_value(&allowed, "csrf_token").as_deref(), Some(""));
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestEnv;
+use reqwest::{Response, StatusCode, header::RETRY_AFTER};
+
+fn retry_after(resp: &Response) -> u64 {
+    resp.headers()
+        .get(RETRY_AFTER)
+        .expect("Retry-After")
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn failures_back_off_then_lock_the_email_out() {
+    let env = TestEnv::start_with(|config, _| {
+        let limits = &mut config.login_limit;
+        limits.email.free = 2;
+        limits.email.lockout_after = 4;
+        limits.backoff = Duration::from_secs(1);
+        limits.lockout = Duration::from_secs(600);
+    })
+    .await;
+    let mut browser = env.browser();
+
+    for _ in 0..3 {
+        let resp = browser.login("carol@example.com", "guess").await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // Third failure is past the free ones, so the next try has to wait.
+    let resp = browser.login("carol@example.com", "carol-password").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(retry_after(&resp), 1);
+
+    // Case and whitespace don't make a fresh key.
+    let resp = browser.login(" Carol@Example.com", "guess").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Other accounts are unaffected.
+    let resp = browser.login("dave@example.com", "dave-password").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Once the wait is over the fourth failure locks the email out.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let resp = browser.login("carol@example.com", "guess").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = browser.login("carol@example.com", "carol-password").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(retry_after(&resp) > 500);
+}
+
+#[tokio::test]
+async fn success_resets_the_email_but_not_the_address() {
+    let env = TestEnv::start_with(|config, _| {
+        let limits = &mut config.login_limit;
+        limits.email.free = 2;
+        limits.ip.free = 3;
+        limits.backoff = Duration::from_secs(60);
+    })
+    .await;
+    let mut browser = env.browser();
+
+    for _ in 0..2 {
+        browser.login("erin@example.com", "guess").await;
+    }
+    let resp = browser.login("erin@example.com", "erin-password").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    // The address has two failures; the next two are over its budget.
+    for email in ["erin@example.com", "nobody@example.com"] {
+        let resp = browser.login(email, "guess").await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let resp = browser.login("dave@example.com", "dave-password").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(retry_after(&resp) >= 55);
+}
+
+#[tokio::test]
+async fn parallel_guesses_share_one_budget() {
+    let env = TestEnv::start_with(|config, _| {
+        let limits = &mut config.login_limit;
+        limits.email.free = 2;
+        // Absurd delays saturate instead of overflowing.
+        limits.backoff = Duration::from_secs(u64::MAX / 4);
+        limits.backoff_max = Duration::from_secs(u64::MAX);
+    })
+    .await;
+
+    let guesses: Vec<_> = (0..10)
+        .map(|_| {
+            let mut browser = env.browser();
+            tokio::spawn(async move { browser.login("carol@example.com", "guess").await.status() })
+        })
+        .collect();
+    let mut statuses = Vec::new();
+    for guess in guesses {
+        statuses.push(guess.await.unwrap());
+    }
+    let refused = statuses
+        .iter()
+        .filter(|s| **s == StatusCode::UNAUTHORIZED)
+        .count();
+    let throttled = statuses
+        .iter()
+        .filter(|s| **s == StatusCode::TOO_MANY_REQUESTS)
+        .count();
+    // Three failures are allowed before the backoff, however they arrive.
+    assert!(refused <= 3, "{} guesses reached the upstream", refused);
+    assert_eq!(refused + throttled, 10);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn parallel_failures_all_count_toward_the_lockout() {
+    let env = TestEnv::start_with(|config, _| {
+        let limits = &mut config.login_limit;
+        limits.email.free = 100;
+        limits.email.lockout_after = 20;
+        limits.ip.free = 100;
+        limits.ip.lockout_after = 100;
+        limits.lockout = Duration::from_secs(600);
+    })
+    .await;
+
+    let guesses: Vec<_> = (0..20)
+        .map(|_| {
+            let mut browser = env.browser();
+            tokio::spawn(async move { browser.login("carol@example.com", "guess").await.status() })
+        })
+        .collect();
+    for guess in guesses {
+        assert_eq!(guess.await.unwrap(), StatusCode::UNAUTHORIZED);
+    }
+
+    let resp = env
+        .browser()
+        .login("carol@example.com", "carol-password")
+        .await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
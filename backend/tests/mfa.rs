@@ -0,0 +1,75 @@
+mod common;
+
+use common::TestEnv;
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+fn require_code(env: &TestEnv, user_id: &str, code: &str) {
+    env.upstream.update_fixtures(|fixtures| {
+        let user = fixtures.users.iter_mut().find(|u| u.user_id == user_id);
+        user.unwrap().totp = Some(code.to_owned());
+    });
+}
+
+#[tokio::test]
+async fn challenged_logins_complete_with_a_code() {
+    let env = TestEnv::start().await;
+    require_code(&env, "alice", "123456");
+    let mut browser = env.browser();
+
+    let resp = browser.login("alice@example.com", "alice-password").await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    assert!(browser.session_id.is_none());
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["mfa_required"], true);
+    assert_eq!(body["methods"], json!(["totp"]));
+    let pending = body["pending_login"].as_str().unwrap().to_owned();
+
+    // Malformed codes never reach the upstream.
+    let resp = browser.verify_login(&pending, "12ab").await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(env.upstream.hits("POST", "auth/login/mfa"), 0);
+
+    let resp = browser.verify_login(&pending, "654321").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(browser.session_id.is_none());
+
+    let resp = browser.verify_login(&pending, "123456").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(browser.session_id.is_some());
+    let whoami: Value = browser.get("auth/whoami").await.json().await.unwrap();
+    assert_eq!(whoami["user_id"], "alice");
+
+    // A pending login completes only once.
+    let resp = env.browser().verify_login(&pending, "123456").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn wrong_codes_use_up_the_pending_login() {
+    let env = TestEnv::start_with(|config, _| {
+        config.login_limit.email.free = 100;
+        config.login_limit.email.lockout_after = 100;
+    })
+    .await;
+    require_code(&env, "bob", "246810");
+    let mut browser = env.browser();
+
+    let body: Value = browser
+        .login("bob@example.com", "bob-password")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let pending = body["pending_login"].as_str().unwrap().to_owned();
+    for _ in 0..5 {
+        let resp = browser.verify_login(&pending, "000000").await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let resp = browser.verify_login(&pending, "246810").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("expired"));
+    assert_eq!(env.upstream.hits("POST", "auth/login/mfa"), 5);
+}
@@ -0,0 +1,205 @@
+use artisan_dashboard::{
+    grpc::secret_service::{
+        CreateSecretRequest, GetSecretRequest, ListSecretVersionsRequest, UpdateSecretRequest,
+        secret_service_client::SecretServiceClient,
+    },
+    mock::{MockFixtures, MockSecretService, MockUpstream, ScriptedResponse},
+};
+use serde_json::{Value, json};
+use tonic::Code;
+
+async fn login(upstream: &MockUpstream, email: &str, password: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}auth/login", upstream.base_url()))
+        .json(&json!({ "email": email, "password": password }))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn upstream_login_and_authenticated_listing() {
+    let upstream = MockUpstream::start(MockFixtures::default()).unwrap();
+    let client = reqwest::Client::new();
+
+    assert_eq!(
+        login(&upstream, "alice@example.com", "wrong")
+            .await
+            .status(),
+        401
+    );
+
+    let tokens: Value = login(&upstream, "alice@example.com", "alice-password")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let auth = tokens["auth"].as_str().unwrap();
+
+    let unauthenticated = client
+        .get(format!("{}runners", upstream.base_url()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unauthenticated.status(), 401);
+
+    let runners: Value = client
+        .get(format!("{}runners", upstream.base_url()))
+        .bearer_auth(auth)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(runners["data"][0]["name"], "ais_web");
+    assert_eq!(upstream.hits("GET", "runners"), 2);
+
+    let control = client
+        .get(format!("{}control/api-1/start", upstream.base_url()))
+        .bearer_auth(auth)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(control.status(), 404, "bob's instance is not alice's");
+    assert!(upstream.commands().is_empty());
+}
+
+#[tokio::test]
+async fn upstream_refreshes_expired_tokens() {
+    let fixtures = MockFixtures {
+        auth_ttl_secs: -10,
+        ..MockFixtures::default()
+    };
+    let upstream = MockUpstream::start(fixtures).unwrap();
+    let client = reqwest::Client::new();
+
+    let tokens: Value = login(&upstream, "bob@example.com", "bob-password")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let expired = tokens["auth"].as_str().unwrap();
+
+    let rejected = client
+        .get(format!("{}account/me", upstream.base_url()))
+        .bearer_auth(expired)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(rejected.status(), 401);
+
+    let refreshed: Value = client
+        .post(format!("{}auth/refresh", upstream.base_url()))
+        .json(&json!({ "expired_token": expired, "refresh_token": tokens["refresh"] }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let me: Value = client
+        .get(format!("{}account/me", upstream.base_url()))
+        .bearer_auth(refreshed["auth"].as_str().unwrap())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(me["user_id"], "bob");
+}
+
+#[tokio::test]
+async fn upstream_scripted_responses_take_precedence_once() {
+    let upstream = MockUpstream::start(MockFixtures::default()).unwrap();
+    upstream.script(
+        "POST",
+        "auth/login",
+        ScriptedResponse {
+            status: 503,
+            body: json!({ "status": "error" }),
+        },
+    );
+
+    assert_eq!(
+        login(&upstream, "alice@example.com", "alice-password")
+            .await
+            .status(),
+        503
+    );
+    assert_eq!(
+        login(&upstream, "alice@example.com", "alice-password")
+            .await
+            .status(),
+        200
+    );
+}
+
+#[tokio::test]
+async fn secret_service_versions_and_failures() {
+    let server = MockSecretService::new().start().await.unwrap();
+    let mut client = SecretServiceClient::connect(server.url()).await.unwrap();
+
+    client
+        .create_secret(CreateSecretRequest {
+            runner_id: "web".into(),
+            environment_id: "prod".into(),
+            secret_key: "API_KEY".into(),
+            value: "one".into(),
+            actor: "alice".into(),
+        })
+        .await
+        .unwrap();
+    client
+        .update_secret(UpdateSecretRequest {
+            runner_id: "web".into(),
+            environment_id: "prod".into(),
+            secret_key: "API_KEY".into(),
+            new_value: "two".into(),
+            actor: "alice".into(),
+        })
+        .await
+        .unwrap();
+
+    let first = client
+        .get_secret(GetSecretRequest {
+            runner_id: "web".into(),
+            environment_id: "prod".into(),
+            secret_key: "API_KEY".into(),
+            version: 1,
+            actor: "alice".into(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(first.value, b"one");
+
+    let versions = client
+        .list_secret_versions(ListSecretVersionsRequest {
+            runner_id: "web".into(),
+            environment_id: "prod".into(),
+            secret_key: "API_KEY".into(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(versions.versions.len(), 2);
+    assert_eq!(versions.versions[0].version, 2);
+    assert_eq!(
+        server.service().value("web", "prod", "API_KEY").as_deref(),
+        Some(&b"two"[..])
+    );
+
+    server.service().fail_next(Code::Unavailable);
+    let err = client
+        .list_secret_versions(ListSecretVersionsRequest {
+            runner_id: "web".into(),
+            environment_id: "prod".into(),
+            secret_key: "API_KEY".into(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use common::TestEnv;
+use reqwest::{Response, StatusCode, header::RETRY_AFTER};
+
+fn header(resp: &Response, name: &str) -> u64 {
+    resp.headers()
+        .get(name)
+        .unwrap_or_else(|| panic!("{} missing", name))
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn buckets_are_per_user_and_route_class() {
+    let env = TestEnv::start_with(|config, _| {
+        config.rate_limit.proxy_read.burst = 3;
+        config.rate_limit.proxy_read.per_minute = 1;
+    })
+    .await;
+    let alice = env.logged_in("alice").await;
+
+    for remaining in [2, 1, 0] {
+        let resp = alice.get("proxy/vms").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "x-ratelimit-limit"), 3);
+        assert_eq!(header(&resp, "x-ratelimit-remaining"), remaining);
+    }
+    let resp = alice.get("proxy/vms").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let wait = header(&resp, RETRY_AFTER.as_str());
+    assert!((55..=60).contains(&wait), "Retry-After {}", wait);
+
+    // Secrets have their own bucket, other users their own buckets, and
+    // unlimited routes carry no headers.
+    let resp = alice
+        .get("secrets/list?runner_id=web&environment_id=prod")
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().contains_key("x-ratelimit-remaining"));
+    let bob = env.logged_in("bob").await;
+    assert_eq!(bob.get("proxy/vms").await.status(), StatusCode::OK);
+    let resp = alice.get("auth/whoami").await;
+    assert!(!resp.headers().contains_key("x-ratelimit-limit"));
+}
+
+#[tokio::test]
+async fn throttled_requests_show_up_in_metrics() {
+    let env = TestEnv::start_with(|config, _| {
+        config.rate_limit.secrets.burst = 1;
+        config.metrics_token = Some("scrape-me".to_owned());
+    })
+    .await;
+    let carol = env.logged_in("carol").await;
+    carol
+        .get("secrets/list?runner_id=carol-app&environment_id=prod")
+        .await;
+    assert_eq!(
+        carol
+            .get("secrets/list?runner_id=carol-app&environment_id=prod")
+            .await
+            .status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    let client = reqwest::Client::new();
+    let url = format!("{}metrics", env.base);
+    let denied = client.get(&url).bearer_auth("guess").send().await.unwrap();
+    assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+
+    let metrics = client
+        .get(&url)
+        .bearer_auth("scrape-me")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("dashboard_rate_limit_throttled_total{class=\"secrets\"} 1"));
+    assert!(metrics.contains("dashboard_rate_limit_allowed_total{class=\"secrets\"} 1"));
+    assert!(metrics.contains("dashboard_rate_limit_throttled_total{class=\"proxy_read\"} 0"));
+}
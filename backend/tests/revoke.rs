@@ -0,0 +1,64 @@
+mod common;
+
+use artisan_dashboard::{auth::token::get_token, mock::upstream::ScriptedResponse};
+use common::TestEnv;
+use reqwest::StatusCode;
+use serde_json::json;
+
+async fn upstream_me(env: &TestEnv, token: &str) -> StatusCode {
+    reqwest::Client::new()
+        .get(format!("{}account/me", env.upstream.base_url()))
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn logout_revokes_the_tokens_upstream() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("alice").await;
+    let session_id = browser.session_id.clone().unwrap();
+    let session = env.state.sessions.lookup(&session_id).await.unwrap();
+    assert_eq!(upstream_me(&env, &session.auth_jwt).await, StatusCode::OK);
+
+    assert_eq!(browser.post("auth/logout").await.status(), StatusCode::OK);
+    assert_eq!(env.upstream.hits("POST", "auth/revoke"), 1);
+    assert_eq!(
+        upstream_me(&env, &session.auth_jwt).await,
+        StatusCode::UNAUTHORIZED
+    );
+    // Revoked upstream, so nothing needs refusing locally.
+    assert!(!env.state.revoked_tokens.contains(&session.auth_jwt).await);
+}
+
+#[tokio::test]
+async fn tokens_that_failed_to_revoke_are_refused_locally() {
+    let env = TestEnv::start().await;
+    let first = env.logged_in("bob").await;
+    let second = env.logged_in("bob").await;
+    let mut sessions = Vec::new();
+    for browser in [&first, &second] {
+        let id = browser.session_id.as_deref().unwrap();
+        sessions.push(env.state.sessions.lookup(id).await.unwrap());
+    }
+    for _ in 0..2 {
+        env.upstream.script(
+            "POST",
+            "auth/revoke",
+            ScriptedResponse {
+                status: 503,
+                body: json!({ "errors": [] }),
+            },
+        );
+    }
+
+    assert_eq!(first.post("auth/logout_all").await.status(), StatusCode::OK);
+    assert_eq!(env.upstream.hits("POST", "auth/revoke"), 2);
+    for session in sessions {
+        // Still good upstream, but the dashboard won't use them.
+        assert_eq!(upstream_me(&env, &session.auth_jwt).await, StatusCode::OK);
+        assert!(get_token(&env.state, session).await.is_err());
+    }
+}
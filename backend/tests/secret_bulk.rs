@@ -0,0 +1,143 @@
+mod common;
+
+use artisan_dashboard::api::secret_bulk::parse_dotenv;
+use common::TestEnv;
+use reqwest::{Method, StatusCode};
+use serde_json::{Value, json};
+
+#[tokio::test]
+async fn import_reports_what_happened_to_each_key() {
+    let env = TestEnv::start().await;
+    let secrets = env.secrets.service();
+    secrets.seed("web", "bulk", "KEPT", "same");
+    secrets.seed("web", "bulk", "CONFLICT", "stored");
+    let alice = env.logged_in("alice").await;
+
+    let body = json!({ "KEPT": "same", "CONFLICT": "incoming", "ADDED": "new" });
+    let report: Value = alice
+        .request(
+            Method::POST,
+            "secrets/import?runner_id=web&environment_id=bulk&format=json",
+        )
+        .body(body.to_string())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let status = |key: &str| {
+        let results = report["results"].as_array().unwrap();
+        let result = results.iter().find(|r| r["key"] == key).unwrap();
+        result["status"].as_str().unwrap().to_owned()
+    };
+    assert_eq!(status("KEPT"), "unchanged");
+    assert_eq!(status("CONFLICT"), "skipped");
+    assert_eq!(status("ADDED"), "applied");
+    assert_eq!(
+        secrets.value("web", "bulk", "CONFLICT").unwrap(),
+        b"stored".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn binary_values_round_trip_through_json_export() {
+    let env = TestEnv::start().await;
+    let secrets = env.secrets.service();
+    let raw = vec![0xff, 0x00, 0xfe, b'k'];
+    secrets.seed("web", "bulk", "CERT", &raw);
+    secrets.seed("web", "bulk", "NAME", "plain");
+    let alice = env.logged_in("alice").await;
+
+    let resp = alice
+        .get("secrets/export?runner_id=web&environment_id=bulk&format=dotenv")
+        .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let exported: Value = alice
+        .get("secrets/export?runner_id=web&environment_id=bulk&format=json")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(exported["NAME"], "plain");
+    assert_eq!(exported["CERT"], json!({ "base64": "/wD+aw==" }));
+
+    let resp = alice
+        .request(
+            Method::POST,
+            "secrets/import?runner_id=web&environment_id=restore&format=json",
+        )
+        .body(exported.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(secrets.value("web", "restore", "CERT").unwrap(), raw);
+    assert_eq!(
+        secrets.value("web", "restore", "NAME").unwrap(),
+        b"plain".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn copy_writes_the_stored_bytes() {
+    let env = TestEnv::start().await;
+    let secrets = env.secrets.service();
+    let raw = vec![0xc3, 0x28, 0x00, 0xff];
+    secrets.seed("web", "staging", "KEY", &raw);
+    let alice = env.logged_in("alice").await;
+
+    let body = json!({
+        "source_runner_id": "web",
+        "source_environment_id": "staging",
+        "target_environment_id": "prod-copy",
+    });
+    let resp = alice
+        .request(Method::POST, "secrets/copy")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(secrets.value("web", "prod-copy", "KEY").unwrap(), raw);
+
+    // A dry run previews updates by size, without the values.
+    secrets.seed("web", "staging", "KEY", "a longer value");
+    let preview: Value = alice
+        .request(Method::POST, "secrets/copy")
+        .json(&json!({
+            "source_runner_id": "web",
+            "source_environment_id": "staging",
+            "target_environment_id": "prod-copy",
+            "policy": "overwrite",
+            "dry_run": true,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let result = &preview["results"][0];
+    assert_eq!(result["action"], "update");
+    assert_eq!(result["status"], "planned");
+    assert_eq!(result["old_size"], 4);
+    assert_eq!(result["new_size"], 14);
+    assert!(!preview.to_string().contains("longer"));
+    assert_eq!(secrets.value("web", "prod-copy", "KEY").unwrap(), raw);
+}
+
+#[test]
+fn quoted_dotenv_values_may_carry_a_comment() {
+    let parsed =
+        parse_dotenv("A=\"v\" # note\nB='x # y' #note\nC=\"say \\\"hi\\\"\"\nD=plain # note\n")
+            .unwrap();
+    assert_eq!(parsed["A"], "v");
+    assert_eq!(parsed["B"], "x # y");
+    assert_eq!(parsed["C"], "say \"hi\"");
+    assert_eq!(parsed["D"], "plain");
+    assert!(parse_dotenv("E=\"v\" trailing").is_err());
+    assert!(parse_dotenv("F=\"open").is_err());
+}
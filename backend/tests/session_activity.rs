@@ -0,0 +1,81 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestEnv;
+use reqwest::StatusCode;
+use serde_json::Value;
+use tokio::time::sleep;
+
+#[tokio::test]
+async fn idle_sessions_end_and_activity_is_flushed() {
+    let env = TestEnv::start_with(|config, _| {
+        config.session_idle_timeout = Duration::from_secs(2);
+        config.activity_flush = Duration::from_millis(200);
+    })
+    .await;
+    let browser = env.logged_in("alice").await;
+    let session_id = browser.session_id.clone().unwrap();
+    let opened = env.state.sessions.meta(&session_id).await.unwrap();
+
+    // Requests keep the session alive and reach the store in the next flush.
+    for _ in 0..2 {
+        sleep(Duration::from_millis(1200)).await;
+        assert_eq!(browser.get("auth/sessions").await.status(), StatusCode::OK);
+    }
+    sleep(Duration::from_millis(400)).await;
+    let meta = env.state.sessions.meta(&session_id).await.unwrap();
+    assert!(meta.last_seen > opened.last_seen);
+    assert_eq!(meta.created_at, opened.created_at);
+
+    // Polling whoami reports the idle time left without resetting it.
+    let whoami: Value = browser.get("auth/whoami").await.json().await.unwrap();
+    let left = whoami["idle_remaining"].as_u64().unwrap();
+    assert!(left < 2, "idle_remaining {}", left);
+    assert!(whoami["lifetime_remaining"].as_u64().unwrap() > 0);
+    sleep(Duration::from_millis(1700)).await;
+
+    let resp = browser.get("auth/whoami").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("idle"));
+    assert!(env.state.sessions.lookup(&session_id).await.is_err());
+}
+
+#[tokio::test]
+async fn sessions_end_at_their_maximum_lifetime() {
+    let env = TestEnv::start_with(|config, _| {
+        config.session_idle_timeout = Duration::ZERO;
+        config.session_max_lifetime = Duration::from_secs(1);
+    })
+    .await;
+    let browser = env.logged_in("bob").await;
+
+    let whoami: Value = browser.get("auth/whoami").await.json().await.unwrap();
+    assert!(whoami["idle_remaining"].is_null());
+    for _ in 0..3 {
+        assert_eq!(browser.get("auth/sessions").await.status(), StatusCode::OK);
+        sleep(Duration::from_millis(400)).await;
+    }
+
+    let resp = browser.get("auth/sessions").await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("lifetime"));
+}
+
+#[tokio::test]
+async fn sessions_whose_activity_cannot_be_read_are_refused() {
+    let env = TestEnv::start().await;
+    let browser = env.logged_in("alice").await;
+    let session_id = browser.session_id.clone().unwrap();
+    assert_eq!(browser.get("auth/sessions").await.status(), StatusCode::OK);
+
+    // The session is still cached, but nothing says when it was last used.
+    env.state.sessions.delete(&session_id).await.unwrap();
+    env.state.session_activity.forget(&session_id).await;
+    assert_eq!(
+        browser.get("auth/sessions").await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{TestEnv, session_cookie};
+use reqwest::{Method, StatusCode, header::SET_COOKIE};
+use serde_json::json;
+
+fn set_cookie(resp: &reqwest::Response) -> String {
+    resp.headers()[SET_COOKIE].to_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn login_cookie_is_hardened_and_expires_with_the_session() {
+    let env = TestEnv::start().await;
+    let mut browser = env.browser();
+    let resp = browser.login("alice@example.com", "alice-password").await;
+    let cookie = set_cookie(&resp);
+    for attribute in ["HttpOnly", "Secure", "SameSite=Lax", "Path=/"] {
+        assert!(cookie.contains(attribute), "{}", cookie);
+    }
+
+    // The mock's refresh tokens last a week.
+    let max_age: i64 = cookie
+        .split("; ")
+        .find_map(|a| a.strip_prefix("Max-Age="))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((7 * 24 * 3600 - 60..=7 * 24 * 3600).contains(&max_age));
+
+    let logout = browser.post("auth/logout").await;
+    assert!(set_cookie(&logout).contains("Max-Age=0"));
+}
+
+#[tokio::test]
+async fn host_prefix_is_dropped_for_localhost_in_dev_mode() {
+    let env = TestEnv::start_with(|config, _| {
+        config.session_cookie.host_prefix = true;
+        config.session_cookie.dev = true;
+    })
+    .await;
+    let login = |host: &'static str| {
+        env.browser()
+            .request(Method::POST, "auth/login")
+            .header("host", host)
+            .json(&json!({ "email": "bob@example.com", "password": "bob-password" }))
+            .send()
+    };
+
+    let public = set_cookie(&login("dashboard.example.com").await.unwrap());
+    assert!(public.starts_with("__Host-session_id="), "{}", public);
+    assert!(public.contains("Secure"));
+    let id = public
+        .strip_prefix("__Host-session_id=")
+        .and_then(|rest| rest.split(';').next())
+        .unwrap();
+    let whoami = env
+        .browser()
+        .request(Method::GET, "auth/whoami")
+        .header("cookie", format!("__Host-session_id={}", id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(whoami.status(), StatusCode::OK);
+
+    let local = set_cookie(&login("localhost:3800").await.unwrap());
+    assert!(local.starts_with("session_id="), "{}", local);
+    assert!(!local.contains("Secure"));
+}
+
+#[tokio::test]
+async fn session_ids_rotate_on_activity() {
+    let env = TestEnv::start_with(|config, _| {
+        config.session_cookie.rotate_every = Duration::from_millis(500);
+    })
+    .await;
+    let browser = env.logged_in("carol").await;
+    let old_id = browser.session_id.clone().unwrap();
+
+    let first = browser.get("auth/whoami").await;
+    assert!(session_cookie(&first).is_none());
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    let rotated = browser.get("auth/whoami").await;
+    assert_eq!(rotated.status(), StatusCode::OK);
+    let new_id = session_cookie(&rotated).unwrap();
+    assert_ne!(new_id, old_id);
+    assert!(env.state.sessions.lookup(&old_id).await.is_err());
+
+    // The old cookie keeps working for requests already in flight.
+    assert_eq!(browser.get("auth/whoami").await.status(), StatusCode::OK);
+    let renewed = env.browser_with_session(&new_id);
+    assert_eq!(renewed.get("auth/whoami").await.status(), StatusCode::OK);
+}
@@ -0,0 +1,141 @@
+//! The MySQL session store, against the database named by
+//! `TEST_DATABASE_URL`, a scratch database whose clock runs on UTC. Run with
+//! `cargo test --test session_store -- --ignored`.
+
+use artisan_dashboard::{
+    api::cookie::{SessionData, SessionMeta},
+    database::sessions::SessionStore,
+};
+use chrono::{Duration, Utc};
+use sqlx::mysql::MySqlPoolOptions;
+use uuid::Uuid;
+
+const SCHEMA: &str = r#"CREATE TABLE IF NOT EXISTS sessions (
+    session_id  VARCHAR(255) PRIMARY KEY,
+    user_id     VARCHAR(255) NOT NULL,
+    auth_jwt    TEXT         NOT NULL,
+    refresh_jwt TEXT         NOT NULL,
+    expires_at  DATETIME     NOT NULL,
+    created_at  DATETIME     NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen   DATETIME     NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ip_address  VARCHAR(45)  NULL,
+    user_agent  VARCHAR(512) NULL,
+    INDEX (user_id)
+)"#;
+
+async fn store() -> SessionStore {
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL");
+    let pool = MySqlPoolOptions::new()
+        .max_connections(2)
+        .connect(&url)
+        .await
+        .expect("test database");
+    sqlx::query(SCHEMA).execute(&pool).await.expect("schema");
+    SessionStore::MySql(pool)
+}
+
+/// A session of `user_id` that ends after `lifetime`.
+fn session(user_id: &str, lifetime: Duration) -> SessionData {
+    SessionData {
+        session_id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_owned(),
+        auth_jwt: "auth".to_owned(),
+        refresh_jwt: "refresh".to_owned(),
+        expires_at: Utc::now() + lifetime,
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn sessions_round_trip_through_mysql() {
+    let store = store().await;
+    let user = Uuid::new_v4().to_string();
+    let live = session(&user, Duration::days(1));
+    let meta = SessionMeta::new(Some("10.0.0.1".to_owned()), Some("curl".to_owned()));
+    store.insert_with_meta(&live, &meta).await.unwrap();
+
+    let found = store.lookup(&live.session_id).await.unwrap();
+    assert_eq!(found.user_id, user);
+    assert_eq!(found.refresh_jwt, "refresh");
+    let stored = store.meta(&live.session_id).await.unwrap();
+    assert_eq!(stored.ip.as_deref(), Some("10.0.0.1"));
+    assert_eq!(stored.user_agent.as_deref(), Some("curl"));
+
+    store
+        .update_auth(&live.session_id, "auth-2".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        store.lookup(&live.session_id).await.unwrap().auth_jwt,
+        "auth-2"
+    );
+    let expires_at = Utc::now() + Duration::days(2);
+    store
+        .rotate(&live.session_id, "auth-3", "refresh-3", expires_at)
+        .await
+        .unwrap();
+    let found = store.lookup(&live.session_id).await.unwrap();
+    assert_eq!(found.refresh_jwt, "refresh-3");
+    assert_eq!(found.expires_at.timestamp(), expires_at.timestamp());
+
+    let seen = Utc::now() + Duration::minutes(5);
+    store.touch(&live.session_id, seen).await.unwrap();
+    let stored = store.meta(&live.session_id).await.unwrap();
+    assert_eq!(stored.last_seen.timestamp(), seen.timestamp());
+
+    let renamed = Uuid::new_v4().to_string();
+    store.rename(&live.session_id, &renamed).await.unwrap();
+    assert!(store.lookup(&live.session_id).await.is_err());
+    assert_eq!(store.lookup(&renamed).await.unwrap().auth_jwt, "auth-3");
+    assert_eq!(
+        store.meta(&renamed).await.unwrap().ip.as_deref(),
+        Some("10.0.0.1")
+    );
+
+    store.delete(&renamed).await.unwrap();
+    assert!(store.lookup(&renamed).await.is_err());
+    assert!(store.meta(&renamed).await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn listings_skip_expired_sessions_and_end_with_the_user() {
+    let store = store().await;
+    let user = Uuid::new_v4().to_string();
+    let older = session(&user, Duration::days(1));
+    let newer = session(&user, Duration::days(1));
+    let expired = session(&user, -Duration::hours(1));
+    let other = session(&Uuid::new_v4().to_string(), Duration::days(1));
+    for s in [&older, &newer, &expired, &other] {
+        store.insert(s).await.unwrap();
+    }
+    store
+        .touch(&newer.session_id, Utc::now() + Duration::minutes(5))
+        .await
+        .unwrap();
+
+    assert!(store.lookup(&expired.session_id).await.is_err());
+    let listed: Vec<String> = store
+        .list_user(&user)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(s, _)| s.session_id)
+        .collect();
+    assert_eq!(listed, [newer.session_id.clone(), older.session_id.clone()]);
+    let active: Vec<String> = store
+        .load_active()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|s| s.session_id)
+        .collect();
+    assert!(active.contains(&older.session_id));
+    assert!(!active.contains(&expired.session_id));
+
+    store.delete_user(&user).await.unwrap();
+    assert!(store.list_user(&user).await.unwrap().is_empty());
+    assert!(store.lookup(&older.session_id).await.is_err());
+    assert!(store.lookup(&other.session_id).await.is_ok());
+    store.delete(&other.session_id).await.unwrap();
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use artisan_dashboard::{
+    api::cookie::SessionData, auth::verify::KeySource, mock::ScriptedResponse,
+};
+use chrono::{Duration, Utc};
+use common::{TestEnv, tamper_sub};
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[tokio::test]
+async fn login_rejects_tampered_tokens() {
+    let env = TestEnv::start().await;
+    let upstream = &env.upstream;
+    upstream.script(
+        "POST",
+        "auth/login",
+        ScriptedResponse {
+            status: 200,
+            body: json!({
+                "auth": tamper_sub(&upstream.issue_token("bob", "auth", 900), "alice"),
+                "refresh": upstream.issue_token("bob", "refresh", 3600),
+            }),
+        },
+    );
+
+    let mut browser = env.browser();
+    let resp = browser.login("bob@example.com", "bob-password").await;
+    assert!(!resp.status().is_success());
+    assert!(browser.session_id.is_none());
+
+    // The genuine tokens still get through.
+    let resp = browser.login("bob@example.com", "bob-password").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(upstream.hits("GET", ".well-known/jwks.json") >= 1);
+}
+
+#[tokio::test]
+async fn refresh_rejects_tampered_tokens() {
+    let env = TestEnv::start().await;
+    let upstream = &env.upstream;
+    let session = SessionData {
+        session_id: "e2e-dave-tampered".to_owned(),
+        user_id: "dave".to_owned(),
+        auth_jwt: upstream.issue_token("dave", "auth", -60),
+        refresh_jwt: upstream.issue_token("dave", "refresh", 3600),
+        expires_at: Utc::now() + Duration::hours(1),
+    };
+    env.state.sessions.insert(&session).await.unwrap();
+
+    // Validly signed, but for somebody else.
+    upstream.script(
+        "POST",
+        "auth/refresh",
+        ScriptedResponse {
+            status: 200,
+            body: json!({ "auth": upstream.issue_token("carol", "auth", 900) }),
+        },
+    );
+    // Dave's, but altered.
+    upstream.script(
+        "POST",
+        "auth/refresh",
+        ScriptedResponse {
+            status: 200,
+            body: json!({ "auth": tamper_sub(&upstream.issue_token("carol", "auth", 900), "dave") }),
+        },
+    );
+
+    let browser = env.browser_with_session(&session.session_id);
+    for _ in 0..2 {
+        assert!(!browser.get("auth/me").await.status().is_success());
+        let stored = env
+            .state
+            .sessions
+            .lookup(&session.session_id)
+            .await
+            .unwrap();
+        assert_eq!(stored.auth_jwt, session.auth_jwt);
+    }
+
+    assert_eq!(browser.get("auth/me").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn login_rejects_unexpected_issuer() {
+    let env = TestEnv::start_with(|config, _| {
+        config.token_verify.issuer = Some(vec!["https://someone-else.example".to_owned()]);
+    })
+    .await;
+    let mut browser = env.browser();
+    let resp = browser.login("alice@example.com", "alice-password").await;
+    assert!(!resp.status().is_success());
+    assert!(browser.session_id.is_none());
+}
+
+#[tokio::test]
+async fn keys_load_from_a_local_jwks_file() {
+    let path = std::env::temp_dir().join(format!("jwks-{}.json", uuid::Uuid::new_v4()));
+    let env = TestEnv::start_with(|config, upstream| {
+        std::fs::write(&path, upstream.jwks().to_string()).unwrap();
+        config.token_verify.keys = Some(KeySource::JwksFile(path.display().to_string()));
+    })
+    .await;
+
+    env.logged_in("alice").await;
+    assert_eq!(env.upstream.hits("GET", ".well-known/jwks.json"), 0);
+    std::fs::remove_file(&path).ok();
+}
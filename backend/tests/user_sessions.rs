@@ -0,0 +1,60 @@
+mod common;
+
+use common::{TestEnv, session_cookie};
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+
+async fn list(browser: &common::Browser) -> Vec<Value> {
+    let body: Value = browser.get("auth/sessions").await.json().await.unwrap();
+    body["sessions"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn users_see_and_revoke_their_sessions() {
+    let env = TestEnv::start().await;
+    let laptop = env.logged_in("alice").await;
+    let phone = env
+        .browser()
+        .login_as_agent("alice@example.com", "alice-password", "e2e-phone")
+        .await;
+    let bob = env.logged_in("bob").await;
+
+    let sessions = list(&laptop).await;
+    assert_eq!(sessions.len(), 2);
+    let current: Vec<_> = sessions.iter().filter(|s| s["current"] == true).collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0]["ip"], "127.0.0.1");
+    let other = sessions.iter().find(|s| s["current"] == false).unwrap();
+    assert_eq!(other["user_agent"], "e2e-phone");
+    let other_id = other["id"].as_str().unwrap();
+    // Ids are handles, not the session ids themselves.
+    assert_ne!(Some(other_id), phone.session_id.as_deref());
+
+    // Nobody else can revoke it.
+    let path = format!("auth/sessions/{}", other_id);
+    let resp = bob.request(Method::DELETE, &path).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(phone.get("auth/whoami").await.status(), StatusCode::OK);
+
+    let resp = laptop.request(Method::DELETE, &path).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(session_cookie(&resp).is_none());
+    assert_eq!(
+        phone.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(list(&laptop).await.len(), 1);
+
+    // Revoking the current session logs it out.
+    let own = list(&laptop).await[0]["id"].as_str().unwrap().to_owned();
+    let resp = laptop
+        .request(Method::DELETE, &format!("auth/sessions/{}", own))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(session_cookie(&resp).as_deref(), Some(""));
+    assert_eq!(
+        laptop.get("auth/whoami").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
@@ -1,6 +1,7 @@
 // src/lib/api.ts
 import { BillingCosts, RefreshRequest, RefreshResponse, UsageSummary, VmActionRequest, VmActionType, VmListItem, VmStatusDetail } from "./types";
 import { API_URL } from "./config";
+import { csrfHeaders } from "./csrf";
 
 export async function fetchWithAuth(endpoint: string) {
   const res = await fetch(
@@ -28,6 +29,7 @@ export async function postWithAuth(endpoint: string, body?: any) {
     credentials: "include", // ← send the server‐issued cookie
     headers: {
       "Content-Type": "application/json",
+      ...(await csrfHeaders()),
     },
   };
 
@@ -54,6 +56,7 @@ export async function putWithAuth(endpoint: string, body?: any) {
     credentials: "include",
     headers: {
       "Content-Type": "application/json",
+      ...(await csrfHeaders()),
     },
   };
 
@@ -80,6 +83,7 @@ export async function deleteWithAuth(endpoint: string, body?: any) {
     credentials: "include",
     headers: {
       "Content-Type": "application/json",
+      ...(await csrfHeaders()),
     },
   };
 
@@ -111,6 +115,7 @@ export async function fetchBilling(
       credentials: "include", // ← send the cookie
       headers: {
         "Content-Type": "application/json",
+        ...(await csrfHeaders()),
       },
       body: JSON.stringify(usage),
     }
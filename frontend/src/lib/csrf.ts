@@ -0,0 +1,31 @@
+import { API_URL } from "./config";
+
+let cached: string | null = null;
+
+function fromCookie(): string | null {
+  const match = document.cookie.match(/(?:^|;\s*)(?:__Host-)?csrf_token=([^;]+)/);
+  return match ? decodeURIComponent(match[1]) : null;
+}
+
+// The token for the current session, sent back in X-CSRF-Token on anything
+// that isn't a GET.
+export async function csrfToken(): Promise<string | null> {
+  const token = fromCookie();
+  if (token) {
+    return token;
+  }
+  if (cached) {
+    return cached;
+  }
+  const res = await fetch(`${API_URL}/auth/csrf`, { credentials: "include" });
+  if (!res.ok) {
+    return null;
+  }
+  cached = (await res.json()).csrf_token ?? null;
+  return cached;
+}
+
+export async function csrfHeaders(): Promise<Record<string, string>> {
+  const token = await csrfToken();
+  return token ? { "X-CSRF-Token": token } : {};
+}
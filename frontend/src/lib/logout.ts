@@ -1,10 +1,12 @@
 import router from "next/router";
 import { API_URL } from "./config";
+import { csrfHeaders } from "./csrf";
 
 export async function handleLogout() {
     await fetch(`${API_URL}/auth/logout`, {
         method: "POST",
         credentials: "include",
+        headers: await csrfHeaders(),
     });
     router.push('/');
 }
@@ -13,6 +15,7 @@ export async function handleLogoutAll() {
     await fetch(`${API_URL}/auth/logout_all`, {
         method: "POST",
         credentials: "include",
+        headers: await csrfHeaders(),
     });
     router.push('/');
 }
\ No newline at end of file
@@ -6,12 +6,23 @@ import { Sidebar } from '@/components/header'
 import LoadingOverlay from '@/components/loading'
 import { handleLogout, handleLogoutAll } from '@/lib/logout'
 import { API_URL } from '@/lib/config'
+import { csrfHeaders } from '@/lib/csrf'
+
+// The first field error of a rejected request, e.g. "password: must be at
+// least 10 characters".
+async function errorText(res: Response, fallback: string): Promise<string> {
+  const body = await res.json().catch(() => null)
+  const field = body?.fields?.[0]
+  if (field) return `${field.field}: ${field.message}`
+  return body?.error ?? fallback
+}
 
 export default function AccountPage() {
   const { username, email: loadedEmail, isLoading, error } = useUser()
 
   // Editable state for email:
   const [email, setEmail] = useState<string>('')
+  const [currentPassword, setCurrentPassword] = useState<string>('')
   const [password, setPassword] = useState<string>('')
   const [confirmPassword, setConfirmPassword] = useState<string>('')
   const [prettyName, setPrettyName] = useState<string>('')
@@ -26,6 +37,7 @@ export default function AccountPage() {
           credentials: 'include',
           headers: {
             'Content-Type': 'application/json',
+            ...(await csrfHeaders()),
           },
           body: JSON.stringify({ email }),
         }
@@ -33,7 +45,7 @@ export default function AccountPage() {
       if (res.ok) {
         setFeedback('Email updated.')
       } else {
-        setFeedback('Error updating email.')
+        setFeedback(await errorText(res, 'Error updating email.'))
       }
     } catch {
       setFeedback('Error updating email.')
@@ -53,16 +65,18 @@ export default function AccountPage() {
           credentials: 'include',
           headers: {
             'Content-Type': 'application/json',
+            ...(await csrfHeaders()),
           },
-          body: JSON.stringify({ password }),
+          body: JSON.stringify({ current_password: currentPassword, password }),
         }
       )
       if (res.ok) {
-        setFeedback('Password updated.')
+        setFeedback('Password updated. Your other sessions were signed out.')
+        setCurrentPassword('')
         setPassword('')
         setConfirmPassword('')
       } else {
-        setFeedback('Error updating password.')
+        setFeedback(await errorText(res, 'Error updating password.'))
       }
     } catch {
       setFeedback('Error updating password.')
@@ -116,7 +130,14 @@ export default function AccountPage() {
         <section className="card p-6 space-y-6">
           <h2 className="text-xl font-semibold text-brand mb-2">Security Settings</h2>
           <div className="space-y-2">
-            <label className="block text-sm">New Password</label>
+            <label className="block text-sm">Current Password</label>
+            <input
+              type="password"
+              className="w-full p-2 rounded bg-gray-800 text-white"
+              value={currentPassword}
+              onChange={(e) => setCurrentPassword(e.target.value)}
+            />
+            <label className="block text-sm mt-2">New Password</label>
             <input
               type="password"
               className="w-full p-2 rounded bg-gray-800 text-white"
@@ -5,11 +5,25 @@ import logo from "../img/logo.webp";
 import LoadingOverlay from "@/components/loading";
 import { API_URL } from "@/lib/config";
 
+// The server's error for a failed login step, with the wait when throttled.
+async function failureMessage(res: Response, fallback: string): Promise<string> {
+    const body = await res.json().catch(() => ({}));
+    const message = body.error ?? fallback;
+    const wait = Number(res.headers.get("Retry-After"));
+    if (res.status === 429 && wait > 0) {
+        return `${message} (try again in ${wait} seconds)`;
+    }
+    return message;
+}
+
 export default function LoginPage() {
     const router = useRouter();
     const [email, setEmail] = useState("");
     const [password, setPassword] = useState("");
     const [errorMsg, setErrorMsg] = useState("");
+    // Set when the password was accepted but a second factor is needed.
+    const [pendingLogin, setPendingLogin] = useState<string | null>(null);
+    const [code, setCode] = useState("");
     const [loading, setLoading] = useState(true);
 
     useEffect(() => {
@@ -49,8 +63,41 @@ export default function LoginPage() {
             }
         );
 
+        if (res.status === 202) {
+            const body = await res.json();
+            setPendingLogin(body.pending_login);
+            setErrorMsg("");
+        } else if (res.ok) {
+            router.push("/apps");
+        } else {
+            setErrorMsg(await failureMessage(res, "Login failed, try again"));
+        }
+    };
+
+    const handleVerify = async (e: React.FormEvent) => {
+        e.preventDefault();
+
+        const res = await fetch(`${API_URL}/auth/login/verify`, {
+            method: "POST",
+            credentials: "include",
+            headers: { "Content-Type": "application/json" },
+            body: JSON.stringify({ pending_login: pendingLogin, code }),
+        });
+
         if (res.ok) {
             router.push("/apps");
+        } else if (res.status === 401) {
+            const body = await res.json().catch(() => ({}));
+            if (String(body.error ?? "").includes("expired")) {
+                // Too many wrong codes or too slow; start over.
+                setPendingLogin(null);
+                setCode("");
+            }
+            setErrorMsg(body.error ?? "Invalid code");
+        } else if (res.status === 422) {
+            setErrorMsg("Enter the 6 digit code from your authenticator app");
+        } else {
+            setErrorMsg(await failureMessage(res, "Verification failed, try again"));
         }
     };
 
@@ -70,55 +117,85 @@ export default function LoginPage() {
                     <p className="text-red-500 mb-4 text-center">{errorMsg}</p>
                 )}
 
-                <form onSubmit={handleLogin} className="space-y-4">
-                    <div>
-                        <label
-                            htmlFor="email"
-                            className="block text-sm font-medium text-gray-700 dark:text-gray-300"
+                {pendingLogin ? (
+                    <form onSubmit={handleVerify} className="space-y-4">
+                        <div>
+                            <label
+                                htmlFor="code"
+                                className="block text-sm font-medium text-gray-700 dark:text-gray-300"
+                            >
+                                Authentication code
+                            </label>
+                            <input
+                                id="code"
+                                type="text"
+                                inputMode="numeric"
+                                autoComplete="one-time-code"
+                                required
+                                value={code}
+                                onChange={(e) => setCode(e.target.value)}
+                                className="mt-1 w-full px-3 py-2 border border-gray-300 dark:border-gray-600 rounded-md bg-white dark:bg-gray-700 text-gray-900 dark:text-gray-100 focus:outline-none focus:ring-2 focus:ring-blue-400"
+                            />
+                        </div>
+
+                        <button
+                            type="submit"
+                            className="w-full bg-blue-600 hover:bg-blue-700 text-white py-2 px-4 rounded focus:outline-none focus:ring-2 focus:ring-blue-400"
+                        >
+                            Verify
+                        </button>
+                    </form>
+                ) : (
+                    <form onSubmit={handleLogin} className="space-y-4">
+                        <div>
+                            <label
+                                htmlFor="email"
+                                className="block text-sm font-medium text-gray-700 dark:text-gray-300"
+                            >
+                                Email
+                            </label>
+                            <input
+                                id="email"
+                                type="email"
+                                required
+                                value={email}
+                                onChange={(e) => setEmail(e.target.value)}
+                                className="mt-1 w-full px-3 py-2 border border-gray-300 dark:border-gray-600 rounded-md bg-white dark:bg-gray-700 text-gray-900 dark:text-gray-100 focus:outline-none focus:ring-2 focus:ring-blue-400"
+                            />
+                        </div>
+
+                        <div>
+                            <label
+                                htmlFor="password"
+                                className="block text-sm font-medium text-gray-700 dark:text-gray-300"
+                            >
+                                Password
+                            </label>
+                            <input
+                                id="password"
+                                type="password"
+                                required
+                                value={password}
+                                onChange={(e) => setPassword(e.target.value)}
+                                className="mt-1 w-full px-3 py-2 border border-gray-300 dark:border-gray-600 rounded-md bg-white dark:bg-gray-700 text-gray-900 dark:text-gray-100 focus:outline-none focus:ring-2 focus:ring-blue-400"
+                            />
+                        </div>
+
+                        <button
+                            type="submit"
+                            className="w-full bg-blue-600 hover:bg-blue-700 text-white py-2 px-4 rounded focus:outline-none focus:ring-2 focus:ring-blue-400"
                         >
-                            Email
-                        </label>
-                        <input
-                            id="email"
-                            type="email"
-                            required
-                            value={email}
-                            onChange={(e) => setEmail(e.target.value)}
-                            className="mt-1 w-full px-3 py-2 border border-gray-300 dark:border-gray-600 rounded-md bg-white dark:bg-gray-700 text-gray-900 dark:text-gray-100 focus:outline-none focus:ring-2 focus:ring-blue-400"
-                        />
-                    </div>
-
-                    <div>
-                        <label
-                            htmlFor="password"
-                            className="block text-sm font-medium text-gray-700 dark:text-gray-300"
+                            Log In
+                        </button>
+
+                        <button
+                            type="reset"
+                            className="w-full bg-gray-600 hover:bg-gray-600 text-white py-2 px-4 rounded focus:outline-none focus:ring-2 focus:ring-blue-400"
                         >
-                            Password
-                        </label>
-                        <input
-                            id="password"
-                            type="password"
-                            required
-                            value={password}
-                            onChange={(e) => setPassword(e.target.value)}
-                            className="mt-1 w-full px-3 py-2 border border-gray-300 dark:border-gray-600 rounded-md bg-white dark:bg-gray-700 text-gray-900 dark:text-gray-100 focus:outline-none focus:ring-2 focus:ring-blue-400"
-                        />
-                    </div>
-
-                    <button
-                        type="submit"
-                        className="w-full bg-blue-600 hover:bg-blue-700 text-white py-2 px-4 rounded focus:outline-none focus:ring-2 focus:ring-blue-400"
-                    >
-                        Log In
-                    </button>
-
-                    <button
-                        type="reset"
-                        className="w-full bg-gray-600 hover:bg-gray-600 text-white py-2 px-4 rounded focus:outline-none focus:ring-2 focus:ring-blue-400"
-                    >
-                        SSO
-                    </button>
-                </form>
+                            SSO
+                        </button>
+                    </form>
+                )}
             </div>
             {loading && <LoadingOverlay />}
         </div>
@@ -1,5 +1,4 @@
 import { useEffect, useState, useCallback } from 'react';
-import { Buffer } from 'buffer';
 import { Sidebar } from '@/components/header';
 import {
   fetchWithAuth,
@@ -11,7 +10,8 @@ import { handleLogout, handleLogoutAll } from '@/lib/logout';
 
 interface SecretItem {
   name: string;
-  value: string;
+  version: number;
+  size: number;
 }
 
 export default function SecretsPage() {
@@ -20,6 +20,7 @@ export default function SecretsPage() {
   const [selectedEnv, setSelectedEnv] = useState('prod');
   const [customEnv, setCustomEnv] = useState('');
   const [items, setItems] = useState<SecretItem[]>([]);
+  const [revealed, setRevealed] = useState<Record<string, string>>({});
   const [newName, setNewName] = useState('');
   const [newValue, setNewValue] = useState('');
 
@@ -52,26 +53,14 @@ const loadSecrets = useCallback(async () => {
       `secrets/list?runner_id=${selectedRunner}&environment_id=${envValue}`
     );
 
-    const list: SecretItem[] = (res.vals || []).map((kv: any) => {
-      let value = '';
-
-      // Handle byte array as decimal string
-      if (typeof kv.value === 'string' && /^\d+$/.test(kv.value)) {
-        // Split into chunks representing ASCII codes
-        const byteArray = kv.value.match(/.{1,3}/g)?.map(Number) || [];
-        value = String.fromCharCode(...byteArray);
-      } else if (Array.isArray(kv.value)) {
-        // If it's actually an array of numbers
-        value = String.fromCharCode(...kv.value);
-      } else {
-        // Fallback: treat as string
-        value = String(kv.value ?? '');
-      }
-
-      return { name: kv.key, value };
-    });
+    const list: SecretItem[] = (res.secrets || []).map((kv: any) => ({
+      name: kv.key,
+      version: kv.version,
+      size: kv.size,
+    }));
 
     setItems(list);
+    setRevealed({});
   } catch (err) {
     console.error('Failed to load secrets', err);
   }
@@ -90,7 +79,6 @@ const loadSecrets = useCallback(async () => {
         environment_id: envValue,
         secret_key: newName,
         value: newValue,
-        actor: 'dashboard',
       });
       setNewName('');
       setNewValue('');
@@ -114,9 +102,33 @@ const loadSecrets = useCallback(async () => {
     }
   };
 
-  const updateSecret = async (name: string, current: string) => {
+  const fetchValue = async (name: string): Promise<string> => {
+    const res = await fetchWithAuth(
+      `secrets/get?runner_id=${selectedRunner}&environment_id=${envValue}&secret_key=${encodeURIComponent(name)}`
+    );
+    return res.value ?? '';
+  };
+
+  const toggleReveal = async (name: string) => {
+    if (name in revealed) {
+      setRevealed((cur) => {
+        const next = { ...cur };
+        delete next[name];
+        return next;
+      });
+      return;
+    }
+    try {
+      const value = await fetchValue(name);
+      setRevealed((cur) => ({ ...cur, [name]: value }));
+    } catch (err) {
+      console.error('Failed to reveal secret', err);
+    }
+  };
+
+  const updateSecret = async (name: string) => {
     if (!selectedRunner || !envValue) return;
-    const newVal = prompt('Enter new value', current);
+    const newVal = prompt('Enter new value');
     if (newVal === null) return;
     try {
       await putWithAuth('secrets/update', {
@@ -124,7 +136,6 @@ const loadSecrets = useCallback(async () => {
         environment_id: envValue,
         secret_key: name,
         new_value: newVal,
-        actor: 'dashboard',
       });
       loadSecrets();
     } catch (err) {
@@ -132,8 +143,9 @@ const loadSecrets = useCallback(async () => {
     }
   };
 
-  const copySecret = async (value: string) => {
+  const copySecret = async (name: string) => {
     try {
+      const value = revealed[name] ?? (await fetchValue(name));
       await navigator.clipboard.writeText(value);
     } catch (e) {
       console.error('Copy failed', e);
@@ -192,16 +204,27 @@ const loadSecrets = useCallback(async () => {
               {items.map((s) => (
                 <div key={s.name} className="card-hover p-4 space-y-2">
                   <p className="font-semibold text-brand">{s.name}</p>
-                  <p className="text-sm text-gray-400 truncate">{s.value}</p>
+                  <p className="text-xs text-gray-500">
+                    v{s.version} · {s.size} bytes
+                  </p>
+                  <p className="text-sm text-gray-400 truncate">
+                    {s.name in revealed ? revealed[s.name] : '••••••••'}
+                  </p>
                   <div className="mt-2 flex gap-2">
                     <button
-                      onClick={() => copySecret(s.value)}
+                      onClick={() => toggleReveal(s.name)}
+                      className="bg-gray-600 text-white px-3 py-1 rounded text-sm hover:bg-gray-700"
+                    >
+                      {s.name in revealed ? 'Hide' : 'Reveal'}
+                    </button>
+                    <button
+                      onClick={() => copySecret(s.name)}
                       className="bg-brand text-white px-3 py-1 rounded text-sm hover:bg-brand-dark"
                     >
                       Copy
                     </button>
                     <button
-                      onClick={() => updateSecret(s.name, s.value)}
+                      onClick={() => updateSecret(s.name)}
                       className="bg-blue-600 text-white px-3 py-1 rounded text-sm hover:bg-blue-700"
                     >
                       Update